@@ -5,7 +5,9 @@ import nox
 @nox.session(python=["3.6", "3.7", "3.8", "3.9"])
 def test(session):
   session.install("pytest", "websockets")
-  session.install(".")
+  session.install("maturin>=0.11,<0.12")
+  # tests/test_tls.py needs the opt-in "tls" feature.
+  session.run("maturin", "develop", "--cargo-extra-args=--features tls")
   session.run("pytest")
 
 @nox.session(python=["3.6", "3.7", "3.8", "3.9"])
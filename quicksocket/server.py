@@ -1,42 +1,189 @@
-import traceback
-from typing import List, Union
+from typing import AsyncIterator, Callable, Dict, List, Optional, Tuple, Union
 
-from .quicksocket import start_server as BACKEND_start_server
-from .quicksocket import is_server_running as BACKEND_is_server_running
-from .quicksocket import shutdown_server as BACKEND_shutdown_server
-from .quicksocket import drain_new_client_events as BACKEND_drain_new_client_events
-from .quicksocket import drain_client_messages as BACKEND_drain_client_messages
-from .quicksocket import try_send_messages as BACKEND_try_send_messages
+from .quicksocket import Server as BACKEND_Server
+from .quicksocket import ClientConnectEvent, ClientDisconnectEvent, ClientLagEvent, ErrorEvent, EventBatch, QueueConfig, CompressionConfig, ServerStats, ClientInfo
+from .quicksocket import HandshakeDecision, HandshakeRequest
+from .quicksocket import QuicksocketError, ServerNotRunningError, BindError
+from .quicksocket import enable_python_logging, disable_python_logging
 
 class Server:
-  '''Wrapper around the quicksocket module that provides type annotations.'''
+  '''Wrapper around a native quicksocket Server that provides type annotations.
 
-  def start(self, port: int):
-    BACKEND_start_server(port = port)
+  Each Server owns its own native server instance, so several can run side by side (on different ports) in one process.'''
+
+  def __init__(self):
+    self._backend = BACKEND_Server()
+
+  def start(self, port: Optional[int] = None, host: str = "127.0.0.1", addresses: Optional[List[str]] = None, tls_cert: Optional[str] = None, tls_key: Optional[str] = None,
+    new_client_queue: Optional[QueueConfig] = None, client_message_queue: Optional[QueueConfig] = None, broadcast_queue: Optional[QueueConfig] = None,
+    lag_policy: str = "continue", lag_threshold: int = 0, topic_control: bool = False, endpoints: Optional[List[str]] = None, subprotocols: Optional[List[str]] = None,
+    allowed_origins: Optional[List[str]] = None, handshake_hook: Optional[Callable[[HandshakeRequest], Optional[HandshakeDecision]]] = None,
+    compression: Optional[CompressionConfig] = None,
+    static_dir: Optional[str] = None, metrics: bool = False, metrics_address: Optional[str] = None,
+    ping_interval_s: Optional[float] = None, pong_timeout_s: Optional[float] = None, disconnect_queue: Optional[QueueConfig] = None) -> bool:
+    '''Start listening on `host`:`port`, or on every "host:port" in `addresses` (e.g. ["0.0.0.0:9000", "[::1]:9000"]).
+
+    Pass PEM `tls_cert` and `tls_key` paths to serve wss:// instead of ws://. This needs a build with the opt-in "tls" cargo feature.
+
+    Pass a QueueConfig(capacity, overflow) to size a queue and choose what happens when it's full: "block", "drop_oldest", "drop_newest" or "disconnect". By default every queue holds 16 items; new client events, client messages and client disconnect events block, and clients that fall behind the broadcast skip the oldest batches. `disconnect_queue` can't use "disconnect".
+
+    Clients that fall a full broadcast queue behind are reported by drain_client_lag_events(). `lag_policy` says what happens to them next: "continue", "resync" (send them the set_resync_snapshot() messages first), or "disconnect" once they've skipped more than `lag_threshold` batches.
+
+    Pass `topic_control = True` to let clients subscribe themselves to topics (see publish()) by sending "quicksocket:subscribe:<topic>", and unsubscribe with "quicksocket:unsubscribe:<topic>".
+
+    Pass `endpoints`, request paths such as ["/hands", "/debug"], to serve each as a separate endpoint with its own client messages and broadcast (see the `endpoint` arguments below). "/" stays the default endpoint, and requests for any other path get a 404.
+
+    Pass `subprotocols`, the names the server speaks in priority order (e.g. ["viz.v2", "viz.v1"]), to select the first one each client offers (see get_client_subprotocol()); clients that offer none of them get a 400.
+
+    Pass `allowed_origins`, e.g. ["https://viz.example.com", "https://*.example.com"], to only accept browser clients from those origins (or any subdomain, for "*."). Other origins get a 403 and are recorded as "security" errors; requests without an Origin header (from non-browser clients) are still accepted.
+
+    Pass a `handshake_hook` to inspect each client's upgrade request (path, query, headers and cookies) before it's accepted. Return None to accept, HandshakeDecision.accept(headers) to accept with extra response headers, or HandshakeDecision.reject(status, body, headers) to turn the client away. It's called from the server's thread, so keep it quick.
+
+    Pass a `static_dir` to serve its files over plain HTTP on the same port, e.g. your web app's HTML and JS; websocket requests are upgraded as usual.
+
+    Pass `metrics = True` to serve Prometheus metrics at /metrics on the same port, or a `metrics_address` such as "127.0.0.1:9100" to serve them on a port of their own.
+
+    Pass a CompressionConfig as `compression` to compress messages with clients that offer the permessage-deflate extension (as browsers do); see get_client_compression() for what was agreed with each. CompressionConfig(server_max_window_bits, client_max_window_bits, server_no_context_takeover, client_no_context_takeover, threshold) sets the window sizes (9 to 15 bits, default 15), whether each side compresses messages on their own, and the size in bytes below which messages are sent uncompressed (default 1024).
+
+    Pass `ping_interval_s` to ping every client that often and disconnect ("timed_out") any that doesn't answer within `pong_timeout_s` (default: the interval), so dead connections don't linger. Each client's round-trip time is then reported by get_client_info().
+
+    Returns False if the server is already running. Raises BindError if an address (or `metrics_address`) can't be resolved or bound, or QuicksocketError if the TLS files can't be loaded (or this build lacks the "tls" feature) or `static_dir` isn't a directory.'''
+    return self._backend.start(port = port, host = host, addresses = addresses, tls_cert = tls_cert, tls_key = tls_key,
+      new_client_queue = new_client_queue, client_message_queue = client_message_queue, broadcast_queue = broadcast_queue,
+      lag_policy = lag_policy, lag_threshold = lag_threshold, topic_control = topic_control, endpoints = endpoints,
+      subprotocols = subprotocols, allowed_origins = allowed_origins, handshake_hook = handshake_hook,
+      compression = compression, static_dir = static_dir, metrics = metrics, metrics_address = metrics_address,
+      ping_interval_s = ping_interval_s, pong_timeout_s = pong_timeout_s, disconnect_queue = disconnect_queue)
 
   def is_running(self) -> bool:
-    running = BACKEND_is_server_running()
+    running = self._backend.is_running()
     return running
 
   def stop(self):
-    BACKEND_shutdown_server()
+    self._backend.shutdown()
+
+  def get_last_error_string(self) -> Optional[str]:
+    return self._backend.get_last_error_string()
 
-  def drain_new_client_events(self) -> List[str]:
-    new_client_events: List[str] = BACKEND_drain_new_client_events()
+  def get_drop_counts(self) -> Dict[str, int]:
+    '''Returns how many items the "new_client_events", "client_messages", "broadcast" and "client_disconnect_events" queues have dropped since the server started.'''
+    return self._backend.get_drop_counts()
+
+  def get_server_stats(self) -> Optional[ServerStats]:
+    '''Returns a snapshot of the server's uptime, current and peak connections, messages and bytes in each direction, drops, broadcast lag and queue depths since it started, or None if it has never been started.'''
+    return self._backend.get_server_stats()
+
+  def drain_errors(self) -> List[ErrorEvent]:
+    '''Returns an event for every error the server encountered since the last call, oldest first, each with its kind, message, timestamp and (if any) client ID.'''
+    return self._backend.drain_errors()
+
+  def drain_new_client_events(self) -> List[Tuple[int, str]]:
+    '''Returns (client_id, peer_address) tuples for every client that connected since the last call.'''
+    new_client_events: List[Tuple[int, str]] = self._backend.drain_new_client_events()
     # for new_client in new_client_events:
     #   print('Drained new client event: {}'.format(new_client))
     
     return new_client_events
+
+  def drain_client_connect_events(self) -> List[ClientConnectEvent]:
+    '''Returns an event for every client that connected since the last call, with its upgrade request. Uses the same queue as drain_new_client_events().'''
+    return self._backend.drain_client_connect_events()
   
-  def drain_client_messages(self) -> List[Union[str, bytes]]:
-    client_msgs: List[Union[str, bytes]] = BACKEND_drain_client_messages()
+  def drain_client_disconnect_events(self) -> List[ClientDisconnectEvent]:
+    '''Returns an event for every client that disconnected since the last call, with its close code, reason, and kind ("clean", "timed_out" or "error").'''
+    return self._backend.drain_client_disconnect_events()
+
+  def drain_client_lag_events(self) -> List[ClientLagEvent]:
+    '''Returns an event for every time a client fell behind the broadcast since the last call, with how many batches it skipped and what the server did about it ("continued", "resynced" or "disconnected").'''
+    return self._backend.drain_client_lag_events()
+
+  def set_resync_snapshot(self, messages: Optional[List[Union[str, bytes]]], topic: Optional[str] = None, endpoint: Optional[str] = None):
+    '''Set the messages sent to clients that fall behind the broadcast under the "resync" lag policy, e.g. the latest full state. Pass None to clear it. Pass a `topic` to set them for that topic's subscribers instead, or an `endpoint` path for that endpoint's clients (either way, the server must be running).'''
+    self._backend.set_resync_snapshot(messages, topic = topic, endpoint = endpoint)
+
+  def get_client_path(self, client_id: int) -> Optional[str]:
+    '''The path and query string a client requested when it connected, e.g. "/hands?user=3". None if the client isn't connected.'''
+    return self._backend.get_client_path(client_id)
+
+  def get_client_subprotocol(self, client_id: int) -> Optional[str]:
+    '''The subprotocol selected when a client connected, e.g. "viz.v2". None if the client isn't connected, or the server wasn't started with `subprotocols`.'''
+    return self._backend.get_client_subprotocol(client_id)
+
+  def get_client_compression(self, client_id: int) -> Optional[str]:
+    '''The permessage-deflate parameters agreed when a client connected, e.g. "permessage-deflate; server_no_context_takeover". None if the client isn't connected, or its messages aren't compressed.'''
+    return self._backend.get_client_compression(client_id)
+
+  def get_clients(self) -> List[ClientInfo]:
+    '''A snapshot of every connected client: peer address, connect time, path, subprotocol, compression, messages and bytes in each direction, outbound queue depth, lag and last activity.'''
+    return self._backend.get_clients()
+
+  def get_client_info(self, client_id: int) -> Optional[ClientInfo]:
+    '''A snapshot of one client, as in get_clients(). None if the client isn't connected.'''
+    return self._backend.get_client_info(client_id)
+
+  def disconnect_client(self, client_id: int, code: int = 1000, reason: str = "") -> bool:
+    '''Close one client's connection with the given close code and reason. Returns False if the client isn't connected.'''
+    return self._backend.disconnect_client(client_id, code, reason)
+
+  def drain_client_messages(self, endpoint: Optional[str] = None) -> List[Tuple[int, Union[str, bytes]]]:
+    '''Returns (client_id, payload) tuples for every message received since the last call. Pass an `endpoint` path to drain the messages from that endpoint's clients, rather than the default endpoint's.'''
+    client_msgs: List[Tuple[int, Union[str, bytes]]] = self._backend.drain_client_messages(endpoint = endpoint)
     return client_msgs
 
-  def send_messages(self, messages: List[Union[str, bytes]]):
-    '''If you have more than one message to send, best to send as many of them as you can to the library at once, so any synchronization overhead isn't eaten more than is necessary.'''
-    try:
-      BACKEND_try_send_messages(messages)
-      # print("Successfully sent messages!")
-    except BaseException as e:
-      print('Exception trying to send messages {}'.format(e))
-      print('Traceback: {}'.format(traceback.print_tb(e.__traceback__)))
+  def wait_for_events(self, timeout_s: float) -> EventBatch:
+    '''Block until there are new clients, client messages, lagging clients or disconnects (or until `timeout_s` seconds pass), then drain them all. Other Python threads keep running while this waits.
+
+    The returned batch has new_client_events, client_messages, client_lag_events and client_disconnect_events lists, and is falsy if the wait timed out. Messages from clients of the `endpoints` passed to start() are in its endpoint_messages dict instead, as lists of (client_id, payload) tuples by path.'''
+    return self._backend.wait_for_events(timeout_s)
+
+  def set_handlers(self,
+    on_connect: Optional[Callable[[int, str], None]] = None,
+    on_message: Optional[Callable[[int, Union[str, bytes]], None]] = None,
+    on_disconnect: Optional[Callable[[int, ClientDisconnectEvent], None]] = None,
+    on_lag: Optional[Callable[[int, ClientLagEvent], None]] = None):
+    '''Have a background thread call these for every new client, message, lagging client and disconnect, instead of draining events yourself.
+
+    While any handler is set, the drain/wait/recv methods see no events. Exceptions raised by handlers show up in drain_errors() as "callback" errors. Call with no handlers to stop.'''
+    self._backend.set_handlers(on_connect = on_connect, on_message = on_message, on_disconnect = on_disconnect, on_lag = on_lag)
+
+  async def recv_message(self) -> Tuple[int, Union[str, bytes]]:
+    '''Wait for the next (client_id, payload) message from any client without blocking the event loop. Raises ServerNotRunningError if the server isn't running or stops first.'''
+    return await self._backend.recv_message()
+
+  async def events(self) -> AsyncIterator[Union[ClientConnectEvent, ClientLagEvent, ClientDisconnectEvent]]:
+    '''Yields client connections, lags and disconnections as they happen, without blocking the event loop, until the server stops.
+
+    e.g. `async for event in server.events(): ...`'''
+    while True:
+      event = await self._backend.next_event()
+      if event is None:
+        return
+      yield event
+
+  def send_messages(self, messages: List[Union[str, bytes]], endpoint: Optional[str] = None):
+    '''If you have more than one message to send, best to send as many of them as you can to the library at once, so any synchronization overhead isn't eaten more than is necessary. Pass an `endpoint` path to send to that endpoint's clients, rather than the default endpoint's. Raises ServerNotRunningError if the server has never been started, or ValueError for an unknown `endpoint`.'''
+    self._backend.try_send_messages(messages, endpoint = endpoint)
+
+  def publish(self, topic: str, messages: List[Union[str, bytes]]):
+    '''Send messages to every client subscribed to `topic`. Nothing happens if nobody's subscribed.'''
+    self._backend.publish(topic, messages)
+
+  def subscribe_client(self, client_id: int, topic: str) -> bool:
+    '''Subscribe a client to a topic. Returns False if the client isn't connected, or is already subscribed to 64 topics, or the server already has 4096. Raises ValueError for a name that's empty or longer than 256 bytes.'''
+    return self._backend.subscribe_client(client_id, topic)
+
+  def unsubscribe_client(self, client_id: int, topic: str) -> bool:
+    '''Unsubscribe a client from a topic. Returns False if the client isn't connected or wasn't subscribed.'''
+    return self._backend.unsubscribe_client(client_id, topic)
+
+  def get_client_topics(self, client_id: int) -> List[str]:
+    '''The topics a client is subscribed to, in name order.'''
+    return self._backend.get_client_topics(client_id)
+
+  def send_to_client(self, client_id: int, messages: List[Union[str, bytes]]) -> bool:
+    '''Send messages to only the given client. Returns False if the client isn't connected or isn't keeping up.'''
+    return self._backend.send_to_client(client_id, messages)
+
+  def send_to_clients(self, client_ids: List[int], messages: List[Union[str, bytes]]) -> int:
+    '''Send messages to each of the given clients. Returns the number of clients the messages were queued for.'''
+    return self._backend.send_to_clients(client_ids, messages)
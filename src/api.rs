@@ -3,80 +3,37 @@
 //
 // Primary Python module and Rust-lib public API.
 
-use futures_util::FutureExt;
-use pyo3::{prelude::*, wrap_pyfunction};
-use tokio_tungstenite::tungstenite::Message as WsMessage;
+use log::{info, warn};
+use pyo3::{create_exception, prelude::*, wrap_pyfunction};
+use tokio_tungstenite::tungstenite::{Message as WsMessage, protocol::{CloseFrame, frame::coding::CloseCode}};
 
-use crate::server::{self, consumer_state::{self}};
-use consumer_state as cs;
+use std::{borrow::Cow, collections::HashMap, path::PathBuf, sync::{Arc, Mutex, RwLock}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 
-/// Starts the websocket server.
-#[pyfunction]
-pub fn start_server(port: u32) -> bool {
-    // For now, start_server can only be called if the server is not already running.
-    if is_server_running() {
-      consumer_state::weakly_record_error("Server is already running, can't invoke start_server().".to_string());
-      return false;
-    }
+use crate::{asyncio_bridge, dispatcher::{Dispatcher, Handlers}, log_bridge};
+use crate::server::{self, ClientId, ServerConfig, ServerThreadHandle, clients::{ClientSendError, ClientSnapshot}, consumer_state::ConsumerState, endpoints::{DEFAULT_ENDPOINT_PATH, Endpoint}, errors::{ErrorKind, ErrorQueue, ServerError}, events::{ConnectEvent, DisconnectEvent, LagEvent}, handshake::{self, HandshakeHook, HandshakeInfo}, compression::CompressionConfig, metrics::MetricsSource, queue::{LagPolicy, OverflowPolicy, QueueConfig}, tls::TlsConfig, topics::{self, TopicError}};
 
-    let server_started = server::start(port).is_ok();
-    if !server_started { return false; }
+// Exceptions
+// ----------
+//
+// Raised by server operations that can't report failure through their return value. All of them derive from QuicksocketError.
 
-    println!("Server started.");
-    return true;
-}
+create_exception!(quicksocket, QuicksocketError, pyo3::exceptions::PyException);
+create_exception!(quicksocket, ServerNotRunningError, QuicksocketError);
+create_exception!(quicksocket, BindError, QuicksocketError);
 
-/// Gets whether the server is running.
-#[pyfunction]
-pub fn is_server_running() -> bool {
-    cs::read(&cs::CS_SER_ALIVE_RX, |rx| {
-        println!("Returning server alive: {}", *rx.borrow());
-        *rx.borrow()
-    }).unwrap_or_else(|| {
-        println!("Failed to get server alive!");
-        false
-    })
-}
-
-/// Requests that the websocket server shut down. The server will not shut down immediately but will stop serving as soon as e.g. it processes the shutdown request and any existing network requests are resolved.
-#[pyfunction]
-pub fn shutdown_server() {
-    let res = cs::mutate(&cs::CS_SER_REQ_SHUTDOWN_TX, |tx| tx.send(true));
-    if res.is_none() {
-        println!("[api.rs] Warning! Failed to send shutdown request.");
+/// Builds the exception to raise for an error of the given kind.
+fn error_to_py(kind: ErrorKind, message: String) -> PyErr {
+    match kind {
+        ErrorKind::Bind       => BindError::new_err(message),
+        ErrorKind::NotRunning => ServerNotRunningError::new_err(message),
+        _                     => QuicksocketError::new_err(message),
     }
 }
 
-/// Returns a string describing the nature of the last error the server encountered. No error has been detected if this function returns None.
-#[pyfunction]
-pub fn get_last_error_string() -> Option<String> {
-    consumer_state::try_get_last_error()
-}
-
-/// Retrieves a List (Rust: Vec<String>) of all new client connection events that have occurred since this function was last called.
-#[pyfunction]
-pub fn drain_new_client_events(py: Python) -> Vec<String> {
-    py.allow_threads(|| {
-        let drained_new_cli_evts = cs::mutate(&cs::CS_CLI_CONN_RX, |rx| {
-            let mut new_cli_evts = vec![];
-            while let Some(Some(new_cli)) = rx.recv().now_or_never() {
-                new_cli_evts.push(new_cli);
-            }
-            new_cli_evts
-        });
-        if drained_new_cli_evts.is_none() {
-            return vec![]
-        }
-        let drained_new_cli_evts = drained_new_cli_evts.unwrap();
-        
-        drained_new_cli_evts
-    })
-}
-
 /// Valid message payloads in the list of messages to provide to try_send_message consist of strings (text messages) and bytes (binary messages).
 ///
 /// Passing any other type within the list of objects will raise an exception.
-#[derive(FromPyObject)]
+#[derive(Clone, FromPyObject)]
 pub enum MessagePayload {
     #[pyo3(transparent, annotation = "str")]
     Text(String),
@@ -96,90 +53,1398 @@ impl IntoPy<PyObject> for MessagePayload {
     }
 }
 
-/// Send messages to all connected clients. The socket stream is flushed after buffering each message in the argument List, so it's better to call this once per 'update,' rather than calling this method multiple times if multiple messages are all available to be sent.
-///
-/// The List may contain strings or bytes.
+impl From<MessagePayload> for WsMessage {
+    fn from(msg: MessagePayload) -> Self {
+        match msg {
+            MessagePayload::Text(text)    => { WsMessage::Text(text) }
+            MessagePayload::Binary(bytes) => { WsMessage::Binary(bytes) }
+        }
+    }
+}
+
+/// A client's HTTP upgrade request, as passed to the handshake hook (see Server.start) and reported on its ClientConnectEvent.
+#[pyclass(name = "HandshakeRequest")]
+#[derive(Clone)]
+pub struct HandshakeRequest {
+    /// The request path, without the query string, e.g. "/hands".
+    #[pyo3(get)]
+    pub path: String,
+    /// The query string, without the leading "?", or None if there wasn't one.
+    #[pyo3(get)]
+    pub query: Option<String>,
+    /// The request headers, by lowercase name. Repeated headers are joined with ", ".
+    #[pyo3(get)]
+    pub headers: HashMap<String, String>,
+    /// The cookies sent in the Cookie header, by name.
+    #[pyo3(get)]
+    pub cookies: HashMap<String, String>,
+}
+impl From<&HandshakeInfo> for HandshakeRequest {
+    fn from(info: &HandshakeInfo) -> Self {
+        let mut headers: HashMap<String, String> = HashMap::new();
+        for (name, value) in &info.headers {
+            headers.entry(name.clone())
+                .and_modify(|values| { values.push_str(", "); values.push_str(value); })
+                .or_insert_with(|| value.clone());
+        }
+        HandshakeRequest { path: info.path.clone(), query: info.query.clone(), headers, cookies: info.cookies().into_iter().collect() }
+    }
+}
+#[pyproto]
+impl pyo3::PyObjectProtocol for HandshakeRequest {
+    fn __repr__(&self) -> String {
+        format!("HandshakeRequest(path={:?}, query={:?}, headers={})", self.path, self.query, self.headers.len())
+    }
+}
+
+/// What a handshake hook decided to do with a client's upgrade request. Make one with HandshakeDecision.accept or HandshakeDecision.reject.
+#[pyclass(name = "HandshakeDecision")]
+#[derive(Clone)]
+pub struct PyHandshakeDecision {
+    decision: handshake::HandshakeDecision,
+}
+#[pymethods]
+impl PyHandshakeDecision {
+    /// Accepts the connection, adding `headers` (a dict of header name to value, if given) to the upgrade response.
+    #[staticmethod]
+    #[args(headers = "None")]
+    fn accept(headers: Option<HashMap<String, String>>) -> Self {
+        PyHandshakeDecision { decision: handshake::HandshakeDecision::Accept { headers: headers.unwrap_or_default().into_iter().collect() } }
+    }
+
+    /// Rejects the connection with an HTTP `status` (default 403), an optional `body`, and optional `headers`.
+    #[staticmethod]
+    #[args(status = "403", body = "None", headers = "None")]
+    fn reject(status: u16, body: Option<String>, headers: Option<HashMap<String, String>>) -> Self {
+        PyHandshakeDecision { decision: handshake::HandshakeDecision::Reject { status, body, headers: headers.unwrap_or_default().into_iter().collect() } }
+    }
+}
+#[pyproto]
+impl pyo3::PyObjectProtocol for PyHandshakeDecision {
+    fn __repr__(&self) -> String {
+        match &self.decision {
+            handshake::HandshakeDecision::Accept { .. } => "HandshakeDecision.accept()".to_string(),
+            handshake::HandshakeDecision::Reject { status, .. } => format!("HandshakeDecision.reject(status={})", status),
+        }
+    }
+}
+
+/// Wraps a Python callable as the server's handshake hook. It's called with the GIL held, with a HandshakeRequest, and may return None (accept) or a HandshakeDecision. If it raises or returns anything else, the client is rejected with a 500 and the problem is recorded as a "callback" error.
+fn python_handshake_hook(hook: PyObject, errors: ErrorQueue) -> Arc<dyn HandshakeHook> {
+    Arc::new(move |info: &HandshakeInfo| Python::with_gil(|py| {
+        let failed = |err: String| {
+            warn!("Handshake hook {}", err);
+            errors.record(ErrorKind::Callback, format!("Handshake hook {}", err), None);
+            handshake::HandshakeDecision::Reject { status: handshake::HOOK_FAILED_STATUS, body: None, headers: vec![] }
+        };
+        match hook.call1(py, (HandshakeRequest::from(info),)) {
+            Ok(decision) if decision.is_none(py) => handshake::HandshakeDecision::Accept { headers: vec![] },
+            Ok(decision) => match decision.extract::<PyHandshakeDecision>(py) {
+                Ok(decision) => decision.decision,
+                Err(_) => failed("returned something other than None or a HandshakeDecision.".to_string()),
+            },
+            Err(err) => failed(format!("raised {}", err)),
+        }
+    }))
+}
+
+/// Describes a newly-connected client. Returned by drain_client_connect_events and yielded by next_event; drain_new_client_events returns the same client ID and peer as (client_id, peer) tuples.
+#[pyclass(name = "ClientConnectEvent")]
+#[derive(Clone)]
+pub struct ClientConnectEvent {
+    /// The client ID, which tags all of this client's messages.
+    #[pyo3(get)]
+    pub client_id: ClientId,
+    /// The client's peer address.
+    #[pyo3(get)]
+    pub peer: String,
+    /// The client's upgrade request.
+    #[pyo3(get)]
+    pub request: HandshakeRequest,
+    /// The subprotocol selected for the connection, or None if the server wasn't started with any.
+    #[pyo3(get)]
+    pub subprotocol: Option<String>,
+}
+impl From<ConnectEvent> for ClientConnectEvent {
+    fn from(evt: ConnectEvent) -> Self {
+        ClientConnectEvent { client_id: evt.client_id, peer: evt.peer, request: HandshakeRequest::from(&evt.request), subprotocol: evt.subprotocol }
+    }
+}
+#[pyproto]
+impl pyo3::PyObjectProtocol for ClientConnectEvent {
+    fn __repr__(&self) -> String {
+        format!("ClientConnectEvent(client_id={}, peer={:?}, path={:?}, subprotocol={:?})", self.client_id, self.peer, self.request.path, self.subprotocol)
+    }
+}
+
+/// Describes a client connection that has closed. Returned by drain_client_disconnect_events.
+#[pyclass(name = "ClientDisconnectEvent")]
+#[derive(Clone)]
+pub struct ClientDisconnectEvent {
+    /// The client ID, as reported in drain_new_client_events.
+    #[pyo3(get)]
+    pub client_id: ClientId,
+    /// The client's peer address.
+    #[pyo3(get)]
+    pub peer: String,
+    /// How the connection ended: "clean" (close handshake completed), "timed_out", or "error".
+    #[pyo3(get)]
+    pub kind: &'static str,
+    /// The close code. 1005 means the close frame had no code; 1006 means there was no close frame at all.
+    #[pyo3(get)]
+    pub code: u16,
+    /// The close reason, or an error description if the connection ended abnormally.
+    #[pyo3(get)]
+    pub reason: String,
+}
+impl From<DisconnectEvent> for ClientDisconnectEvent {
+    fn from(evt: DisconnectEvent) -> Self {
+        ClientDisconnectEvent { client_id: evt.client_id, peer: evt.peer, kind: evt.kind.as_str(), code: evt.code, reason: evt.reason }
+    }
+}
+#[pyproto]
+impl pyo3::PyObjectProtocol for ClientDisconnectEvent {
+    fn __repr__(&self) -> String {
+        format!("ClientDisconnectEvent(client_id={}, peer={:?}, kind={:?}, code={}, reason={:?})", self.client_id, self.peer, self.kind, self.code, self.reason)
+    }
+}
+
+/// Describes a client that fell a full broadcast queue behind and skipped some batches of server messages. Returned by drain_client_lag_events.
+#[pyclass(name = "ClientLagEvent")]
+#[derive(Clone)]
+pub struct ClientLagEvent {
+    /// The client ID, as reported in drain_new_client_events.
+    #[pyo3(get)]
+    pub client_id: ClientId,
+    /// The topic the client fell behind on, or None for the server broadcast (try_send_messages).
+    #[pyo3(get)]
+    pub topic: Option<String>,
+    /// How many batches (try_send_messages or publish calls) the client just skipped.
+    #[pyo3(get)]
+    pub skipped: u64,
+    /// How many batches the client has skipped since it connected, including these.
+    #[pyo3(get)]
+    pub total_skipped: u64,
+    /// What the server did about it: "continued", "resynced" (sent the resync snapshot), or "disconnected".
+    #[pyo3(get)]
+    pub action: &'static str,
+}
+impl From<LagEvent> for ClientLagEvent {
+    fn from(evt: LagEvent) -> Self {
+        ClientLagEvent { client_id: evt.client_id, topic: evt.topic, skipped: evt.skipped, total_skipped: evt.total_skipped, action: evt.action.as_str() }
+    }
+}
+#[pyproto]
+impl pyo3::PyObjectProtocol for ClientLagEvent {
+    fn __repr__(&self) -> String {
+        let topic = self.topic.as_ref().map(|topic| format!("{:?}", topic)).unwrap_or_else(|| "None".to_string());
+        format!("ClientLagEvent(client_id={}, topic={}, skipped={}, total_skipped={}, action={:?})", self.client_id, topic, self.skipped, self.total_skipped, self.action)
+    }
+}
+
+/// Describes an error the server encountered. Returned by drain_errors.
+#[pyclass(name = "ErrorEvent")]
+#[derive(Clone)]
+pub struct ErrorEvent {
+    /// What went wrong: "bind", "tls", "handshake", "connection", "lock_poisoned", "channel_closed", "not_running", "client_not_connected", "queue_full", "invalid_request", "callback" or "security".
+    #[pyo3(get)]
+    pub kind: &'static str,
+    /// A description of the error.
+    #[pyo3(get)]
+    pub message: String,
+    /// When the error occurred, in seconds since the Unix epoch (as returned by time.time()).
+    #[pyo3(get)]
+    pub timestamp: f64,
+    /// The client the error concerns, or None if it isn't about a particular client.
+    #[pyo3(get)]
+    pub client_id: Option<ClientId>,
+}
+impl From<ServerError> for ErrorEvent {
+    fn from(err: ServerError) -> Self {
+        ErrorEvent { kind: err.kind.as_str(), message: err.message, timestamp: unix_seconds(err.timestamp), client_id: err.client_id }
+    }
+}
+#[pyproto]
+impl pyo3::PyObjectProtocol for ErrorEvent {
+    fn __repr__(&self) -> String {
+        let client_id = self.client_id.map(|id| id.to_string()).unwrap_or_else(|| "None".to_string());
+        format!("ErrorEvent(kind={:?}, message={:?}, timestamp={}, client_id={})", self.kind, self.message, self.timestamp, client_id)
+    }
+}
+
+/// Everything drained by a single call to wait_for_events.
+#[pyclass(name = "EventBatch")]
+#[derive(Clone, Default)]
+pub struct EventBatch {
+    /// (client_id, peer_address) tuples, as returned by drain_new_client_events.
+    #[pyo3(get)]
+    pub new_client_events: Vec<(ClientId, String)>,
+    /// (client_id, payload) tuples, as returned by drain_client_messages.
+    #[pyo3(get)]
+    pub client_messages: Vec<(ClientId, MessagePayload)>,
+    /// Messages from clients of the registered endpoints (see start), as (client_id, payload) tuples by endpoint path. Only endpoints with messages are included; the default endpoint's are in client_messages.
+    #[pyo3(get)]
+    pub endpoint_messages: HashMap<String, Vec<(ClientId, MessagePayload)>>,
+    /// ClientLagEvents, as returned by drain_client_lag_events.
+    #[pyo3(get)]
+    pub client_lag_events: Vec<ClientLagEvent>,
+    /// ClientDisconnectEvents, as returned by drain_client_disconnect_events.
+    #[pyo3(get)]
+    pub client_disconnect_events: Vec<ClientDisconnectEvent>,
+}
+impl EventBatch {
+    pub fn is_empty(&self) -> bool {
+        self.new_client_events.is_empty() && self.client_messages.is_empty() && self.endpoint_messages.is_empty() && self.client_lag_events.is_empty() && self.client_disconnect_events.is_empty()
+    }
+}
+#[pyproto]
+impl pyo3::PyObjectProtocol for EventBatch {
+    fn __repr__(&self) -> String {
+        let endpoint_messages: usize = self.endpoint_messages.values().map(Vec::len).sum();
+        format!("EventBatch(new_client_events={}, client_messages={}, endpoint_messages={}, client_lag_events={}, client_disconnect_events={})", self.new_client_events.len(), self.client_messages.len(), endpoint_messages, self.client_lag_events.len(), self.client_disconnect_events.len())
+    }
+
+    /// False if the batch is empty, i.e. the wait timed out.
+    fn __bool__(&self) -> bool {
+        !self.is_empty()
+    }
+}
+
+/// A snapshot of a server's counters and queues, as returned by Server.get_server_stats. Counts are since the server was last started.
+#[pyclass(name = "ServerStats")]
+#[derive(Clone)]
+pub struct ServerStats {
+    /// Seconds since the server was last started.
+    #[pyo3(get)]
+    pub uptime_s: f64,
+    /// Clients currently connected.
+    #[pyo3(get)]
+    pub connected_clients: usize,
+    /// The most clients connected at once.
+    #[pyo3(get)]
+    pub peak_clients: u64,
+    /// Clients that completed the websocket handshake.
+    #[pyo3(get)]
+    pub connections_total: u64,
+    /// Text and binary messages received from clients.
+    #[pyo3(get)]
+    pub messages_received: u64,
+    /// Payload bytes received from clients.
+    #[pyo3(get)]
+    pub bytes_received: u64,
+    /// Messages sent to clients.
+    #[pyo3(get)]
+    pub messages_sent: u64,
+    /// Payload bytes sent to clients.
+    #[pyo3(get)]
+    pub bytes_sent: u64,
+    /// Items each queue has dropped, as returned by get_drop_counts.
+    #[pyo3(get)]
+    pub dropped: HashMap<&'static str, u64>,
+    /// Broadcast batches skipped by clients that fell a full queue behind, across all clients and topics.
+    #[pyo3(get)]
+    pub lag_skipped: u64,
+    /// Client messages waiting to be drained, by endpoint path ("/" for the default endpoint).
+    #[pyo3(get)]
+    pub client_message_queue_depths: HashMap<String, usize>,
+    /// Broadcast batches the furthest-behind client has yet to be sent, by endpoint path.
+    #[pyo3(get)]
+    pub broadcast_queue_depths: HashMap<String, usize>,
+    /// Topics with subscribers or a resync snapshot. Topics are forgotten once they have neither.
+    #[pyo3(get)]
+    pub topics: usize,
+}
+impl ServerStats {
+    fn collect(source: &MetricsSource, dropped: HashMap<&'static str, u64>) -> Self {
+        let counters = &source.counters;
+        let endpoints: Vec<(&str, &Endpoint)> = source.endpoints.iter().collect();
+        ServerStats {
+            uptime_s: counters.uptime().as_secs_f64(),
+            connected_clients: source.clients.count(),
+            peak_clients: counters.peak_clients(),
+            connections_total: counters.connections(),
+            messages_received: counters.messages_received(),
+            bytes_received: counters.bytes_received(),
+            messages_sent: counters.messages_sent(),
+            bytes_sent: counters.bytes_sent(),
+            dropped,
+            lag_skipped: counters.lag_skipped(),
+            client_message_queue_depths: endpoints.iter().map(|(path, endpoint)| (path.to_string(), endpoint.cli_msg_queue.depth())).collect(),
+            broadcast_queue_depths: endpoints.iter().map(|(path, endpoint)| (path.to_string(), endpoint.ser_msg_queue.depth())).collect(),
+            topics: source.topics.count(),
+        }
+    }
+}
+#[pyproto]
+impl pyo3::PyObjectProtocol for ServerStats {
+    fn __repr__(&self) -> String {
+        format!("ServerStats(uptime_s={:.1}, connected_clients={}, peak_clients={}, connections_total={}, messages_received={}, messages_sent={})", self.uptime_s, self.connected_clients, self.peak_clients, self.connections_total, self.messages_received, self.messages_sent)
+    }
+}
+
+/// A snapshot of a single connection, as returned by Server.get_clients and Server.get_client_info. Counts are since the client connected.
+#[pyclass(name = "ClientInfo")]
+#[derive(Clone)]
+pub struct ClientInfo {
+    /// The client ID, as reported in drain_new_client_events.
+    #[pyo3(get)]
+    pub client_id: ClientId,
+    /// The client's address.
+    #[pyo3(get)]
+    pub peer: String,
+    /// When the client connected, in seconds since the Unix epoch (as returned by time.time()).
+    #[pyo3(get)]
+    pub connected_at: f64,
+    /// The path and query string the client requested, e.g. "/hands?user=3".
+    #[pyo3(get)]
+    pub path: String,
+    /// The subprotocol selected when the client connected, if any.
+    #[pyo3(get)]
+    pub subprotocol: Option<String>,
+    /// The permessage-deflate parameters agreed when the client connected, as answered in its Sec-WebSocket-Extensions header (e.g. "permessage-deflate; server_no_context_takeover"), or None if its messages aren't compressed.
+    #[pyo3(get)]
+    pub compression: Option<String>,
+    /// Text and binary messages received from the client.
+    #[pyo3(get)]
+    pub messages_received: u64,
+    /// Payload bytes received from the client.
+    #[pyo3(get)]
+    pub bytes_received: u64,
+    /// Messages sent to the client.
+    #[pyo3(get)]
+    pub messages_sent: u64,
+    /// Payload bytes sent to the client.
+    #[pyo3(get)]
+    pub bytes_sent: u64,
+    /// Batches queued for only this client (see send_to_client) that haven't been sent yet.
+    #[pyo3(get)]
+    pub outbound_queue_depth: usize,
+    /// How many times the client fell a full broadcast queue behind (see drain_client_lag_events).
+    #[pyo3(get)]
+    pub lag_events: u64,
+    /// How many broadcast batches the client skipped by falling behind.
+    #[pyo3(get)]
+    pub lag_skipped: u64,
+    /// When anything was last received from or sent to the client, in seconds since the Unix epoch.
+    #[pyo3(get)]
+    pub last_activity: f64,
+    /// The round-trip time measured from the client's last answer to a keepalive ping, in seconds. None until it has answered one, or if the server wasn't started with `ping_interval_s`.
+    #[pyo3(get)]
+    pub rtt_s: Option<f64>,
+}
+impl From<ClientSnapshot> for ClientInfo {
+    fn from(client: ClientSnapshot) -> Self {
+        let stats = &client.stats;
+        ClientInfo {
+            client_id: client.client_id,
+            peer: client.peer,
+            connected_at: unix_seconds(client.connected_at),
+            path: client.path,
+            subprotocol: client.subprotocol,
+            compression: client.compression,
+            messages_received: stats.messages_received(),
+            bytes_received: stats.bytes_received(),
+            messages_sent: stats.messages_sent(),
+            bytes_sent: stats.bytes_sent(),
+            outbound_queue_depth: client.outbound_depth,
+            lag_events: stats.lag_events(),
+            lag_skipped: stats.lag_skipped(),
+            last_activity: unix_seconds(stats.last_activity()),
+            rtt_s: stats.rtt().map(|rtt| rtt.as_secs_f64()),
+        }
+    }
+}
+#[pyproto]
+impl pyo3::PyObjectProtocol for ClientInfo {
+    fn __repr__(&self) -> String {
+        format!("ClientInfo(client_id={}, peer={:?}, path={:?}, messages_received={}, messages_sent={})", self.client_id, self.peer, self.path, self.messages_received, self.messages_sent)
+    }
+}
+
+/// Seconds since the Unix epoch, as returned by time.time().
+fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).map(|since| since.as_secs_f64()).unwrap_or(0.0)
+}
+
+/// Capacity and overflow policy for one of a server's queues, passed to Server.start.
 ///
-/// Will return false if there are not currently any active subscribers (websocket clients), indicating no data was sent. False may also be returned if there was an error trying to access the broadcast channel in the first place (i.e. thread contention to access it).
+/// `overflow` says what happens to an item that arrives while the queue is full: "block" (wait for room), "drop_oldest", "drop_newest", or "disconnect" (drop the item and disconnect the client responsible). None means the queue's default.
+#[pyclass(name = "QueueConfig")]
+#[derive(Clone)]
+pub struct PyQueueConfig {
+    /// The most items the queue holds.
+    #[pyo3(get)]
+    pub capacity: usize,
+    overflow: Option<OverflowPolicy>,
+}
+#[pymethods]
+impl PyQueueConfig {
+    /// Raises ValueError if the capacity is 0 or the overflow policy isn't one of the above.
+    #[new]
+    #[args(capacity = "server::queue::DEFAULT_QUEUE_CAPACITY", overflow = "None")]
+    fn new(capacity: usize, overflow: Option<String>) -> PyResult<Self> {
+        if capacity == 0 {
+            return Err(pyo3::exceptions::PyValueError::new_err("A queue's capacity must be at least 1."));
+        }
+        let overflow = overflow.as_deref().map(OverflowPolicy::parse).transpose().map_err(pyo3::exceptions::PyValueError::new_err)?;
+        Ok(PyQueueConfig { capacity, overflow })
+    }
+
+    /// The overflow policy, or None for the queue's default.
+    #[getter]
+    fn overflow(&self) -> Option<&'static str> {
+        self.overflow.map(|overflow| overflow.as_str())
+    }
+}
+impl PyQueueConfig {
+    /// The config for a queue, filling in whatever wasn't given from the queue's default.
+    fn resolve(config: Option<PyQueueConfig>, default: QueueConfig) -> QueueConfig {
+        match config {
+            Some(config) => QueueConfig::new(config.capacity, config.overflow.unwrap_or(default.overflow)),
+            None => default,
+        }
+    }
+}
+#[pyproto]
+impl pyo3::PyObjectProtocol for PyQueueConfig {
+    fn __repr__(&self) -> String {
+        let overflow = self.overflow.map(|overflow| format!("{:?}", overflow.as_str())).unwrap_or_else(|| "None".to_string());
+        format!("QueueConfig(capacity={}, overflow={})", self.capacity, overflow)
+    }
+}
+
+/// How the server compresses messages for clients that offer the permessage-deflate extension, passed to Server.start.
 ///
-/// A return value of true does not guarantee all websocket clients received the message, as the tokio tasks for forwarding the messages to the clients must be able to receive the broadcast messages to forward them, which is subject to thread/task contention.
-#[pyfunction]
-pub fn try_send_messages(py: Python, messages: Vec<MessagePayload>) -> PyResult<()> {
-    py.allow_threads(|| {
-        // Create a Vec<WsMessage> out of the Vec<MessagePayload> so the backend is just working with the tungstenite WebSocket lib types.
-        let messages: Vec<WsMessage> = messages.into_iter().map(|msg| { match msg {
-            MessagePayload::Text(text)   => { WsMessage::Text(text) }
-            MessagePayload::Binary(bytes) => { WsMessage::Binary(bytes) }
-        }}).collect();
-    
-        let send_res = cs::read(&cs::CS_SER_MSG_TX, |tx| {
-            // Send!
-            tx.send(messages)
-        });
-        // Check whether, and precisely how, we failed to send.
-        if send_res.is_none() || send_res.as_ref().unwrap().is_err() {
-            let details = "Error reading server state for transmitter".to_string();
-            // if send_res.is_some() {
-            //     details = format!("{:?}", send_res.unwrap().err());return Err(pyo3::exceptions::PyBaseException::new_err(format!("Failed to send message. Details: {}", details)));
-            // }
+/// The window sizes are in bits, from 9 to 15; smaller windows use less memory per connection, at some cost to the compression ratio. `server_max_window_bits` is the window the server compresses with (clients can ask for a smaller one), and `client_max_window_bits` the one clients that offer to limit theirs are asked to use. With `server_no_context_takeover`, the server compresses each message on its own rather than referring back to earlier ones, so it doesn't keep the window between messages; `client_no_context_takeover` asks clients to do the same. Messages shorter than `threshold` bytes are sent uncompressed.
+#[pyclass(name = "CompressionConfig")]
+#[derive(Clone)]
+pub struct PyCompressionConfig {
+    #[pyo3(get)]
+    pub server_max_window_bits: u8,
+    #[pyo3(get)]
+    pub client_max_window_bits: u8,
+    #[pyo3(get)]
+    pub server_no_context_takeover: bool,
+    #[pyo3(get)]
+    pub client_no_context_takeover: bool,
+    #[pyo3(get)]
+    pub threshold: usize,
+}
+#[pymethods]
+impl PyCompressionConfig {
+    /// Raises ValueError if either window size is outside 9 to 15.
+    #[new]
+    #[args(server_max_window_bits = "server::compression::MAX_WINDOW_BITS", client_max_window_bits = "server::compression::MAX_WINDOW_BITS", server_no_context_takeover = "false", client_no_context_takeover = "false", threshold = "server::compression::DEFAULT_THRESHOLD")]
+    fn new(server_max_window_bits: u8, client_max_window_bits: u8, server_no_context_takeover: bool, client_no_context_takeover: bool, threshold: usize) -> PyResult<Self> {
+        let server_max_window_bits = CompressionConfig::check_window_bits("server_max_window_bits", server_max_window_bits).map_err(pyo3::exceptions::PyValueError::new_err)?;
+        let client_max_window_bits = CompressionConfig::check_window_bits("client_max_window_bits", client_max_window_bits).map_err(pyo3::exceptions::PyValueError::new_err)?;
+        Ok(PyCompressionConfig { server_max_window_bits, client_max_window_bits, server_no_context_takeover, client_no_context_takeover, threshold })
+    }
+}
+impl From<PyCompressionConfig> for CompressionConfig {
+    fn from(config: PyCompressionConfig) -> Self {
+        CompressionConfig {
+            server_max_window_bits: config.server_max_window_bits,
+            client_max_window_bits: config.client_max_window_bits,
+            server_no_context_takeover: config.server_no_context_takeover,
+            client_no_context_takeover: config.client_no_context_takeover,
+            threshold: config.threshold,
+        }
+    }
+}
+#[pyproto]
+impl pyo3::PyObjectProtocol for PyCompressionConfig {
+    fn __repr__(&self) -> String {
+        let py_bool = |value: bool| if value { "True" } else { "False" };
+        format!("CompressionConfig(server_max_window_bits={}, client_max_window_bits={}, server_no_context_takeover={}, client_no_context_takeover={}, threshold={})",
+            self.server_max_window_bits, self.client_max_window_bits, py_bool(self.server_no_context_takeover), py_bool(self.client_no_context_takeover), self.threshold)
+    }
+}
+
+lazy_static! {
+    /// The server instance operated on by the module-level functions, kept so scripts written against the single-server API keep working.
+    static ref DEFAULT_SERVER: Server = Server::new();
+}
+
+/// A single websocket server. Each Server owns its own channels, tokio runtime thread and config, so one process can run several servers on different ports with fully independent state.
+#[pyclass(name = "Server")]
+pub struct Server {
+    state: Arc<ConsumerState>,
+    thread: Mutex<Option<ServerThreadHandle>>,
+    config: RwLock<Option<ServerConfig>>,
+    /// Set while handlers are registered; see set_handlers.
+    dispatcher: Mutex<Option<Dispatcher>>,
+    /// Set by Rust consumers; see set_handshake_hook.
+    handshake_hook: RwLock<Option<Arc<dyn HandshakeHook>>>,
+}
+
+impl Default for Server {
+    fn default() -> Self { Self::new() }
+}
+
+impl Server {
+    /// Sets a handshake hook written in Rust, for Rust consumers: every later start() that isn't passed a Python `handshake_hook` uses it. Pass None to remove it.
+    pub fn set_handshake_hook(&self, hook: Option<Arc<dyn HandshakeHook>>) {
+        if let Ok(mut current) = self.handshake_hook.write() { *current = hook; }
+    }
+}
+
+#[pymethods]
+impl Server {
+    #[new]
+    pub fn new() -> Self {
+        Server {
+            state: Arc::new(ConsumerState::new()),
+            thread: Mutex::new(None),
+            config: RwLock::new(None),
+            dispatcher: Mutex::new(None),
+            handshake_hook: RwLock::new(None),
+        }
+    }
+
+    /// Starts the websocket server.
+    ///
+    /// Listens on the given port of `host`, which may be an IP address or a host name (default "127.0.0.1", so only local clients can connect; use "0.0.0.0" or "::" to accept clients from other machines). Alternatively, pass `addresses`, a list of "host:port" strings such as ["0.0.0.0:9000", "[::1]:9000"], to listen on several addresses at once. Clients from every address share the same events, messages and broadcasts.
+    ///
+    /// To serve wss:// (TLS) instead of ws://, pass `tls_cert` and `tls_key`: paths to a PEM certificate chain (leaf first) and its PEM private key. This requires quicksocket to be built with the "tls" cargo feature, which is off by default.
+    ///
+    /// Each of the server's queues can be given a QueueConfig with its capacity and overflow policy:
+    ///
+    /// - `new_client_queue` holds new client events until they're drained (default: 16, "block", which holds up new connections while it's full; "disconnect" closes connections that complete the handshake while it's full, with close code 1013),
+    /// - `client_message_queue` holds each endpoint's client messages until they're drained (default: 16, "block", which stops reading from a client while it's full; "disconnect" disconnects a client whose message arrives while it's full),
+    /// - `broadcast_queue` holds try_send_messages batches until every client has been sent them (default: 16, "drop_oldest", so clients that fall a full queue behind skip the batches they missed; "block" makes try_send_messages wait for them instead, and "disconnect" disconnects them). Its capacity is rounded up to a power of two.
+    /// - `disconnect_queue` holds client disconnect events until they're drained (default: 16, "block", which holds up closing connections while it's full, so every client reported as connected is also reported as disconnected). Raises ValueError for "disconnect", as its clients are already gone.
+    ///
+    /// Every dropped item is counted; see get_drop_counts.
+    ///
+    /// Whenever a client falls a full broadcast queue behind and skips batches, a ClientLagEvent is reported (see drain_client_lag_events). Unless broadcast_queue's policy is "disconnect", `lag_policy` then decides what happens to it: "continue" (the default) carries on from the next batch, "resync" first sends it the messages set with set_resync_snapshot, and "disconnect" disconnects it once it has skipped more than `lag_threshold` batches in total. Raises ValueError for any other lag policy.
+    ///
+    /// Each topic (see publish) is a broadcast of its own, with the same capacity, overflow policy and lag policy as `broadcast_queue`. If `topic_control` is True, clients can subscribe themselves to topics by sending the text message "quicksocket:subscribe:<topic>", and unsubscribe with "quicksocket:unsubscribe:<topic>". The server handles these messages itself, so they're never drained as client messages. Topic names are 1 to 256 bytes long, a client can be subscribed to at most 64 topics, and the server keeps at most 4096; a client's control message that breaks these limits is refused and recorded as an "invalid_request" error. Topics are forgotten once they have neither subscribers nor a resync snapshot.
+    ///
+    /// By default every request path is served alike. Pass `endpoints`, a list of paths such as ["/hands", "/debug"], to serve each as a separate endpoint with its own client messages and broadcast: pass the path as `endpoint` to drain_client_messages, try_send_messages and set_resync_snapshot to use them. The root path "/" stays the default endpoint, which the other methods use, and requests for any other path are rejected with a 404. Raises ValueError for a path that doesn't start with "/", has a query string, or is "/" itself.
+    ///
+    /// To negotiate a subprotocol, pass `subprotocols`, the names the server speaks in priority order (e.g. ["viz.v2", "viz.v1"]). Each client gets the first of them it offered in its Sec-WebSocket-Protocol header (see get_client_subprotocol), and clients that offered none of them are rejected with a 400. Without it, clients' subprotocols are ignored. Raises ValueError for a name that's empty or has spaces or separators such as ",".
+    ///
+    /// Any web page can open a websocket to the server, so pass `allowed_origins` to only accept browser clients from certain origins: exact origins such as "https://viz.example.com", or wildcards such as "https://*.example.com" for any of its subdomains. Requests with any other Origin header are rejected with a 403, and each rejection is recorded as a "security" error. Requests without an Origin header (which browsers always send) are accepted, so local non-browser clients keep working. Raises ValueError for an entry that isn't "scheme://host" or "scheme://host:port".
+    ///
+    /// To inspect each client's upgrade request before it's accepted, pass a `handshake_hook`: a callable that takes a HandshakeRequest and returns None to accept the connection, or a HandshakeDecision to accept it with extra response headers or reject it with an HTTP status and body. It's called from the server's thread, after the request has been routed to an endpoint, so it should return quickly. Rejected clients are never reported as connected.
+    ///
+    /// To serve a web app from the same port, pass `static_dir`, a directory to answer plain HTTP GET (and HEAD) requests from. Requests for a directory get its index.html. Websocket upgrade requests are handled as usual.
+    ///
+    /// To monitor the server, pass `metrics=True` to serve Prometheus metrics at /metrics on the same port: connected clients, total connections, handshake failures by reason, messages and bytes in each direction, broadcast batches skipped by lagging clients, and queue depths. Pass `metrics_address`, a "host:port" string such as "127.0.0.1:9100", to serve them on a listener of their own instead (which implies `metrics`).
+    ///
+    /// To compress messages, pass a CompressionConfig as `compression`. Clients that offer the permessage-deflate extension (as browsers do) then exchange compressed messages with the server; the rest are unaffected. See get_client_compression for what was agreed with each client.
+    ///
+    /// Dead connections (e.g. from a laptop that went to sleep) can otherwise linger until the OS gives up on them. Pass `ping_interval_s` to ping every client that often, and disconnect any that doesn't answer within `pong_timeout_s` (default: the same as the interval); it's reported as a "timed_out" disconnect. Each answer measures the client's round-trip time (see get_client_info). Raises ValueError unless both are positive.
+    ///
+    /// Returns False if the server is already running. Raises BindError if the addresses (or `metrics_address`) can't be resolved or bound, and QuicksocketError if the TLS files can't be loaded or `static_dir` isn't a directory. Either way, the error is also recorded (see drain_errors).
+    #[allow(clippy::too_many_arguments)]
+    #[args(port = "None", host = "\"127.0.0.1\"", addresses = "None", tls_cert = "None", tls_key = "None", new_client_queue = "None", client_message_queue = "None", broadcast_queue = "None", lag_policy = "\"continue\"", lag_threshold = "0", topic_control = "false", endpoints = "None", subprotocols = "None", allowed_origins = "None", handshake_hook = "None", compression = "None", static_dir = "None", metrics = "false", metrics_address = "None", ping_interval_s = "None", pong_timeout_s = "None", disconnect_queue = "None")]
+    pub fn start(&self, port: Option<u16>, host: &str, addresses: Option<Vec<String>>, tls_cert: Option<PathBuf>, tls_key: Option<PathBuf>, new_client_queue: Option<PyQueueConfig>, client_message_queue: Option<PyQueueConfig>, broadcast_queue: Option<PyQueueConfig>, lag_policy: &str, lag_threshold: u64, topic_control: bool, endpoints: Option<Vec<String>>, subprotocols: Option<Vec<String>>, allowed_origins: Option<Vec<String>>, handshake_hook: Option<PyObject>, compression: Option<PyCompressionConfig>, static_dir: Option<PathBuf>, metrics: bool, metrics_address: Option<String>, ping_interval_s: Option<f64>, pong_timeout_s: Option<f64>, disconnect_queue: Option<PyQueueConfig>) -> PyResult<bool> {
+        // For now, start can only be called if the server is not already running.
+        if self.is_running() {
+            self.state.record_error(ErrorKind::InvalidRequest, "Server is already running, can't invoke start().".to_string());
+            return Ok(false);
+        }
+
+        let lag_policy = LagPolicy::parse(lag_policy, lag_threshold).map_err(pyo3::exceptions::PyValueError::new_err)?;
+        let endpoints = ServerConfig::check_endpoints(endpoints.unwrap_or_default()).map_err(pyo3::exceptions::PyValueError::new_err)?;
+        let subprotocols = ServerConfig::check_subprotocols(subprotocols.unwrap_or_default()).map_err(pyo3::exceptions::PyValueError::new_err)?;
+        let allowed_origins = ServerConfig::check_origins(allowed_origins.unwrap_or_default()).map_err(pyo3::exceptions::PyValueError::new_err)?;
+        let keepalive = ServerConfig::check_keepalive(ping_interval_s, pong_timeout_s).map_err(pyo3::exceptions::PyValueError::new_err)?;
+        let disconnect_queue = ServerConfig::check_disconnect_queue(PyQueueConfig::resolve(disconnect_queue, ServerConfig::DEFAULT_DISCONNECT_QUEUE)).map_err(pyo3::exceptions::PyValueError::new_err)?;
+        let bind_addrs = ServerConfig::resolve_bind_addrs(port, host, addresses);
+        if let Err(err) = bind_addrs {
+            self.state.record_error(ErrorKind::Bind, err.clone());
+            return Err(error_to_py(ErrorKind::Bind, err));
+        }
+        let metrics_addr = match metrics_address {
+            Some(address) => match ServerConfig::resolve_bind_addrs(None, "", Some(vec![address])) {
+                Ok(addrs) => Some(addrs[0]),
+                Err(err) => {
+                    self.state.record_error(ErrorKind::Bind, err.clone());
+                    return Err(error_to_py(ErrorKind::Bind, err));
+                }
+            },
+            None => None,
+        };
+        let tls = match (tls_cert, tls_key) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig { cert_path, key_path }),
+            (None, None) => None,
+            _ => {
+                let err = "Both tls_cert and tls_key are required to serve wss://.".to_string();
+                self.state.record_error(ErrorKind::InvalidRequest, err.clone());
+                return Err(error_to_py(ErrorKind::InvalidRequest, err));
+            }
+        };
+        let config = ServerConfig {
+            bind_addrs: bind_addrs.unwrap(),
+            tls,
+            new_client_queue: PyQueueConfig::resolve(new_client_queue, ServerConfig::DEFAULT_NEW_CLIENT_QUEUE),
+            client_message_queue: PyQueueConfig::resolve(client_message_queue, ServerConfig::DEFAULT_CLIENT_MESSAGE_QUEUE),
+            broadcast_queue: PyQueueConfig::resolve(broadcast_queue, ServerConfig::DEFAULT_BROADCAST_QUEUE),
+            disconnect_queue,
+            lag_policy,
+            topic_control,
+            endpoints,
+            allowed_origins,
+            subprotocols,
+            handshake_hook: match handshake_hook {
+                Some(hook) => Some(python_handshake_hook(hook, self.state.errors.clone())),
+                None => self.handshake_hook.read().ok().and_then(|hook| hook.clone()),
+            },
+            compression: compression.map(CompressionConfig::from),
+            static_dir,
+            metrics: metrics && metrics_addr.is_none(),
+            metrics_addr,
+            keepalive,
+        };
+        let thread_handle = server::start(config.clone(), &self.state);
+        if let Err(kind) = thread_handle {
+            return Err(error_to_py(kind, self.state.try_get_last_error().unwrap_or_default()));
+        }
+
+        // A previous thread (if any) has already stopped serving, so it's fine to let its handle go.
+        if let Ok(mut thread) = self.thread.lock() { *thread = thread_handle.ok(); }
+        info!("Server started on {:?}.", config.bind_addrs);
+        if let Ok(mut cfg) = self.config.write() { *cfg = Some(config); }
+        Ok(true)
+    }
+
+    /// The port this server was last started on (the first one, if listening on several addresses), or None if it has never been started.
+    #[getter]
+    pub fn port(&self) -> Option<u16> {
+        self.config.read().ok().and_then(|cfg| cfg.as_ref().and_then(|cfg| cfg.bind_addrs.first().map(|addr| addr.port())))
+    }
+
+    /// The "host:port" addresses this server was last started on.
+    #[getter]
+    pub fn addresses(&self) -> Vec<String> {
+        self.config.read().ok().and_then(|cfg| cfg.as_ref().map(|cfg| {
+            cfg.bind_addrs.iter().map(|addr| addr.to_string()).collect()
+        })).unwrap_or_default()
+    }
+
+    /// Gets whether the server is running.
+    pub fn is_running(&self) -> bool {
+        let cs = &self.state;
+        cs.read(&cs.ser_alive_rx, |rx| *rx.borrow()).unwrap_or(false)
+    }
+
+    /// Requests that the websocket server shut down. The server will not shut down immediately but will stop serving as soon as e.g. it processes the shutdown request and any existing network requests are resolved.
+    pub fn shutdown(&self) {
+        let cs = &self.state;
+        let res = cs.mutate(&cs.ser_req_shutdown_tx, |tx| tx.send(true));
+        if let Err(kind) = res {
+            warn!("Failed to send shutdown request ({}).", kind.as_str());
+        }
+    }
+
+    /// Returns a string describing the nature of the last error the server encountered. No error has been detected if this function returns None.
+    pub fn get_last_error_string(&self) -> Option<String> {
+        self.state.try_get_last_error()
+    }
+
+    /// Returns how many items each queue has dropped since the server was last started, by any overflow policy, as a dict with "new_client_events", "client_messages", "broadcast" and "client_disconnect_events" keys. Broadcast drops count the batches refused under "drop_newest" plus, for every client that fell behind, each batch it skipped.
+    pub fn get_drop_counts(&self) -> HashMap<&'static str, u64> {
+        let cs = &self.state;
+        let mut counts = HashMap::new();
+        counts.insert("new_client_events", cs.read(&cs.cli_conn_queue, |queue| queue.dropped()).unwrap_or(0));
+        counts.insert("client_messages", cs.read(&cs.endpoints, |endpoints| endpoints.client_messages_dropped()).unwrap_or(0));
+        counts.insert("broadcast", cs.read(&cs.ser_msg_queue, |queue| queue.dropped()).unwrap_or(0));
+        counts.insert("client_disconnect_events", cs.read(&cs.cli_disconn_queue, |queue| queue.dropped()).unwrap_or(0));
+        counts
+    }
+
+    /// Returns a ServerStats snapshot of the server's uptime, connections, traffic, drops and queue depths since it was last started, or None if it has never been started. Reading it doesn't reset anything.
+    pub fn get_server_stats(&self) -> Option<ServerStats> {
+        let cs = &self.state;
+        let dropped = self.get_drop_counts();
+        cs.read(&cs.metrics, |source| ServerStats::collect(source, dropped)).ok()
+    }
+
+    /// Retrieves a List of ErrorEvents for every error the server encountered since this function was last called, oldest first. Only the most recent errors are kept (currently 256), so drain regularly if you care about all of them.
+    pub fn drain_errors(&self) -> Vec<ErrorEvent> {
+        self.state.errors.drain().into_iter().map(ErrorEvent::from).collect()
+    }
+
+    /// Retrieves a List (Rust: Vec<(ClientId, String)>) of all new client connection events that have occurred since this function was last called. Each event is a (client_id, peer_address) tuple; the client ID matches the one attached to that client's messages.
+    pub fn drain_new_client_events(&self, py: Python) -> Vec<(ClientId, String)> {
+        py.allow_threads(|| take_new_client_events(&self.state))
+    }
+
+    /// Retrieves a List of ClientConnectEvents for all clients that connected since this function was last called, including each client's upgrade request. Draws from the same queue as drain_new_client_events, so use one or the other.
+    pub fn drain_client_connect_events(&self, py: Python) -> Vec<ClientConnectEvent> {
+        py.allow_threads(|| take_client_connect_events(&self.state))
+    }
+
+    /// Retrieves a List of ClientDisconnectEvents for all client connections that have closed since this function was last called. Only clients that completed the websocket handshake are reported.
+    pub fn drain_client_disconnect_events(&self, py: Python) -> Vec<ClientDisconnectEvent> {
+        py.allow_threads(|| take_client_disconnect_events(&self.state))
+    }
+
+    /// Retrieves a List of ClientLagEvents for every time a client fell behind the server broadcast since this function was last called. Only the most recent events are kept (currently 16), so drain regularly if you care about all of them.
+    pub fn drain_client_lag_events(&self, py: Python) -> Vec<ClientLagEvent> {
+        py.allow_threads(|| take_client_lag_events(&self.state))
+    }
+
+    /// Blocks until there is at least one new client, client message, client lag or client disconnect to drain, or until `timeout_s` seconds have passed, then drains them all at once. The GIL is released while waiting, so other Python threads keep running.
+    ///
+    /// Messages from clients of every endpoint are drained: the default endpoint's into client_messages, and the registered endpoints' (see start) into endpoint_messages, by path.
+    ///
+    /// Returns an EventBatch, which is empty (and falsy) if the wait timed out. Returns right away if the server hasn't been started or has been asked to shut down.
+    pub fn wait_for_events(&self, py: Python, timeout_s: f64) -> PyResult<EventBatch> {
+        if !timeout_s.is_finite() || timeout_s < 0.0 {
+            return Err(pyo3::exceptions::PyValueError::new_err("timeout_s must be a non-negative number of seconds."));
+        }
+        let deadline = Instant::now() + Duration::from_secs_f64(timeout_s);
+
+        Ok(py.allow_threads(|| {
+            let signal = &self.state.event_signal;
+            loop {
+                // Read the signal count before draining, so an event that arrives in between still ends the wait.
+                let seen = signal.count();
+                let mut batch = take_event_batch(&self.state);
+                batch.endpoint_messages = take_endpoint_messages(&self.state);
+                if !batch.is_empty() || !self.state.is_serving() || !signal.wait_past(seen, deadline) {
+                    return batch;
+                }
+            }
+        }))
+    }
+
+    /// Send messages to all connected clients. The socket stream is flushed after buffering each message in the argument List, so it's better to call this once per 'update,' rather than calling this method multiple times if multiple messages are all available to be sent.
+    ///
+    /// The List may contain strings or bytes.
+    ///
+    /// Raises ServerNotRunningError if the server has never been started, or QuicksocketError if the server state couldn't be accessed. Sending when no clients are connected is not an error; the messages simply go nowhere.
+    ///
+    /// If a client is a full broadcast queue behind, what happens depends on the queue's overflow policy (see start). Under "block", this waits (without holding the GIL) until the client catches up or the server stops; under "drop_newest", the messages are dropped.
+    ///
+    /// If the server was started with `endpoints`, this sends to the clients of the default endpoint ("/"); pass an `endpoint` path to send to the clients of that endpoint instead. Raises ValueError for a path that isn't one of the server's endpoints.
+    ///
+    /// A successful return of true does not guarantee all websocket clients received the message, as the tokio tasks for forwarding the messages to the clients must be able to receive the broadcast messages to forward them, which is subject to thread/task contention.
+    #[args(endpoint = "None")]
+    pub fn try_send_messages(&self, py: Python, messages: Vec<MessagePayload>, endpoint: Option<String>) -> PyResult<()> {
+        let cs = &self.state;
+        py.allow_threads(|| {
+            // Create a Vec<WsMessage> out of the Vec<MessagePayload> so the backend is just working with the tungstenite WebSocket lib types.
+            let messages: Vec<WsMessage> = messages.into_iter().map(WsMessage::from).collect();
+
+            // Clone the queue out of the lock, since sending may block.
+            let queue = match &endpoint {
+                None => cs.read(&cs.ser_msg_queue, |queue| queue.clone()),
+                Some(path) => match with_endpoint(cs, path, |endpoint| endpoint.ser_msg_queue.clone()) {
+                    // An unknown endpoint raises ValueError right away.
+                    Ok(queue) => Ok(queue?),
+                    Err(kind) => Err(kind),
+                },
+            };
+            let send_res = queue.map(|queue| {
+                // Send!
+                queue.send(messages, || cs.is_serving())
+            });
+            // Check whether, and precisely how, we failed to send.
             // For now, only return an error if the send fails unrelated to the number of receivers, because we simply expect the message to go nowhere if there are no connected clients.
+            if let Err(kind) = send_res {
+                return Err(error_to_py(kind, format!("Failed to send message. Details: {}", send_failure_details(kind))));
+            }
+
+            Ok(())
+        })
+    }
 
-            if send_res.is_none() {
-                return Err(pyo3::exceptions::PyBaseException::new_err(format!("Failed to send message. Details: {}", details)));
+    /// Send messages to every client subscribed to a topic (see subscribe_client). Each topic is a separate broadcast, so clients only receive the topics they joined; otherwise this works just like try_send_messages, including its overflow policy.
+    ///
+    /// Publishing to a topic nobody is subscribed to is not an error; the messages simply go nowhere. Raises ServerNotRunningError if the server has never been started.
+    pub fn publish(&self, py: Python, topic: String, messages: Vec<MessagePayload>) -> PyResult<()> {
+        let cs = &self.state;
+        py.allow_threads(|| {
+            let messages: Vec<WsMessage> = messages.into_iter().map(WsMessage::from).collect();
+            let send_res = cs.read(&cs.topics, |topics| topics.get(&topic)).map(|subscribed| {
+                if let Some(subscribed) = subscribed { subscribed.queue.send(messages, || cs.is_serving()); }
+            });
+            if let Err(kind) = send_res {
+                return Err(error_to_py(kind, format!("Failed to publish to topic {:?}. Details: {}", topic, send_failure_details(kind))));
             }
-    
-            // weakly_record_error(format!("Failed to send message. Details: {}", details));
-            // return Err(pyo3::exceptions::PyBaseException::new_err(format!("Failed to send message. Details: {}", details)));
+            Ok(())
+        })
+    }
+
+    /// Subscribes a client to a topic, so it receives everything published to the topic from now on. Returns False if the client isn't connected, is already subscribed to 64 topics, or if the server already has 4096 topics (see drain_errors for which). Raises ValueError for a topic name that's empty or longer than 256 bytes.
+    pub fn subscribe_client(&self, client_id: ClientId, topic: &str) -> PyResult<bool> {
+        topics::check_name(topic).map_err(|err| pyo3::exceptions::PyValueError::new_err(format!("Invalid topic {:?}: {}.", topic, err)))?;
+        let cs = &self.state;
+        let topics = match cs.read(&cs.topics, |topics| topics.clone()) { Ok(topics) => topics, Err(_) => return Ok(false) };
+        Ok(cs.read(&cs.clients, |clients| {
+            let res = clients.subscribe(client_id, topic, &topics);
+            if let Err(err) = &res { record_client_send_error(cs, client_id, err); }
+            res.is_ok()
+        }).unwrap_or(false))
+    }
+
+    /// Unsubscribes a client from a topic. Returns False if the client isn't connected or wasn't subscribed to the topic.
+    pub fn unsubscribe_client(&self, client_id: ClientId, topic: &str) -> bool {
+        let cs = &self.state;
+        cs.read(&cs.clients, |clients| {
+            let res = clients.unsubscribe(client_id, topic);
+            if let Err(err) = &res { record_client_send_error(cs, client_id, err); }
+            res.unwrap_or(false)
+        }).unwrap_or(false)
+    }
+
+    /// The topics a client is subscribed to, whether by subscribe_client or by its own control messages, in name order. Empty if the client isn't connected.
+    pub fn get_client_topics(&self, client_id: ClientId) -> Vec<String> {
+        let cs = &self.state;
+        cs.read(&cs.clients, |clients| clients.topics(client_id).unwrap_or_default()).unwrap_or_default()
+    }
+
+    /// Sets the messages sent to a client that falls behind the server broadcast, under the "resync" lag policy (see start), in place of the batches it skipped. Typically this is the latest full state, which the skipped batches were updates to. Keep it up to date as the state changes; pass None to clear it, after which lagging clients just carry on.
+    ///
+    /// Pass a `topic` to set the snapshot for clients that fall behind on that topic instead. The List may contain strings or bytes. The server broadcast's snapshot can be set before the server starts, and is kept across restarts; topic snapshots need the server to be running (raising ServerNotRunningError otherwise), and only last until it stops. A topic with a snapshot counts towards the server's 4096 topics until it's cleared, raising QuicksocketError once they're used up; a topic name that's empty or longer than 256 bytes raises ValueError.
+    ///
+    /// Likewise, pass an `endpoint` path to set the snapshot for clients that fall behind on that endpoint's broadcast (see start); this also needs the server to be running, and raises ValueError for a path that isn't one of its endpoints. Pass at most one of `topic` and `endpoint`.
+    #[args(topic = "None", endpoint = "None")]
+    pub fn set_resync_snapshot(&self, messages: Option<Vec<MessagePayload>>, topic: Option<String>, endpoint: Option<String>) -> PyResult<()> {
+        let messages = messages.map(|messages| messages.into_iter().map(WsMessage::from).collect());
+        let cs = &self.state;
+        match (topic, endpoint) {
+            (None, None) => { cs.resync_snapshot.set(messages); Ok(()) }
+            (Some(topic), None) => match cs.read(&cs.topics, |topics| topics.set_snapshot(&topic, messages)) {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(TopicError::InvalidName)) => Err(pyo3::exceptions::PyValueError::new_err(format!("Invalid topic {:?}: {}.", topic, TopicError::InvalidName))),
+                Ok(Err(err)) => Err(error_to_py(ErrorKind::InvalidRequest, format!("Failed to set the resync snapshot for topic {:?}: {}.", topic, err))),
+                Err(kind) => Err(error_to_py(kind, format!("Failed to set the resync snapshot for topic {:?}. Details: {}", topic, send_failure_details(kind)))),
+            },
+            (None, Some(path)) => match with_endpoint(cs, &path, |endpoint| endpoint.snapshot.set(messages)) {
+                Ok(res) => res,
+                Err(kind) => Err(error_to_py(kind, format!("Failed to set the resync snapshot for endpoint {:?}. Details: {}", path, send_failure_details(kind)))),
+            },
+            (Some(_), Some(_)) => Err(pyo3::exceptions::PyValueError::new_err("Pass at most one of topic and endpoint.")),
         }
-    
-        Ok(())
-    })
+    }
+
+    /// The path and query string a client requested when it connected, e.g. "/hands?user=3". None if the client isn't connected.
+    pub fn get_client_path(&self, client_id: ClientId) -> Option<String> {
+        let cs = &self.state;
+        cs.read(&cs.clients, |clients| clients.path(client_id).ok()).ok().flatten()
+    }
+
+    /// The subprotocol selected when a client connected (see start). None if the client isn't connected, or the server wasn't started with any subprotocols.
+    pub fn get_client_subprotocol(&self, client_id: ClientId) -> Option<String> {
+        let cs = &self.state;
+        cs.read(&cs.clients, |clients| clients.subprotocol(client_id).ok()).ok().flatten().flatten()
+    }
+
+    /// The permessage-deflate parameters agreed when a client connected (see start), as the server answered them in its Sec-WebSocket-Extensions header, e.g. "permessage-deflate; server_no_context_takeover". None if the client isn't connected, or its messages aren't compressed.
+    pub fn get_client_compression(&self, client_id: ClientId) -> Option<String> {
+        let cs = &self.state;
+        cs.read(&cs.clients, |clients| clients.compression(client_id).ok()).ok().flatten().flatten()
+    }
+
+    /// Returns a ClientInfo for every connected client, in client ID order: its address, connect time, path, subprotocol, compression, traffic in each direction, outbound queue depth, lag and last activity.
+    pub fn get_clients(&self) -> Vec<ClientInfo> {
+        let cs = &self.state;
+        cs.read(&cs.clients, |clients| clients.snapshots()).unwrap_or_default().into_iter().map(ClientInfo::from).collect()
+    }
+
+    /// Returns a ClientInfo for a single client, or None if it isn't connected.
+    pub fn get_client_info(&self, client_id: ClientId) -> Option<ClientInfo> {
+        let cs = &self.state;
+        cs.read(&cs.clients, |clients| clients.snapshot(client_id).ok()).ok().flatten().map(ClientInfo::from)
+    }
+
+    /// Send messages to a single client, identified by the client ID reported in drain_new_client_events and drain_client_messages. Like try_send_messages, the whole List is flushed to the client at once.
+    ///
+    /// Returns False if the messages couldn't be queued: either the client is no longer connected, or its outbound queue is full because the connection isn't keeping up. Never blocks.
+    pub fn send_to_client(&self, py: Python, client_id: ClientId, messages: Vec<MessagePayload>) -> bool {
+        let cs = &self.state;
+        py.allow_threads(|| {
+            let messages: Vec<WsMessage> = messages.into_iter().map(WsMessage::from).collect();
+            cs.read(&cs.clients, |clients| {
+                let res = clients.try_send(client_id, messages);
+                if let Err(err) = &res { record_client_send_error(cs, client_id, err); }
+                res.is_ok()
+            }).unwrap_or(false)
+        })
+    }
+
+    /// Send the same messages to each of the listed clients. Clients that aren't connected or aren't keeping up are skipped, as in send_to_client.
+    ///
+    /// Returns the number of clients the messages were queued for.
+    pub fn send_to_clients(&self, py: Python, client_ids: Vec<ClientId>, messages: Vec<MessagePayload>) -> usize {
+        let cs = &self.state;
+        py.allow_threads(|| {
+            let messages: Vec<WsMessage> = messages.into_iter().map(WsMessage::from).collect();
+            cs.read(&cs.clients, |clients| {
+                client_ids.iter().filter(|&&client_id| {
+                    let res = clients.try_send(client_id, messages.clone());
+                    if let Err(err) = &res { record_client_send_error(cs, client_id, err); }
+                    res.is_ok()
+                }).count()
+            }).unwrap_or(0)
+        })
+    }
+
+    /// Closes a single client's connection: sends it a close frame with the given code and reason, then stops the connection's tasks once the client answers (or after a few seconds if it doesn't). The client's disconnect event reports this code and reason.
+    ///
+    /// Returns False if the client isn't connected. Raises ValueError if the code can't be sent in a close frame (e.g. the reserved 1005 and 1006), or if the reason is longer than 123 bytes.
+    #[args(code = "1000", reason = "String::new()")]
+    pub fn disconnect_client(&self, py: Python, client_id: ClientId, code: u16, reason: String) -> PyResult<bool> {
+        let close_code = CloseCode::from(code);
+        if !close_code.is_allowed() {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!("{} is not a valid close code to send.", code)));
+        }
+        if reason.len() > 123 {
+            return Err(pyo3::exceptions::PyValueError::new_err("The close reason can be at most 123 bytes long."));
+        }
+
+        let cs = &self.state;
+        Ok(py.allow_threads(|| {
+            cs.read(&cs.clients, |clients| {
+                let res = clients.request_disconnect(client_id, CloseFrame { code: close_code, reason: Cow::Owned(reason) });
+                if let Err(err) = &res { record_client_send_error(cs, client_id, err); }
+                res.is_ok()
+            }).unwrap_or(false)
+        }))
+    }
+
+    /// Drains all messages pending from all clients and returns them as a list[tuple[int, str | bytes]] of (client_id, payload) pairs, in the order they were received. The client ID identifies the connection that sent the message, matching the ID reported in drain_new_client_events.
+    ///
+    /// If the server was started with `endpoints`, this drains the messages from clients of the default endpoint ("/"), as do wait_for_events, recv_message and the handlers; pass an `endpoint` path to drain the messages from clients of that endpoint instead. Raises ValueError for a path that isn't one of the server's endpoints.
+    #[args(endpoint = "None")]
+    pub fn drain_client_messages(&self, py: Python, endpoint: Option<String>) -> PyResult<Vec<(ClientId, MessagePayload)>> {
+        let cs = &self.state;
+        py.allow_threads(|| match endpoint {
+            None => Ok(take_client_messages(cs)),
+            Some(path) => match with_endpoint(cs, &path, |endpoint| endpoint.cli_msg_queue.drain()) {
+                Ok(res) => Ok(res?.into_iter().filter_map(client_message_payload).collect()),
+                // Like the default endpoint, there's nothing to drain until the server starts.
+                Err(_) => Ok(vec![]),
+            },
+        })
+    }
+
+    /// Registers Python callables to be called for every event, instead of draining events yourself. They're called from a dispatcher thread (holding the GIL only while calling them), in the order the events were drained:
+    ///
+    /// - on_connect(client_id, peer_address) for each new client,
+    /// - on_message(client_id, payload) for each text (str) or binary (bytes) message from a client of the default endpoint,
+    /// - on_lag(client_id, event) for each time a client fell behind the server broadcast, with its ClientLagEvent,
+    /// - on_disconnect(client_id, event) for each closed connection, with its ClientDisconnectEvent.
+    ///
+    /// While handlers are registered, the dispatcher takes every event from the server's queues, so the drain_* methods, wait_for_events, recv_message and next_event won't see any. Messages to the registered endpoints (see start) aren't dispatched; drain them with drain_client_messages or wait_for_events. Events without a handler are dropped. An exception raised by a handler is recorded as a "callback" error (see drain_errors) and doesn't stop the dispatcher.
+    ///
+    /// Replaces any handlers registered before. Call with no handlers to stop dispatching. Handlers can be registered before or after the server starts.
+    #[args(on_connect = "None", on_message = "None", on_disconnect = "None", on_lag = "None")]
+    pub fn set_handlers(&self, on_connect: Option<PyObject>, on_message: Option<PyObject>, on_disconnect: Option<PyObject>, on_lag: Option<PyObject>) {
+        let handlers = Handlers { on_connect, on_message, on_lag, on_disconnect };
+        let any = handlers.on_connect.is_some() || handlers.on_message.is_some() || handlers.on_lag.is_some() || handlers.on_disconnect.is_some();
+        if let Ok(mut dispatcher) = self.dispatcher.lock() {
+            // Dropping the previous dispatcher stops it.
+            *dispatcher = if any { Some(Dispatcher::start(self.state.clone(), handlers)) } else { None };
+        }
+    }
+
+    /// Receives the next message from any client, for asyncio consumers. Returns an awaitable that resolves to a (client_id, payload) tuple, as in drain_client_messages; call it from a coroutine running on an asyncio event loop. The event loop isn't blocked while waiting.
+    ///
+    /// When awaited, raises ServerNotRunningError if the server isn't running or stops before a message arrives. Messages come from the same queue as drain_client_messages and wait_for_events, so each message is delivered to only one of them.
+    pub fn recv_message(&self, py: Python) -> PyResult<PyObject> {
+        asyncio_bridge::receive(py, self.state.clone(), take_client_message_py, raise_not_running)
+    }
+
+    /// Receives the next client connection, lag or disconnection, for asyncio consumers. Returns an awaitable that resolves to a ClientConnectEvent, ClientLagEvent or ClientDisconnectEvent, or to None once the server isn't running and every event has been received. Call it from a coroutine running on an asyncio event loop.
+    ///
+    /// Events come from the same queues as drain_new_client_events, drain_client_lag_events and drain_client_disconnect_events. Connections are delivered first and disconnections last, so a client's connection always comes before its lag events, and those before its disconnection.
+    pub fn next_event(&self, py: Python) -> PyResult<PyObject> {
+        asyncio_bridge::receive(py, self.state.clone(), take_client_event_py, no_more_events)
+    }
 }
 
-/// Drains all messages pending from all clients and returns them as a list[bytes]. Note that clients are not distinguished, so clients will have to self-identify in their messages, or the library will need to change to return messages per-client or bundled with client connection info.
-#[pyfunction]
-pub fn drain_client_messages(py: Python) -> Vec<MessagePayload> {
-    py.allow_threads(|| {
-        let drained_messages = cs::mutate(&cs::CS_CLI_MSG_RX, |rx| {
-            let mut messages = vec![];
-    
-            // Apparently there's an issue with try_recv() where messages may not be immediately available once submitted to the channel (they may be subject to a slight delay).
-            // Details: https://github.com/tokio-rs/tokio/issues/3350
-            // TODO: May look into using flume, with some tokio-based sync primitive on the tokio task side.
-            while let Some(Some(cli_msg)) = rx.recv().now_or_never() {
-                // Convert the message into the python-convertible MessagePayload type.
-                // For now, we ignore the ping/pong and Close websocket messages.
-                let converted_msg = match cli_msg {
-                    WsMessage::Text(text)    => { Some(MessagePayload::Text(text)) }
-                    WsMessage::Binary(bytes) => { Some(MessagePayload::Binary(bytes)) }
-                    WsMessage::Ping(_)       => { None }
-                    WsMessage::Pong(_)       => { None }
-                    WsMessage::Close(_)      => { None }
-                };
-                if converted_msg.is_some() { messages.push(converted_msg.unwrap()); }
-            }
-    
-            messages
-        });
-        if drained_messages.is_none() {
-            return vec![];
-        }
-        let drained_messages = drained_messages.unwrap();
-    
-        drained_messages
+// Draining
+// --------
+//
+// Shared by the drain_* methods, wait_for_events and the handler dispatcher. These expect to be called with the GIL released.
+
+pub(crate) fn take_new_client_events(cs: &ConsumerState) -> Vec<(ClientId, String)> {
+    cs.read(&cs.cli_conn_queue, |queue| {
+        queue.drain().into_iter().map(|evt| (evt.client_id, evt.peer)).collect()
+    }).unwrap_or_default()
+}
+
+pub(crate) fn take_client_connect_events(cs: &ConsumerState) -> Vec<ClientConnectEvent> {
+    cs.read(&cs.cli_conn_queue, |queue| {
+        queue.drain().into_iter().map(ClientConnectEvent::from).collect()
+    }).unwrap_or_default()
+}
+
+pub(crate) fn take_client_disconnect_events(cs: &ConsumerState) -> Vec<ClientDisconnectEvent> {
+    cs.read(&cs.cli_disconn_queue, |queue| {
+        queue.drain().into_iter().map(ClientDisconnectEvent::from).collect()
+    }).unwrap_or_default()
+}
+
+pub(crate) fn take_client_lag_events(cs: &ConsumerState) -> Vec<ClientLagEvent> {
+    cs.read(&cs.cli_lag_queue, |queue| {
+        queue.drain().into_iter().map(ClientLagEvent::from).collect()
+    }).unwrap_or_default()
+}
+
+pub(crate) fn take_client_messages(cs: &ConsumerState) -> Vec<(ClientId, MessagePayload)> {
+    cs.read(&cs.cli_msg_queue, |queue| {
+        queue.drain().into_iter().filter_map(client_message_payload).collect()
+    }).unwrap_or_default()
+}
+
+/// Drains the messages from clients of every registered endpoint, by path. Endpoints without messages are left out.
+fn take_endpoint_messages(cs: &ConsumerState) -> HashMap<String, Vec<(ClientId, MessagePayload)>> {
+    cs.read(&cs.endpoints, |endpoints| {
+        endpoints.iter().filter(|(path, _)| *path != DEFAULT_ENDPOINT_PATH).filter_map(|(path, endpoint)| {
+            let messages: Vec<(ClientId, MessagePayload)> = endpoint.cli_msg_queue.drain().into_iter().filter_map(client_message_payload).collect();
+            if messages.is_empty() { None } else { Some((path.to_string(), messages)) }
+        }).collect()
+    }).unwrap_or_default()
+}
+
+/// Drains all the event queues at once, except the registered endpoints' client messages (see take_endpoint_messages).
+pub(crate) fn take_event_batch(cs: &ConsumerState) -> EventBatch {
+    EventBatch {
+        new_client_events: take_new_client_events(cs),
+        client_messages: take_client_messages(cs),
+        endpoint_messages: HashMap::new(),
+        client_lag_events: take_client_lag_events(cs),
+        client_disconnect_events: take_client_disconnect_events(cs),
+    }
+}
+
+/// Converts a client message into the python-convertible MessagePayload type. Only text and binary messages are queued for the consumer, but to be safe, anything else is ignored.
+fn client_message_payload((client_id, cli_msg): (ClientId, WsMessage)) -> Option<(ClientId, MessagePayload)> {
+    match cli_msg {
+        WsMessage::Text(text)    => { Some((client_id, MessagePayload::Text(text))) }
+        WsMessage::Binary(bytes) => { Some((client_id, MessagePayload::Binary(bytes))) }
+        WsMessage::Ping(_)       => { None }
+        WsMessage::Pong(_)       => { None }
+        WsMessage::Close(_)      => { None }
+    }
+}
+
+// asyncio receives
+// ----------------
+//
+// How recv_message and next_event take their results; see asyncio_bridge.
+
+fn take_client_message_py(py: Python, cs: &ConsumerState) -> Option<PyObject> {
+    let msg = cs.read(&cs.cli_msg_queue, |queue| {
+        std::iter::from_fn(|| queue.pop()).find_map(client_message_payload)
+    }).ok().flatten();
+    msg.map(|msg| msg.into_py(py))
+}
+
+fn take_client_event_py(py: Python, cs: &ConsumerState) -> Option<PyObject> {
+    let connect = cs.read(&cs.cli_conn_queue, |queue| queue.pop()).ok().flatten();
+    if let Some(evt) = connect {
+        return Some(ClientConnectEvent::from(evt).into_py(py));
+    }
+    let lag = cs.read(&cs.cli_lag_queue, |queue| queue.pop()).ok().flatten();
+    if let Some(evt) = lag {
+        return Some(ClientLagEvent::from(evt).into_py(py));
+    }
+    let disconnect = cs.read(&cs.cli_disconn_queue, |queue| queue.pop()).ok().flatten();
+    disconnect.map(|evt| ClientDisconnectEvent::from(evt).into_py(py))
+}
+
+fn raise_not_running(_py: Python) -> PyResult<PyObject> {
+    Err(ServerNotRunningError::new_err("The server isn't running."))
+}
+
+fn no_more_events(py: Python) -> PyResult<PyObject> {
+    Ok(py.None())
+}
+
+/// Does something with one of the server's endpoints, by path. The outer error is as for ConsumerState::read; the inner one is a ValueError for a path that isn't one of the server's endpoints.
+fn with_endpoint<T>(cs: &ConsumerState, path: &str, f: impl FnOnce(&Endpoint) -> T) -> Result<PyResult<T>, ErrorKind> {
+    cs.read(&cs.endpoints, |endpoints| match endpoints.get(Some(path)) {
+        Some(endpoint) => Ok(f(endpoint)),
+        None => Err(pyo3::exceptions::PyValueError::new_err(format!("{:?} isn't one of the server's endpoints.", path))),
     })
 }
 
+/// Describes why a send to every client (or every subscriber) failed.
+fn send_failure_details(kind: ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::NotRunning => "The server hasn't been started.",
+        _                     => "Error reading server state for transmitter.",
+    }
+}
+
+fn record_client_send_error(cs: &ConsumerState, client_id: ClientId, err: &ClientSendError) {
+    let (kind, msg) = match err {
+        ClientSendError::NotConnected => (ErrorKind::ClientNotConnected, format!("Failed to reach client {}: the client is not connected.", client_id)),
+        ClientSendError::QueueFull    => (ErrorKind::QueueFull, format!("Failed to send to client {}: its outbound queue is full.", client_id)),
+        ClientSendError::Topic(err)   => (ErrorKind::InvalidRequest, format!("Failed to subscribe client {} to a topic: {}.", client_id, err)),
+    };
+    cs.record_client_error(kind, msg, client_id);
+}
+
+// Module-level API
+// ----------------
+//
+// These operate on DEFAULT_SERVER, a process-wide Server instance.
+
+/// Starts the websocket server. See Server.start.
+#[allow(clippy::too_many_arguments)]
+#[pyfunction(port = "None", host = "\"127.0.0.1\"", addresses = "None", tls_cert = "None", tls_key = "None", new_client_queue = "None", client_message_queue = "None", broadcast_queue = "None", lag_policy = "\"continue\"", lag_threshold = "0", topic_control = "false", endpoints = "None", subprotocols = "None", allowed_origins = "None", handshake_hook = "None", compression = "None", static_dir = "None", metrics = "false", metrics_address = "None", ping_interval_s = "None", pong_timeout_s = "None", disconnect_queue = "None")]
+pub fn start_server(port: Option<u16>, host: &str, addresses: Option<Vec<String>>, tls_cert: Option<PathBuf>, tls_key: Option<PathBuf>, new_client_queue: Option<PyQueueConfig>, client_message_queue: Option<PyQueueConfig>, broadcast_queue: Option<PyQueueConfig>, lag_policy: &str, lag_threshold: u64, topic_control: bool, endpoints: Option<Vec<String>>, subprotocols: Option<Vec<String>>, allowed_origins: Option<Vec<String>>, handshake_hook: Option<PyObject>, compression: Option<PyCompressionConfig>, static_dir: Option<PathBuf>, metrics: bool, metrics_address: Option<String>, ping_interval_s: Option<f64>, pong_timeout_s: Option<f64>, disconnect_queue: Option<PyQueueConfig>) -> PyResult<bool> {
+    DEFAULT_SERVER.start(port, host, addresses, tls_cert, tls_key, new_client_queue, client_message_queue, broadcast_queue, lag_policy, lag_threshold, topic_control, endpoints, subprotocols, allowed_origins, handshake_hook, compression, static_dir, metrics, metrics_address, ping_interval_s, pong_timeout_s, disconnect_queue)
+}
+
+/// Gets whether the server is running.
+#[pyfunction]
+pub fn is_server_running() -> bool {
+    DEFAULT_SERVER.is_running()
+}
+
+/// Requests that the websocket server shut down. The server will not shut down immediately but will stop serving as soon as e.g. it processes the shutdown request and any existing network requests are resolved.
+#[pyfunction]
+pub fn shutdown_server() {
+    DEFAULT_SERVER.shutdown()
+}
+
+/// Returns a string describing the nature of the last error the server encountered. No error has been detected if this function returns None.
+#[pyfunction]
+pub fn get_last_error_string() -> Option<String> {
+    DEFAULT_SERVER.get_last_error_string()
+}
+
+/// Returns a snapshot of the server's counters and queues. See Server.get_server_stats.
+#[pyfunction]
+pub fn get_server_stats() -> Option<ServerStats> {
+    DEFAULT_SERVER.get_server_stats()
+}
+
+/// Returns how many items each queue has dropped since the server was last started. See Server.get_drop_counts.
+#[pyfunction]
+pub fn get_drop_counts() -> HashMap<&'static str, u64> {
+    DEFAULT_SERVER.get_drop_counts()
+}
+
+/// Retrieves a List of ErrorEvents for every error the server encountered since this function was last called. See Server.drain_errors.
+#[pyfunction]
+pub fn drain_errors() -> Vec<ErrorEvent> {
+    DEFAULT_SERVER.drain_errors()
+}
+
+/// Retrieves a List (Rust: Vec<(ClientId, String)>) of all new client connection events that have occurred since this function was last called.
+#[pyfunction]
+pub fn drain_new_client_events(py: Python) -> Vec<(ClientId, String)> {
+    DEFAULT_SERVER.drain_new_client_events(py)
+}
+
+/// Retrieves a List of ClientConnectEvents for all clients that connected since this function was last called, including each client's upgrade request.
+#[pyfunction]
+pub fn drain_client_connect_events(py: Python) -> Vec<ClientConnectEvent> {
+    DEFAULT_SERVER.drain_client_connect_events(py)
+}
+
+/// Retrieves a List of ClientDisconnectEvents for all client connections that have closed since this function was last called.
+#[pyfunction]
+pub fn drain_client_disconnect_events(py: Python) -> Vec<ClientDisconnectEvent> {
+    DEFAULT_SERVER.drain_client_disconnect_events(py)
+}
+
+/// Retrieves a List of ClientLagEvents for every time a client fell behind the server broadcast since this function was last called. See Server.drain_client_lag_events.
+#[pyfunction]
+pub fn drain_client_lag_events(py: Python) -> Vec<ClientLagEvent> {
+    DEFAULT_SERVER.drain_client_lag_events(py)
+}
+
+/// Sets the messages sent to clients that fall behind the server broadcast (or a topic, or an endpoint's broadcast), under the "resync" lag policy. See Server.set_resync_snapshot.
+#[pyfunction(topic = "None", endpoint = "None")]
+pub fn set_resync_snapshot(messages: Option<Vec<MessagePayload>>, topic: Option<String>, endpoint: Option<String>) -> PyResult<()> {
+    DEFAULT_SERVER.set_resync_snapshot(messages, topic, endpoint)
+}
+
+/// Send messages to every client subscribed to a topic. See Server.publish.
+#[pyfunction]
+pub fn publish(py: Python, topic: String, messages: Vec<MessagePayload>) -> PyResult<()> {
+    DEFAULT_SERVER.publish(py, topic, messages)
+}
+
+/// Subscribes a client to a topic. See Server.subscribe_client.
+#[pyfunction]
+pub fn subscribe_client(client_id: ClientId, topic: &str) -> PyResult<bool> {
+    DEFAULT_SERVER.subscribe_client(client_id, topic)
+}
+
+/// Unsubscribes a client from a topic. See Server.unsubscribe_client.
+#[pyfunction]
+pub fn unsubscribe_client(client_id: ClientId, topic: &str) -> bool {
+    DEFAULT_SERVER.unsubscribe_client(client_id, topic)
+}
+
+/// The topics a client is subscribed to. See Server.get_client_topics.
+#[pyfunction]
+pub fn get_client_topics(client_id: ClientId) -> Vec<String> {
+    DEFAULT_SERVER.get_client_topics(client_id)
+}
+
+/// Send messages to all connected clients (of an endpoint). See Server.try_send_messages.
+#[pyfunction(endpoint = "None")]
+pub fn try_send_messages(py: Python, messages: Vec<MessagePayload>, endpoint: Option<String>) -> PyResult<()> {
+    DEFAULT_SERVER.try_send_messages(py, messages, endpoint)
+}
+
+/// The path and query string a client requested when it connected. See Server.get_client_path.
+#[pyfunction]
+pub fn get_client_path(client_id: ClientId) -> Option<String> {
+    DEFAULT_SERVER.get_client_path(client_id)
+}
+
+/// The subprotocol selected when a client connected. See Server.get_client_subprotocol.
+#[pyfunction]
+pub fn get_client_subprotocol(client_id: ClientId) -> Option<String> {
+    DEFAULT_SERVER.get_client_subprotocol(client_id)
+}
+
+/// The permessage-deflate parameters agreed when a client connected. See Server.get_client_compression.
+#[pyfunction]
+pub fn get_client_compression(client_id: ClientId) -> Option<String> {
+    DEFAULT_SERVER.get_client_compression(client_id)
+}
+
+/// Returns a ClientInfo for every connected client. See Server.get_clients.
+#[pyfunction]
+pub fn get_clients() -> Vec<ClientInfo> {
+    DEFAULT_SERVER.get_clients()
+}
+
+/// Returns a ClientInfo for a single client. See Server.get_client_info.
+#[pyfunction]
+pub fn get_client_info(client_id: ClientId) -> Option<ClientInfo> {
+    DEFAULT_SERVER.get_client_info(client_id)
+}
+
+/// Send messages to a single client. See Server.send_to_client.
+#[pyfunction]
+pub fn send_to_client(py: Python, client_id: ClientId, messages: Vec<MessagePayload>) -> bool {
+    DEFAULT_SERVER.send_to_client(py, client_id, messages)
+}
+
+/// Send messages to each of the listed clients. See Server.send_to_clients.
+#[pyfunction]
+pub fn send_to_clients(py: Python, client_ids: Vec<ClientId>, messages: Vec<MessagePayload>) -> usize {
+    DEFAULT_SERVER.send_to_clients(py, client_ids, messages)
+}
+
+/// Closes a single client's connection. See Server.disconnect_client.
+#[pyfunction(code = "1000", reason = "String::new()")]
+pub fn disconnect_client(py: Python, client_id: ClientId, code: u16, reason: String) -> PyResult<bool> {
+    DEFAULT_SERVER.disconnect_client(py, client_id, code, reason)
+}
+
+/// Drains all messages pending from all clients (of an endpoint). See Server.drain_client_messages.
+#[pyfunction(endpoint = "None")]
+pub fn drain_client_messages(py: Python, endpoint: Option<String>) -> PyResult<Vec<(ClientId, MessagePayload)>> {
+    DEFAULT_SERVER.drain_client_messages(py, endpoint)
+}
+
+/// Registers Python callables to be called for every event. See Server.set_handlers.
+#[pyfunction(on_connect = "None", on_message = "None", on_disconnect = "None", on_lag = "None")]
+pub fn set_handlers(on_connect: Option<PyObject>, on_message: Option<PyObject>, on_disconnect: Option<PyObject>, on_lag: Option<PyObject>) {
+    DEFAULT_SERVER.set_handlers(on_connect, on_message, on_disconnect, on_lag)
+}
+
+/// Receives the next message from any client, for asyncio consumers. See Server.recv_message.
+#[pyfunction]
+pub fn recv_message(py: Python) -> PyResult<PyObject> {
+    DEFAULT_SERVER.recv_message(py)
+}
+
+/// Receives the next client connection or disconnection, for asyncio consumers. See Server.next_event.
+#[pyfunction]
+pub fn next_event(py: Python) -> PyResult<PyObject> {
+    DEFAULT_SERVER.next_event(py)
+}
+
+/// Blocks (without holding the GIL) until there are events to drain or the timeout expires, then drains them. See Server.wait_for_events.
+#[pyfunction]
+pub fn wait_for_events(py: Python, timeout_s: f64) -> PyResult<EventBatch> {
+    DEFAULT_SERVER.wait_for_events(py, timeout_s)
+}
+
+/// Forwards quicksocket's log records to a Python logging.Logger (by default logging.getLogger("quicksocket")), in addition to stderr. Each record is logged under a child logger named after the module it came from, e.g. "quicksocket.server.tokio_server", so the consumer's handlers and filters apply as usual.
+///
+/// Records below the logger's effective level at the time of the call are never forwarded; call this again after changing the level.
+#[pyfunction(logger = "None")]
+pub fn enable_python_logging(py: Python, logger: Option<PyObject>) -> PyResult<()> {
+    let logger = match logger {
+        Some(logger) => logger,
+        None => py.import("logging")?.call_method1("getLogger", (log_bridge::DEFAULT_PYTHON_LOGGER,))?.into(),
+    };
+    log_bridge::enable_python(py, logger)
+}
+
+/// Stops forwarding log records to Python. Called automatically at interpreter exit.
+#[pyfunction]
+pub fn disable_python_logging() {
+    log_bridge::disable_python()
+}
+
 /// Defines the actual python module for pyo3 to generate.
 #[pymodule]
-fn quicksocket(_py: Python, m: &PyModule) -> PyResult<()> {
+fn quicksocket(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Server>()?;
+    m.add_class::<ClientConnectEvent>()?;
+    m.add_class::<HandshakeRequest>()?;
+    m.add_class::<PyHandshakeDecision>()?;
+    m.add_class::<ClientDisconnectEvent>()?;
+    m.add_class::<ClientLagEvent>()?;
+    m.add_class::<ErrorEvent>()?;
+    m.add_class::<EventBatch>()?;
+    m.add_class::<ServerStats>()?;
+    m.add_class::<ClientInfo>()?;
+    m.add_class::<PyQueueConfig>()?;
+    m.add_class::<PyCompressionConfig>()?;
+
+    m.add("QuicksocketError",      py.get_type::<QuicksocketError>())?;
+    m.add("ServerNotRunningError", py.get_type::<ServerNotRunningError>())?;
+    m.add("BindError",             py.get_type::<BindError>())?;
+
     m.add_function(wrap_pyfunction!(start_server,               m)?)?;
     m.add_function(wrap_pyfunction!(is_server_running,          m)?)?;
     m.add_function(wrap_pyfunction!(shutdown_server,            m)?)?;
     m.add_function(wrap_pyfunction!(get_last_error_string,      m)?)?;
+    m.add_function(wrap_pyfunction!(get_drop_counts,            m)?)?;
+    m.add_function(wrap_pyfunction!(get_server_stats,           m)?)?;
+    m.add_function(wrap_pyfunction!(drain_errors,               m)?)?;
     m.add_function(wrap_pyfunction!(drain_new_client_events,    m)?)?;
+    m.add_function(wrap_pyfunction!(drain_client_connect_events, m)?)?;
+    m.add_function(wrap_pyfunction!(drain_client_disconnect_events, m)?)?;
+    m.add_function(wrap_pyfunction!(drain_client_lag_events,    m)?)?;
+    m.add_function(wrap_pyfunction!(set_resync_snapshot,        m)?)?;
     m.add_function(wrap_pyfunction!(try_send_messages,          m)?)?;
+    m.add_function(wrap_pyfunction!(publish,                    m)?)?;
+    m.add_function(wrap_pyfunction!(subscribe_client,           m)?)?;
+    m.add_function(wrap_pyfunction!(unsubscribe_client,         m)?)?;
+    m.add_function(wrap_pyfunction!(get_client_topics,          m)?)?;
+    m.add_function(wrap_pyfunction!(get_client_path,            m)?)?;
+    m.add_function(wrap_pyfunction!(get_client_subprotocol,     m)?)?;
+    m.add_function(wrap_pyfunction!(get_client_compression,     m)?)?;
+    m.add_function(wrap_pyfunction!(get_clients,                m)?)?;
+    m.add_function(wrap_pyfunction!(get_client_info,            m)?)?;
+    m.add_function(wrap_pyfunction!(send_to_client,             m)?)?;
+    m.add_function(wrap_pyfunction!(send_to_clients,            m)?)?;
+    m.add_function(wrap_pyfunction!(disconnect_client,          m)?)?;
     m.add_function(wrap_pyfunction!(drain_client_messages,      m)?)?;
+    m.add_function(wrap_pyfunction!(wait_for_events,            m)?)?;
+    m.add_function(wrap_pyfunction!(set_handlers,               m)?)?;
+    m.add_function(wrap_pyfunction!(recv_message,               m)?)?;
+    m.add_function(wrap_pyfunction!(next_event,                 m)?)?;
+    m.add_function(wrap_pyfunction!(enable_python_logging,      m)?)?;
+    m.add_function(wrap_pyfunction!(disable_python_logging,     m)?)?;
+
+    // Diagnostics go through `log`. The tokio threads may still be logging while the interpreter shuts down, so stop forwarding to Python before then.
+    log_bridge::init();
+    py.import("atexit")?.call_method1("register", (m.getattr("disable_python_logging")?,))?;
 
     Ok(())
 }
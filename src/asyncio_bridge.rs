@@ -0,0 +1,99 @@
+// asyncio_bridge.rs
+// =================
+//
+// Awaitable receives for asyncio consumers. A receive returns an asyncio future created on the caller's event loop. Whenever the server's EventSignal fires, a task on a small shared tokio runtime schedules an attempt on that loop (via call_soon_threadsafe), and the attempt takes the next item from the server's channel and resolves the future with it.
+//
+// Items are only ever taken on the event loop's own thread, right before resolving the future, so a future cancelled while waiting (e.g. by asyncio.wait_for) never swallows an item.
+
+use pyo3::prelude::*;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::server::consumer_state::ConsumerState;
+
+lazy_static! {
+  /// Runs the tasks that wait for events on behalf of pending receives. It only ever waits on EventSignals, so one worker is plenty for every server in the process.
+  static ref RUNTIME: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
+    .worker_threads(1)
+    .thread_name("quicksocket-asyncio")
+    .build()
+    .expect("Failed to start the quicksocket asyncio bridge runtime.");
+}
+
+/// Takes the next item from a server's channels, if one is ready.
+pub type TakeFn = fn(Python, &ConsumerState) -> Option<PyObject>;
+/// Produces the result (or exception) for a receive once the server has stopped and there's nothing left to take.
+pub type StoppedFn = fn(Python) -> PyResult<PyObject>;
+
+/// A receive waiting for its future to be resolved. Calling it, on the future's event loop, makes one attempt; if there's nothing to take yet, it schedules another attempt for the next event.
+#[pyclass]
+pub struct PendingReceive {
+  state: Arc<ConsumerState>,
+  event_loop: PyObject,
+  future: PyObject,
+  take: TakeFn,
+  stopped: StoppedFn,
+  /// The RUNTIME task waiting for the next event on this receive's behalf, if any. Aborted once the future is done, so a cancelled receive doesn't leave it parked until the next event.
+  waiter: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// Starts a receive, returning an asyncio future for the caller to await. Must be called from the thread running an asyncio event loop.
+pub fn receive(py: Python, state: Arc<ConsumerState>, take: TakeFn, stopped: StoppedFn) -> PyResult<PyObject> {
+  let asyncio = py.import("asyncio")?;
+  // get_running_loop is new in Python 3.7; on 3.6, get_event_loop returns the running loop when called from a coroutine.
+  let event_loop: PyObject = if asyncio.hasattr("get_running_loop")? {
+    asyncio.call_method0("get_running_loop")?.into()
+  } else {
+    asyncio.call_method0("get_event_loop")?.into()
+  };
+  let future = event_loop.call_method0(py, "create_future")?;
+  let pending = Py::new(py, PendingReceive { state, event_loop, future: future.clone_ref(py), take, stopped, waiter: Mutex::new(None) })?;
+  future.call_method1(py, "add_done_callback", (pending.getattr(py, "abort_waiter")?,))?;
+  pending.call0(py)?;
+  Ok(future)
+}
+
+#[pymethods]
+impl PendingReceive {
+  #[call]
+  fn __call__(slf: PyRef<Self>, py: Python) -> PyResult<()> {
+    // Cancelled, most likely; nobody's waiting for the item any more, so leave it for the next receive.
+    if slf.future.call_method0(py, "done")?.extract::<bool>(py)? { return Ok(()); }
+
+    // Read before taking, so an event that arrives in between still triggers the next attempt.
+    let seen = slf.state.event_signal.count();
+    if let Some(item) = (slf.take)(py, &slf.state) {
+      slf.future.call_method1(py, "set_result", (item,))?;
+      return Ok(());
+    }
+    if !slf.state.is_serving() {
+      match (slf.stopped)(py) {
+        Ok(result) => slf.future.call_method1(py, "set_result", (result,))?,
+        Err(err)   => slf.future.call_method1(py, "set_exception", (err.into_py(py),))?,
+      };
+      return Ok(());
+    }
+
+    // Nothing yet: try again once there's been an event.
+    let signal = slf.state.event_signal.clone();
+    let event_loop = slf.event_loop.clone_ref(py);
+    let pending: Py<PendingReceive> = slf.into();
+    let waiting = pending.clone_ref(py);
+    let waiter = RUNTIME.spawn(async move {
+      signal.notified_past(seen).await;
+      Python::with_gil(|py| {
+        // Fails if the loop has been closed, in which case there's nobody left to resolve the future for.
+        let _ = event_loop.call_method1(py, "call_soon_threadsafe", (waiting,));
+      });
+    });
+    if let Ok(mut slot) = pending.borrow(py).waiter.lock() { *slot = Some(waiter); }
+    Ok(())
+  }
+
+  /// The future's done callback: stops waiting for events on behalf of a receive that's been resolved or cancelled.
+  fn abort_waiter(&self, _future: PyObject) {
+    if let Some(waiter) = self.waiter.lock().ok().and_then(|mut slot| slot.take()) {
+      waiter.abort();
+    }
+  }
+}
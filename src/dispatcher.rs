@@ -0,0 +1,90 @@
+// dispatcher.rs
+// =============
+//
+// Handler dispatch for consumers that would rather register callbacks than poll. A dispatcher thread waits on the server's EventSignal without holding the GIL, drains every event queue when woken, and only takes the GIL when it has events to hand to the handlers.
+
+use log::warn;
+use pyo3::prelude::*;
+use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, thread, time::{Duration, Instant}};
+
+use crate::{api::take_event_batch, server::{ClientId, consumer_state::ConsumerState, errors::ErrorKind}};
+
+/// How often an idle dispatcher wakes up to check whether it's been stopped. Events wake it right away regardless.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The Python callables events are dispatched to. Events without a handler are dropped.
+pub struct Handlers {
+  /// Called as on_connect(client_id, peer_address).
+  pub on_connect: Option<PyObject>,
+  /// Called as on_message(client_id, payload).
+  pub on_message: Option<PyObject>,
+  /// Called as on_lag(client_id, event), with a ClientLagEvent.
+  pub on_lag: Option<PyObject>,
+  /// Called as on_disconnect(client_id, event), with a ClientDisconnectEvent.
+  pub on_disconnect: Option<PyObject>,
+}
+
+/// A running dispatcher thread. Dropping it stops the thread.
+pub struct Dispatcher {
+  stop: Arc<AtomicBool>,
+  state: Arc<ConsumerState>,
+}
+
+impl Dispatcher {
+  /// Launches a dispatcher thread that hands every event from `state` to `handlers`.
+  pub fn start(state: Arc<ConsumerState>, handlers: Handlers) -> Dispatcher {
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = stop.clone();
+    let thread_state = state.clone();
+    thread::Builder::new()
+      .name("quicksocket-dispatcher".to_string())
+      .spawn(move || dispatch(thread_state, handlers, thread_stop))
+      .expect("Failed to launch the quicksocket dispatcher thread.");
+    Dispatcher { stop, state }
+  }
+}
+
+impl Drop for Dispatcher {
+  /// Asks the thread to stop without waiting for it: the thread may need the GIL to finish a batch, and the caller may be holding it (or may even be one of the handlers).
+  fn drop(&mut self) {
+    self.stop.store(true, Ordering::Relaxed);
+    self.state.event_signal.notify();
+  }
+}
+
+fn dispatch(state: Arc<ConsumerState>, handlers: Handlers, stop: Arc<AtomicBool>) {
+  while !stop.load(Ordering::Relaxed) {
+    // Read the signal count before draining, so an event that arrives in between still wakes us.
+    let seen = state.event_signal.count();
+    let batch = take_event_batch(&state);
+    if batch.is_empty() {
+      state.event_signal.wait_past(seen, Instant::now() + IDLE_CHECK_INTERVAL);
+      continue;
+    }
+
+    Python::with_gil(|py| {
+      for (client_id, peer) in batch.new_client_events {
+        call_handler(py, &state, "on_connect", &handlers.on_connect, client_id, peer.into_py(py));
+      }
+      for (client_id, payload) in batch.client_messages {
+        call_handler(py, &state, "on_message", &handlers.on_message, client_id, payload.into_py(py));
+      }
+      for evt in batch.client_lag_events {
+        call_handler(py, &state, "on_lag", &handlers.on_lag, evt.client_id, evt.into_py(py));
+      }
+      for evt in batch.client_disconnect_events {
+        call_handler(py, &state, "on_disconnect", &handlers.on_disconnect, evt.client_id, evt.into_py(py));
+      }
+    });
+  }
+}
+
+/// Calls a handler, if there is one. An exception raised by the handler is recorded as an error rather than ending the dispatcher.
+fn call_handler(py: Python, state: &ConsumerState, name: &str, handler: &Option<PyObject>, client_id: ClientId, arg: PyObject) {
+  if let Some(handler) = handler {
+    if let Err(err) = handler.call1(py, (client_id, arg)) {
+      warn!("[client {}] {} handler raised {}", client_id, name, err);
+      state.record_client_error(ErrorKind::Callback, format!("{} handler raised {}", name, err), client_id);
+    }
+  }
+}
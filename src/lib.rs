@@ -8,5 +8,9 @@ extern crate lazy_static;
 
 mod server;
 mod api;
+mod asyncio_bridge;
+mod dispatcher;
+mod log_bridge;
 
 pub use api::*;
+pub use server::handshake::{HandshakeDecision, HandshakeHook, HandshakeInfo};
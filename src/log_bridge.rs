@@ -0,0 +1,121 @@
+// log_bridge.rs
+// =============
+//
+// The `log` backend installed when quicksocket is loaded as a Python module. Records always go to env_logger (stderr, filtered by RUST_LOG, warnings and errors only by default). Once enabled from Python, they are also forwarded to a Python logging.Logger, so they pass through the consumer's own handlers and filters.
+//
+// Rust consumers of the library install their own logger instead; nothing here runs unless the Python module is initialized.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use pyo3::prelude::*;
+use std::sync::RwLock;
+
+/// Name of the Python logger records are forwarded to if none is given.
+pub const DEFAULT_PYTHON_LOGGER: &str = "quicksocket";
+
+/// A Python logging.Logger and the most verbose level it was accepting when it was attached.
+#[derive(Clone)]
+struct PythonLogger {
+  logger: PyObject,
+  level: LevelFilter,
+}
+
+struct Bridge {
+  env: env_logger::Logger,
+  python: RwLock<Option<PythonLogger>>,
+}
+
+lazy_static! {
+  static ref BRIDGE: Bridge = Bridge {
+    env: env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).build(),
+    python: RwLock::new(None),
+  };
+}
+
+/// Installs the bridge as the `log` logger. Does nothing if a logger is already installed.
+pub fn init() {
+  if log::set_logger(&*BRIDGE).is_ok() {
+    update_max_level();
+  }
+}
+
+/// Starts forwarding records to the given Python logger, replacing any previous one. Only records at or above the logger's effective level at the time of this call are forwarded; call again after changing it.
+pub fn enable_python(py: Python, logger: PyObject) -> PyResult<()> {
+  let effective_level: i32 = logger.call_method0(py, "getEffectiveLevel")?.extract(py)?;
+  let python = PythonLogger { logger, level: level_filter_for_python(effective_level) };
+  if let Ok(mut current) = BRIDGE.python.write() { *current = Some(python); }
+  update_max_level();
+  Ok(())
+}
+
+/// Stops forwarding records to Python.
+pub fn disable_python() {
+  if let Ok(mut current) = BRIDGE.python.write() { *current = None; }
+  update_max_level();
+}
+
+fn update_max_level() {
+  let python_level = BRIDGE.python_logger().map(|python| python.level).unwrap_or(LevelFilter::Off);
+  log::set_max_level(BRIDGE.env.filter().max(python_level));
+}
+
+impl Bridge {
+  /// Clones the Python logger out, so the lock is never held while waiting on the GIL.
+  fn python_logger(&self) -> Option<PythonLogger> {
+    self.python.read().ok().and_then(|python| python.clone())
+  }
+}
+
+impl Log for Bridge {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    self.env.enabled(metadata) || self.python_logger().is_some_and(|python| metadata.level() <= python.level)
+  }
+
+  fn log(&self, record: &Record) {
+    if self.env.matches(record) {
+      self.env.log(record);
+    }
+
+    let python = self.python_logger();
+    if let Some(python) = python.filter(|python| record.level() <= python.level) {
+      Python::with_gil(|py| {
+        // Log under a child logger named after the record's module, e.g. "quicksocket.server.tokio_server", so Python filters can tell them apart.
+        let res = python.logger.call_method1(py, "getChild", (python_logger_suffix(record.target()),))
+          .and_then(|logger| logger.call_method1(py, "log", (python_level(record.level()), "%s", record.args().to_string())));
+        // Logging must never fail the code doing the logging, and reporting the failure through `log` could recurse, so it's dropped.
+        let _ = res;
+      });
+    }
+  }
+
+  fn flush(&self) {
+    self.env.flush();
+  }
+}
+
+/// The numeric Python logging level for a `log` level. Python has no TRACE; trace records use 5, below DEBUG.
+fn python_level(level: Level) -> i32 {
+  match level {
+    Level::Error => 40,
+    Level::Warn  => 30,
+    Level::Info  => 20,
+    Level::Debug => 10,
+    Level::Trace => 5,
+  }
+}
+
+/// The most verbose `log` level a Python logger at the given effective level accepts.
+fn level_filter_for_python(level: i32) -> LevelFilter {
+  match level {
+    l if l <= 5  => LevelFilter::Trace,
+    l if l <= 10 => LevelFilter::Debug,
+    l if l <= 20 => LevelFilter::Info,
+    l if l <= 30 => LevelFilter::Warn,
+    l if l <= 40 => LevelFilter::Error,
+    _            => LevelFilter::Off,
+  }
+}
+
+/// The child logger name for a record target: quicksocket's own modules drop the crate name ("quicksocket::server" -> "server"), and other crates keep theirs ("tokio_tungstenite").
+fn python_logger_suffix(target: &str) -> String {
+  target.strip_prefix("quicksocket::").unwrap_or(target).replace("::", ".")
+}
@@ -0,0 +1,186 @@
+// clients.rs
+//
+// Registry of the connections currently open on a server, shared between the tokio server thread (which adds and removes connections as they come and go) and the consumer thread(s) (which look connections up to address them directly).
+
+use std::{collections::{BTreeSet, HashMap}, sync::{Arc, RwLock}, time::SystemTime};
+use tokio::sync::{mpsc, watch};
+use tokio_tungstenite::tungstenite::{Message, protocol::CloseFrame};
+
+use super::{ClientId, metrics::ClientStats, topics::{MAX_TOPICS_PER_CLIENT, TopicCommand, TopicError, TopicRegistry}};
+
+/// Capacity of each connection's outbound queue, in batches of messages.
+pub const CLIENT_OUTBOUND_CAPACITY: usize = 16;
+
+/// Consumer-side handle to a single open connection.
+pub struct ClientHandle {
+  /// Transmitter for messages addressed to only this client. The connection's sender task reads these alongside the server broadcast.
+  pub outbound_tx: mpsc::Sender<Vec<Message>>,
+  /// Transmitter for asking the connection to close with the given close frame. Both connection tasks watch it.
+  pub disconnect_tx: watch::Sender<Option<CloseFrame<'static>>>,
+  /// Transmitter for changing which topics the connection's sender task forwards.
+  pub topics_tx: mpsc::UnboundedSender<TopicCommand>,
+  /// The topics the client is subscribed to.
+  pub topics: BTreeSet<String>,
+  /// The path and query string the client requested in its handshake, e.g. "/hands?user=3".
+  pub path: String,
+  /// The subprotocol selected in its handshake, if any.
+  pub subprotocol: Option<String>,
+  /// The permessage-deflate parameters agreed in its handshake, as answered in Sec-WebSocket-Extensions, if compression was agreed.
+  pub compression: Option<String>,
+  /// The client's address.
+  pub peer: String,
+  /// When the client connected, and its traffic since, kept by its tasks.
+  pub stats: ClientStats,
+}
+
+/// A point-in-time view of a single open connection.
+pub struct ClientSnapshot {
+  pub client_id: ClientId,
+  pub peer: String,
+  pub connected_at: SystemTime,
+  pub path: String,
+  pub subprotocol: Option<String>,
+  pub compression: Option<String>,
+  /// Batches queued for only this client that haven't been written yet.
+  pub outbound_depth: usize,
+  pub stats: ClientStats,
+}
+
+impl ClientHandle {
+  fn snapshot(&self, client_id: ClientId) -> ClientSnapshot {
+    ClientSnapshot {
+      client_id,
+      peer: self.peer.clone(),
+      connected_at: self.stats.connected_at(),
+      path: self.path.clone(),
+      subprotocol: self.subprotocol.clone(),
+      compression: self.compression.clone(),
+      outbound_depth: self.outbound_tx.max_capacity() - self.outbound_tx.capacity(),
+      stats: self.stats.clone(),
+    }
+  }
+}
+
+/// Why a message batch couldn't be queued for a client.
+#[derive(Debug)]
+pub enum ClientSendError {
+  /// No client with that ID is connected (it may have already disconnected).
+  NotConnected,
+  /// The client's outbound queue is full; its connection isn't keeping up.
+  QueueFull,
+  /// The client couldn't be subscribed to a topic.
+  Topic(TopicError),
+}
+
+/// Cheaply cloneable, thread-safe map of client ID -> ClientHandle.
+#[derive(Clone, Default)]
+pub struct ClientRegistry {
+  clients: Arc<RwLock<HashMap<ClientId, ClientHandle>>>,
+}
+
+impl ClientRegistry {
+  pub fn new() -> Self { Self::default() }
+
+  /// The number of open connections.
+  pub fn count(&self) -> usize {
+    self.clients.read().map(|clients| clients.len()).unwrap_or(0)
+  }
+
+  /// Registers a newly-accepted connection.
+  pub fn insert(&self, client_id: ClientId, handle: ClientHandle) {
+    if let Ok(mut clients) = self.clients.write() {
+      clients.insert(client_id, handle);
+    }
+  }
+
+  /// Forgets a connection once both of its tasks have finished.
+  pub fn remove(&self, client_id: ClientId) {
+    if let Ok(mut clients) = self.clients.write() {
+      clients.remove(&client_id);
+    }
+  }
+
+  /// Queues a batch of messages for a single client without blocking.
+  pub fn try_send(&self, client_id: ClientId, messages: Vec<Message>) -> Result<(), ClientSendError> {
+    let clients = self.clients.read().map_err(|_| ClientSendError::NotConnected)?;
+    let client = clients.get(&client_id).ok_or(ClientSendError::NotConnected)?;
+    client.outbound_tx.try_send(messages).map_err(|err| match err {
+      mpsc::error::TrySendError::Full(_)   => ClientSendError::QueueFull,
+      mpsc::error::TrySendError::Closed(_) => ClientSendError::NotConnected,
+    })
+  }
+
+  /// Subscribes a client to a topic, creating the topic if needed. Returns false if the client was already subscribed.
+  pub fn subscribe(&self, client_id: ClientId, topic: &str, topics: &TopicRegistry) -> Result<bool, ClientSendError> {
+    let mut clients = self.clients.write().map_err(|_| ClientSendError::NotConnected)?;
+    let client = clients.get_mut(&client_id).ok_or(ClientSendError::NotConnected)?;
+    if client.topics.contains(topic) { return Ok(false); }
+    if client.topics.len() >= MAX_TOPICS_PER_CLIENT { return Err(ClientSendError::Topic(TopicError::TooManyForClient)); }
+    let (topic, rx) = topics.subscribe(topic).map_err(ClientSendError::Topic)?;
+    client.topics.insert(topic.name.clone());
+    client.topics_tx.send(TopicCommand::Subscribe(topic, rx)).map_err(|_| ClientSendError::NotConnected)?;
+    Ok(true)
+  }
+
+  /// Unsubscribes a client from a topic. Returns false if the client wasn't subscribed.
+  pub fn unsubscribe(&self, client_id: ClientId, topic: &str) -> Result<bool, ClientSendError> {
+    let mut clients = self.clients.write().map_err(|_| ClientSendError::NotConnected)?;
+    let client = clients.get_mut(&client_id).ok_or(ClientSendError::NotConnected)?;
+    if !client.topics.remove(topic) { return Ok(false); }
+    client.topics_tx.send(TopicCommand::Unsubscribe(topic.to_string())).map_err(|_| ClientSendError::NotConnected)?;
+    Ok(true)
+  }
+
+  /// The topics a client is subscribed to, in name order.
+  pub fn topics(&self, client_id: ClientId) -> Result<Vec<String>, ClientSendError> {
+    let clients = self.clients.read().map_err(|_| ClientSendError::NotConnected)?;
+    let client = clients.get(&client_id).ok_or(ClientSendError::NotConnected)?;
+    Ok(client.topics.iter().cloned().collect())
+  }
+
+  /// The path and query string the client requested in its handshake.
+  pub fn path(&self, client_id: ClientId) -> Result<String, ClientSendError> {
+    let clients = self.clients.read().map_err(|_| ClientSendError::NotConnected)?;
+    let client = clients.get(&client_id).ok_or(ClientSendError::NotConnected)?;
+    Ok(client.path.clone())
+  }
+
+  /// The subprotocol selected in the client's handshake, if any.
+  pub fn subprotocol(&self, client_id: ClientId) -> Result<Option<String>, ClientSendError> {
+    let clients = self.clients.read().map_err(|_| ClientSendError::NotConnected)?;
+    let client = clients.get(&client_id).ok_or(ClientSendError::NotConnected)?;
+    Ok(client.subprotocol.clone())
+  }
+
+  /// The permessage-deflate parameters agreed in the client's handshake, if compression was agreed.
+  pub fn compression(&self, client_id: ClientId) -> Result<Option<String>, ClientSendError> {
+    let clients = self.clients.read().map_err(|_| ClientSendError::NotConnected)?;
+    let client = clients.get(&client_id).ok_or(ClientSendError::NotConnected)?;
+    Ok(client.compression.clone())
+  }
+
+  /// A view of a single connection.
+  pub fn snapshot(&self, client_id: ClientId) -> Result<ClientSnapshot, ClientSendError> {
+    let clients = self.clients.read().map_err(|_| ClientSendError::NotConnected)?;
+    let client = clients.get(&client_id).ok_or(ClientSendError::NotConnected)?;
+    Ok(client.snapshot(client_id))
+  }
+
+  /// A view of every open connection, in client ID order.
+  pub fn snapshots(&self) -> Vec<ClientSnapshot> {
+    let clients = match self.clients.read() {
+      Ok(clients) => clients,
+      Err(_) => return vec![],
+    };
+    let mut snapshots: Vec<ClientSnapshot> = clients.iter().map(|(client_id, client)| client.snapshot(*client_id)).collect();
+    snapshots.sort_by_key(|snapshot| snapshot.client_id);
+    snapshots
+  }
+
+  /// Asks a client's connection to send the given close frame and shut down.
+  pub fn request_disconnect(&self, client_id: ClientId, frame: CloseFrame<'static>) -> Result<(), ClientSendError> {
+    let clients = self.clients.read().map_err(|_| ClientSendError::NotConnected)?;
+    let client = clients.get(&client_id).ok_or(ClientSendError::NotConnected)?;
+    client.disconnect_tx.send(Some(frame)).map_err(|_| ClientSendError::NotConnected)
+  }
+}
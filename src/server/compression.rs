@@ -0,0 +1,715 @@
+// compression.rs
+//
+// The permessage-deflate extension (RFC 7692): negotiating it during the handshake, and compressing messages once it's agreed.
+//
+// tungstenite 0.15 has no extension support (it rejects any frame with the RSV1 bit that compressed messages are marked with), so the extension lives underneath it instead. A DeflateStream wraps each connection's socket and, once compression is enabled, rewrites frames as they pass: compressed messages from the client are inflated into plain frames before tungstenite reads them, and the plain data frames tungstenite writes are deflated on their way out.
+
+use std::{io, pin::Pin, task::{Context, Poll}};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The extension's name, as offered in Sec-WebSocket-Extensions.
+pub const EXTENSION_NAME: &str = "permessage-deflate";
+
+/// Default threshold: messages shorter than this many bytes are sent uncompressed, since they'd barely shrink (or even grow).
+pub const DEFAULT_THRESHOLD: usize = 1024;
+
+/// The smallest and largest LZ77 window sizes, in bits, that can be configured. RFC 7692 allows 8, but zlib can't compress with a window that small.
+pub const MIN_WINDOW_BITS: u8 = 9;
+pub const MAX_WINDOW_BITS: u8 = 15;
+
+/// The largest message inflated from a client, matching tungstenite's default max_message_size. Larger ones fail the connection, so a small compressed message can't balloon into an unbounded one.
+const MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+/// Inflated messages are handed to tungstenite in frames of at most this many bytes, well under its default max_frame_size.
+const MAX_INFLATED_FRAME: usize = 1 << 20;
+
+/// How many encoded bytes can wait to be written to the socket before writes stop accepting more.
+const MAX_WRITE_BACKLOG: usize = 64 * 1024;
+
+/// Every deflate block flushed with Z_SYNC_FLUSH ends with these bytes, which the extension leaves off the wire.
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+
+/// How the server compresses messages for clients that offer permessage-deflate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressionConfig {
+  /// The LZ77 window the server compresses with, in bits (9-15). Clients can ask for a smaller one. Smaller windows use less memory per connection, at some cost to the compression ratio.
+  pub server_max_window_bits: u8,
+  /// The window clients are asked to compress with, in bits (9-15). Only clients that offer to limit their window are asked.
+  pub client_max_window_bits: u8,
+  /// Compress each message on its own, rather than referring back to earlier messages. Saves the window's memory between messages, at some cost to the compression ratio. Clients can also ask for this.
+  pub server_no_context_takeover: bool,
+  /// Ask clients to compress each message on its own too.
+  pub client_no_context_takeover: bool,
+  /// Messages shorter than this many bytes are sent uncompressed.
+  pub threshold: usize,
+}
+
+impl Default for CompressionConfig {
+  fn default() -> Self {
+    CompressionConfig {
+      server_max_window_bits: MAX_WINDOW_BITS,
+      client_max_window_bits: MAX_WINDOW_BITS,
+      server_no_context_takeover: false,
+      client_no_context_takeover: false,
+      threshold: DEFAULT_THRESHOLD,
+    }
+  }
+}
+
+impl CompressionConfig {
+  /// Checks a window size, in bits, for the named setting.
+  pub fn check_window_bits(name: &str, bits: u8) -> Result<u8, String> {
+    if !(MIN_WINDOW_BITS..=MAX_WINDOW_BITS).contains(&bits) {
+      return Err(format!("Invalid {} {}; expected {} to {}.", name, bits, MIN_WINDOW_BITS, MAX_WINDOW_BITS));
+    }
+    Ok(bits)
+  }
+
+  /// Accepts the first permessage-deflate offer in a client's Sec-WebSocket-Extensions header values that the server can honour. Returns the agreed parameters and the header value to answer with, or None if the client made no acceptable offer (in which case its messages are exchanged uncompressed).
+  pub fn negotiate<'a>(&self, header_values: impl Iterator<Item = &'a str>) -> Option<(Negotiated, String)> {
+    header_values.flat_map(|value| value.split(',')).find_map(|offer| self.accept_offer(offer))
+  }
+
+  /// Accepts a single extension offer, e.g. "permessage-deflate; client_max_window_bits", if it's for permessage-deflate and all of its parameters are valid and can be honoured.
+  fn accept_offer(&self, offer: &str) -> Option<(Negotiated, String)> {
+    let mut parts = offer.split(';').map(str::trim);
+    if !parts.next()?.eq_ignore_ascii_case(EXTENSION_NAME) { return None; }
+
+    let mut server_window_bits = self.server_max_window_bits;
+    let mut server_no_context_takeover = self.server_no_context_takeover;
+    let mut offered_server_bits = false;
+    let mut offered_client_bits: Option<Option<u8>> = None;
+    let mut offered_client_no_context_takeover = false;
+    let mut seen: Vec<String> = vec![];
+    for param in parts {
+      let (name, value) = match param.split_once('=') {
+        Some((name, value)) => (name.trim().to_ascii_lowercase(), Some(value.trim().trim_matches('"'))),
+        None => (param.to_ascii_lowercase(), None),
+      };
+      // Each parameter may only appear once, and an offer with anything unknown or malformed is declined.
+      if seen.contains(&name) { return None; }
+      let bits = |value: Option<&str>| value.and_then(|value| value.parse::<u8>().ok()).filter(|bits| (8..=MAX_WINDOW_BITS).contains(bits));
+      match (name.as_str(), value) {
+        ("server_no_context_takeover", None) => server_no_context_takeover = true,
+        ("client_no_context_takeover", None) => offered_client_no_context_takeover = true,
+        ("server_max_window_bits", value) => {
+          server_window_bits = server_window_bits.min(bits(value)?);
+          offered_server_bits = true;
+        }
+        ("client_max_window_bits", None) => offered_client_bits = Some(None),
+        ("client_max_window_bits", value) => offered_client_bits = Some(Some(bits(value)?)),
+        _ => return None,
+      }
+      seen.push(name);
+    }
+    // The client asked for a window smaller than the server can compress with.
+    if server_window_bits < MIN_WINDOW_BITS { return None; }
+
+    let mut response = vec![EXTENSION_NAME.to_string()];
+    if server_no_context_takeover { response.push("server_no_context_takeover".to_string()); }
+    if self.client_no_context_takeover || offered_client_no_context_takeover { response.push("client_no_context_takeover".to_string()); }
+    if offered_server_bits { response.push(format!("server_max_window_bits={}", server_window_bits)); }
+    // The client's window can only be limited if it offered to limit it. Inflating always uses the largest window, which handles any smaller one.
+    if let Some(offered) = offered_client_bits {
+      let client_window_bits = offered.unwrap_or(MAX_WINDOW_BITS).min(self.client_max_window_bits);
+      if client_window_bits < MAX_WINDOW_BITS { response.push(format!("client_max_window_bits={}", client_window_bits)); }
+    }
+    let negotiated = Negotiated { server_window_bits, server_no_context_takeover, threshold: self.threshold };
+    Some((negotiated, response.join("; ")))
+  }
+}
+
+/// The parameters agreed with a client, which its DeflateStream compresses with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Negotiated {
+  server_window_bits: u8,
+  server_no_context_takeover: bool,
+  threshold: usize,
+}
+
+/// A connection's socket, passed through as it is until compression is enabled, and from then on compressing and decompressing messages as their frames pass between tungstenite and the socket.
+///
+/// Compression is enabled once the handshake is done, so the handshake itself always passes through untouched. Clients can't send frames before they've read the handshake response, so no compressed frames can have been read by then.
+pub struct DeflateStream<S> {
+  inner: S,
+  codec: Option<Box<Codec>>,
+}
+
+impl<S> DeflateStream<S> {
+  pub fn new(inner: S) -> Self {
+    DeflateStream { inner, codec: None }
+  }
+
+  /// Starts compressing and decompressing messages with the agreed parameters.
+  pub fn enable(&mut self, negotiated: Negotiated) {
+    self.codec = Some(Box::new(Codec::new(negotiated)));
+  }
+}
+
+/// The header of a websocket frame.
+struct FrameHead {
+  fin: bool,
+  rsv1: bool,
+  opcode: u8,
+  mask: Option<[u8; 4]>,
+  /// The length of the header itself, up to the payload.
+  len: usize,
+  payload_len: usize,
+}
+
+impl FrameHead {
+  /// Parses the header at the start of buf, or returns None if buf doesn't hold all of it yet.
+  fn parse(buf: &[u8]) -> io::Result<Option<FrameHead>> {
+    if buf.len() < 2 { return Ok(None); }
+    let masked = buf[1] & 0x80 != 0;
+    let (payload_len, mut len) = match buf[1] & 0x7f {
+      126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+      127 if buf.len() >= 10 => (u64::from_be_bytes([buf[2], buf[3], buf[4], buf[5], buf[6], buf[7], buf[8], buf[9]]), 10),
+      126 | 127 => return Ok(None),
+      payload_len => (payload_len as u64, 2),
+    };
+    if payload_len > MAX_MESSAGE_SIZE as u64 {
+      return Err(invalid_data(format!("frame of {} bytes is larger than the maximum message size", payload_len)));
+    }
+    let mask = match masked {
+      true if buf.len() < len + 4 => return Ok(None),
+      true => { len += 4; Some([buf[len - 4], buf[len - 3], buf[len - 2], buf[len - 1]]) }
+      false => None,
+    };
+    Ok(Some(FrameHead { fin: buf[0] & FIN != 0, rsv1: buf[0] & RSV1 != 0, opcode: buf[0] & 0x0f, mask, len, payload_len: payload_len as usize }))
+  }
+
+  /// Appends a frame header to out.
+  fn write(out: &mut Vec<u8>, first_byte: u8, mask: Option<[u8; 4]>, payload_len: usize) {
+    out.push(first_byte);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    if payload_len < 126 {
+      out.push(mask_bit | payload_len as u8);
+    } else if payload_len <= u16::MAX as usize {
+      out.push(mask_bit | 126);
+      out.extend_from_slice(&(payload_len as u16).to_be_bytes());
+    } else {
+      out.push(mask_bit | 127);
+      out.extend_from_slice(&(payload_len as u64).to_be_bytes());
+    }
+    if let Some(mask) = mask { out.extend_from_slice(&mask); }
+  }
+}
+
+fn invalid_data(err: impl ToString) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, format!("permessage-deflate: {}", err.to_string()))
+}
+
+/// A connection's compression state, and its frames on their way through.
+struct Codec {
+  negotiated: Negotiated,
+  inflater: Decompress,
+  deflater: Compress,
+  /// Bytes read from the socket that don't make up a whole frame yet.
+  read_in: Vec<u8>,
+  /// Frames ready for tungstenite to read, from read_pos on.
+  read_out: Vec<u8>,
+  read_pos: usize,
+  /// The socket reached EOF.
+  read_eof: bool,
+  /// The opcode and payload so far of a fragmented compressed message being read.
+  fragmented: Option<(u8, Vec<u8>)>,
+  /// Bytes written by tungstenite that don't make up a whole frame yet.
+  write_in: Vec<u8>,
+  /// Frames ready to write to the socket, from write_pos on.
+  write_out: Vec<u8>,
+  write_pos: usize,
+}
+
+impl Codec {
+  fn new(negotiated: Negotiated) -> Self {
+    Codec {
+      negotiated,
+      // Clients compress without a zlib header, with at most a 15-bit window.
+      inflater: Decompress::new(false),
+      deflater: Compress::new_with_window_bits(Compression::default(), false, negotiated.server_window_bits),
+      read_in: vec![],
+      read_out: vec![],
+      read_pos: 0,
+      read_eof: false,
+      fragmented: None,
+      write_in: vec![],
+      write_out: vec![],
+      write_pos: 0,
+    }
+  }
+
+  /// Decodes every whole frame read so far into read_out.
+  fn decode_frames(&mut self) -> io::Result<()> {
+    let input = std::mem::take(&mut self.read_in);
+    let mut pos = 0;
+    while let Some(head) = FrameHead::parse(&input[pos..])? {
+      let frame_len = head.len + head.payload_len;
+      if input.len() - pos < frame_len { break; }
+      self.decode_frame(head, &input[pos..pos + frame_len])?;
+      pos += frame_len;
+    }
+    self.read_in = input;
+    self.read_in.drain(..pos);
+    Ok(())
+  }
+
+  /// Inflates a frame of a compressed message, passing every other frame through as it is (for tungstenite to check).
+  fn decode_frame(&mut self, head: FrameHead, frame: &[u8]) -> io::Result<()> {
+    let payload = &frame[head.len..];
+    match head.opcode {
+      OP_TEXT | OP_BINARY if head.rsv1 => {
+        if self.fragmented.is_some() { return Err(invalid_data("new message started before the last one finished")); }
+        let payload = unmask(payload, head.mask)?;
+        if head.fin {
+          let message = self.inflate(payload)?;
+          self.emit(head.opcode, &message);
+        } else {
+          self.fragmented = Some((head.opcode, payload));
+        }
+      }
+      OP_CONTINUATION if self.fragmented.is_some() => {
+        if head.rsv1 { return Err(invalid_data("RSV1 set on a continuation frame")); }
+        let (opcode, mut message) = self.fragmented.take().unwrap_or_default();
+        message.extend_from_slice(&unmask(payload, head.mask)?);
+        if message.len() > MAX_MESSAGE_SIZE { return Err(invalid_data("message is larger than the maximum message size")); }
+        if head.fin {
+          let message = self.inflate(message)?;
+          self.emit(opcode, &message);
+        } else {
+          self.fragmented = Some((opcode, message));
+        }
+      }
+      _ => self.read_out.extend_from_slice(frame),
+    }
+    Ok(())
+  }
+
+  /// Inflates a compressed message's payload. The window is kept for the next message, which works whether or not the client kept its own.
+  fn inflate(&mut self, mut payload: Vec<u8>) -> io::Result<Vec<u8>> {
+    payload.extend_from_slice(&DEFLATE_TAIL);
+    let mut message = Vec::with_capacity(payload.len() * 4);
+    let start = self.inflater.total_in();
+    loop {
+      let consumed = (self.inflater.total_in() - start) as usize;
+      if message.len() == message.capacity() { message.reserve(message.capacity()); }
+      let produced = message.len();
+      let status = self.inflater.decompress_vec(&payload[consumed..], &mut message, FlushDecompress::Sync).map_err(invalid_data)?;
+      if message.len() > MAX_MESSAGE_SIZE { return Err(invalid_data("inflated message is larger than the maximum message size")); }
+      let now_consumed = (self.inflater.total_in() - start) as usize;
+      // A client may end its deflate stream after a message; the next one starts a new stream. Only the tail appended above can follow the stream's end, so anything else would be silently dropped.
+      if status == Status::StreamEnd {
+        let rest = &payload[now_consumed..];
+        if !rest.is_empty() && rest != DEFLATE_TAIL { return Err(invalid_data("data after the end of the compressed stream")); }
+        self.inflater.reset(false);
+        break;
+      }
+      if now_consumed == payload.len() && message.len() < message.capacity() { break; }
+      if now_consumed == consumed && message.len() == produced && message.len() < message.capacity() {
+        return Err(invalid_data("truncated compressed message"));
+      }
+    }
+    Ok(message)
+  }
+
+  /// Queues a message for tungstenite as uncompressed frames. Clients' frames must be masked, so these are too, with a mask that leaves them as they are.
+  fn emit(&mut self, opcode: u8, message: &[u8]) {
+    if message.is_empty() {
+      FrameHead::write(&mut self.read_out, FIN | opcode, Some([0; 4]), 0);
+      return;
+    }
+    let mut chunks = message.chunks(MAX_INFLATED_FRAME).peekable();
+    let mut opcode = opcode;
+    while let Some(chunk) = chunks.next() {
+      let fin = if chunks.peek().is_none() { FIN } else { 0 };
+      FrameHead::write(&mut self.read_out, fin | opcode, Some([0; 4]), chunk.len());
+      self.read_out.extend_from_slice(chunk);
+      opcode = OP_CONTINUATION;
+    }
+  }
+
+  /// Encodes every whole frame tungstenite has written so far into write_out.
+  fn encode_frames(&mut self) -> io::Result<()> {
+    let input = std::mem::take(&mut self.write_in);
+    let mut pos = 0;
+    while let Some(head) = FrameHead::parse(&input[pos..])? {
+      let frame_len = head.len + head.payload_len;
+      if input.len() - pos < frame_len { break; }
+      self.encode_frame(head, &input[pos..pos + frame_len])?;
+      pos += frame_len;
+    }
+    self.write_in = input;
+    self.write_in.drain(..pos);
+    Ok(())
+  }
+
+  /// Deflates an unfragmented data frame of at least the threshold's size, passing every other frame through as it is. (tungstenite doesn't fragment the messages it sends.)
+  fn encode_frame(&mut self, head: FrameHead, frame: &[u8]) -> io::Result<()> {
+    let payload = &frame[head.len..];
+    if !(head.fin && (head.opcode == OP_TEXT || head.opcode == OP_BINARY)) || payload.len() < self.negotiated.threshold {
+      self.write_out.extend_from_slice(frame);
+      return Ok(());
+    }
+    let compressed = self.deflate(payload)?;
+    FrameHead::write(&mut self.write_out, FIN | RSV1 | head.opcode, None, compressed.len());
+    self.write_out.extend_from_slice(&compressed);
+    Ok(())
+  }
+
+  /// Deflates a message's payload, leaving off the tail every message's compressed payload ends with.
+  fn deflate(&mut self, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut compressed = Vec::with_capacity(payload.len() / 2 + 64);
+    let start = self.deflater.total_in();
+    loop {
+      if compressed.len() == compressed.capacity() { compressed.reserve(compressed.capacity()); }
+      let consumed = (self.deflater.total_in() - start) as usize;
+      self.deflater.compress_vec(&payload[consumed..], &mut compressed, FlushCompress::Sync).map_err(invalid_data)?;
+      let consumed = (self.deflater.total_in() - start) as usize;
+      if consumed == payload.len() && compressed.len() < compressed.capacity() { break; }
+    }
+    if compressed.ends_with(&DEFLATE_TAIL) { compressed.truncate(compressed.len() - DEFLATE_TAIL.len()); }
+    if self.negotiated.server_no_context_takeover { self.deflater.reset(); }
+    Ok(compressed)
+  }
+
+  /// Writes out as much of write_out as the socket takes, returning Ready once all of it is written.
+  fn poll_write_out<S>(&mut self, inner: &mut S, cx: &mut Context<'_>) -> Poll<io::Result<()>> where S: AsyncWrite + Unpin {
+    while self.write_pos < self.write_out.len() {
+      let written = match Pin::new(&mut *inner).poll_write(cx, &self.write_out[self.write_pos..]) {
+        Poll::Ready(Ok(written)) => written,
+        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+        Poll::Pending => return Poll::Pending,
+      };
+      if written == 0 { return Poll::Ready(Err(io::ErrorKind::WriteZero.into())); }
+      self.write_pos += written;
+    }
+    self.write_out.clear();
+    self.write_pos = 0;
+    Poll::Ready(Ok(()))
+  }
+}
+
+/// Unmasks a client frame's payload. Clients must mask every frame.
+fn unmask(payload: &[u8], mask: Option<[u8; 4]>) -> io::Result<Vec<u8>> {
+  let mask = mask.ok_or_else(|| invalid_data("unmasked frame from client"))?;
+  Ok(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect())
+}
+
+impl<S> AsyncRead for DeflateStream<S> where S: AsyncRead + Unpin {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let DeflateStream { inner, codec } = self.get_mut();
+    let codec = match codec {
+      None => return Pin::new(inner).poll_read(cx, buf),
+      Some(codec) => codec,
+    };
+    loop {
+      if codec.read_pos < codec.read_out.len() {
+        let len = buf.remaining().min(codec.read_out.len() - codec.read_pos);
+        buf.put_slice(&codec.read_out[codec.read_pos..codec.read_pos + len]);
+        codec.read_pos += len;
+        if codec.read_pos == codec.read_out.len() {
+          codec.read_out.clear();
+          codec.read_pos = 0;
+        }
+        return Poll::Ready(Ok(()));
+      }
+      if codec.read_eof {
+        // Hand over whatever partial frame is left as it is, for tungstenite to report, then the EOF itself.
+        if codec.read_in.is_empty() { return Poll::Ready(Ok(())); }
+        codec.read_out = std::mem::take(&mut codec.read_in);
+        continue;
+      }
+      let mut chunk = [0u8; 8 * 1024];
+      let mut chunk = ReadBuf::new(&mut chunk);
+      match Pin::new(&mut *inner).poll_read(cx, &mut chunk) {
+        Poll::Ready(Ok(())) => {}
+        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+        Poll::Pending => return Poll::Pending,
+      }
+      if chunk.filled().is_empty() {
+        codec.read_eof = true;
+        continue;
+      }
+      codec.read_in.extend_from_slice(chunk.filled());
+      codec.decode_frames()?;
+    }
+  }
+}
+
+impl<S> AsyncWrite for DeflateStream<S> where S: AsyncWrite + Unpin {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    let DeflateStream { inner, codec } = self.get_mut();
+    let codec = match codec {
+      None => return Pin::new(inner).poll_write(cx, buf),
+      Some(codec) => codec,
+    };
+    // Write out the backlog before taking more, so a slow socket pushes back on tungstenite.
+    if codec.write_out.len() - codec.write_pos >= MAX_WRITE_BACKLOG {
+      match codec.poll_write_out(inner, cx) {
+        Poll::Ready(Ok(())) => {}
+        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+        Poll::Pending => return Poll::Pending,
+      }
+    }
+    codec.write_in.extend_from_slice(buf);
+    codec.encode_frames()?;
+    // The bytes are taken either way; what isn't written now is written on the next write or flush.
+    if let Poll::Ready(Err(err)) = codec.poll_write_out(inner, cx) { return Poll::Ready(Err(err)); }
+    Poll::Ready(Ok(buf.len()))
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    let DeflateStream { inner, codec } = self.get_mut();
+    if let Some(codec) = codec {
+      match codec.poll_write_out(inner, cx) {
+        Poll::Ready(Ok(())) => {}
+        other => return other,
+      }
+    }
+    Pin::new(inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    let DeflateStream { inner, codec } = self.get_mut();
+    if let Some(codec) = codec {
+      match codec.poll_write_out(inner, cx) {
+        Poll::Ready(Ok(())) => {}
+        other => return other,
+      }
+    }
+    Pin::new(inner).poll_shutdown(cx)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+  fn negotiated(threshold: usize) -> Negotiated {
+    Negotiated { server_window_bits: MAX_WINDOW_BITS, server_no_context_takeover: false, threshold }
+  }
+
+  /// A frame as a client sends it, masked.
+  fn client_frame(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![];
+    FrameHead::write(&mut frame, first_byte, Some(MASK), payload.len());
+    frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ MASK[i % 4]));
+    frame
+  }
+
+  /// Compresses a message as a client does, continuing the stream across calls.
+  fn client_deflate(compress: &mut Compress, message: &[u8], flush: FlushCompress) -> Vec<u8> {
+    let mut compressed = Vec::with_capacity(message.len() + 64);
+    compress.compress_vec(message, &mut compressed, flush).unwrap();
+    if compressed.ends_with(&DEFLATE_TAIL) { compressed.truncate(compressed.len() - DEFLATE_TAIL.len()); }
+    compressed
+  }
+
+  /// Splits frames back into their first byte and (unmasked) payload.
+  fn frames(mut buf: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut frames = vec![];
+    while let Some(head) = FrameHead::parse(buf).unwrap() {
+      let payload = &buf[head.len..head.len + head.payload_len];
+      let payload = match head.mask {
+        Some(mask) => payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect(),
+        None => payload.to_vec(),
+      };
+      frames.push((buf[0], payload));
+      buf = &buf[head.len + head.payload_len..];
+    }
+    assert!(buf.is_empty());
+    frames
+  }
+
+  fn decode(codec: &mut Codec, input: &[u8]) -> io::Result<Vec<(u8, Vec<u8>)>> {
+    codec.read_in.extend_from_slice(input);
+    codec.decode_frames()?;
+    Ok(frames(&std::mem::take(&mut codec.read_out)))
+  }
+
+  fn encode(codec: &mut Codec, frame: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    codec.write_in.extend_from_slice(frame);
+    codec.encode_frames().unwrap();
+    frames(&std::mem::take(&mut codec.write_out))
+  }
+
+  #[test]
+  fn parses_each_payload_length_encoding() {
+    for &(payload_len, head_len) in &[(0, 2), (125, 2), (126, 4), (65535, 4), (65536, 10)] {
+      let mut buf = vec![];
+      FrameHead::write(&mut buf, FIN | OP_BINARY, None, payload_len);
+      assert_eq!(buf.len(), head_len);
+      let head = FrameHead::parse(&buf).unwrap().unwrap();
+      assert_eq!((head.len, head.payload_len, head.mask), (head_len, payload_len, None));
+      assert!(head.fin && !head.rsv1 && head.opcode == OP_BINARY);
+      // A header that hasn't all arrived yet isn't parsed.
+      assert!(FrameHead::parse(&buf[..head_len - 1]).unwrap().is_none());
+    }
+  }
+
+  #[test]
+  fn parses_masked_heads() {
+    let mut buf = vec![];
+    FrameHead::write(&mut buf, RSV1 | OP_TEXT, Some(MASK), 300);
+    let head = FrameHead::parse(&buf).unwrap().unwrap();
+    assert_eq!((head.len, head.payload_len, head.mask), (8, 300, Some(MASK)));
+    assert!(!head.fin && head.rsv1 && head.opcode == OP_TEXT);
+    assert!(FrameHead::parse(&buf[..7]).unwrap().is_none());
+  }
+
+  #[test]
+  fn rejects_oversized_frames() {
+    let mut buf = vec![];
+    FrameHead::write(&mut buf, FIN | OP_BINARY, None, MAX_MESSAGE_SIZE + 1);
+    assert!(matches!(FrameHead::parse(&buf), Err(err) if err.kind() == io::ErrorKind::InvalidData));
+  }
+
+  #[test]
+  fn inflates_compressed_messages() {
+    let mut codec = Codec::new(negotiated(0));
+    let mut compress = Compress::new(Compression::default(), false);
+    let message = b"quicksocket ".repeat(100);
+    let first = client_deflate(&mut compress, &message, FlushCompress::Sync);
+    let second = client_deflate(&mut compress, &message, FlushCompress::Sync);
+    // The second message refers back to the first, so it only inflates with the window kept.
+    assert!(second.len() < first.len());
+    let mut input = client_frame(FIN | RSV1 | OP_TEXT, &first);
+    input.extend(client_frame(FIN | RSV1 | OP_BINARY, &second));
+    assert_eq!(decode(&mut codec, &input).unwrap(), vec![(FIN | OP_TEXT, message.clone()), (FIN | OP_BINARY, message)]);
+  }
+
+  #[test]
+  fn inflates_fragmented_messages_around_control_frames() {
+    let mut codec = Codec::new(negotiated(0));
+    let mut compress = Compress::new(Compression::default(), false);
+    let message = b"\x00\x01".repeat(500);
+    let payload = client_deflate(&mut compress, &message, FlushCompress::Sync);
+    let (first, second) = (payload.len() / 3, payload.len() * 2 / 3);
+    let mut input = client_frame(RSV1 | OP_BINARY, &payload[..first]);
+    input.extend(client_frame(FIN | 0x9, b"ping"));
+    input.extend(client_frame(OP_CONTINUATION, &payload[first..second]));
+    // Frames can arrive split anywhere; nothing is decoded until they're whole.
+    let split = input.len() - 3;
+    assert_eq!(decode(&mut codec, &input[..split]).unwrap(), vec![(FIN | 0x9, b"ping".to_vec())]);
+    input = input[split..].to_vec();
+    input.extend(client_frame(FIN | OP_CONTINUATION, &payload[second..]));
+    assert_eq!(decode(&mut codec, &input).unwrap(), vec![(FIN | OP_BINARY, message)]);
+  }
+
+  #[test]
+  fn passes_uncompressed_frames_through() {
+    let mut codec = Codec::new(negotiated(0));
+    let mut input = client_frame(OP_TEXT, b"plain ");
+    input.extend(client_frame(FIN | OP_CONTINUATION, b"text"));
+    input.extend(client_frame(FIN | 0x8, &[0x03, 0xe8]));
+    let decoded = decode(&mut codec, &input).unwrap();
+    assert_eq!(decoded, vec![(OP_TEXT, b"plain ".to_vec()), (FIN | OP_CONTINUATION, b"text".to_vec()), (FIN | 0x8, vec![0x03, 0xe8])]);
+  }
+
+  #[test]
+  fn rejects_malformed_compressed_frames() {
+    let mut compress = Compress::new(Compression::default(), false);
+    let payload = client_deflate(&mut compress, b"quicksocket", FlushCompress::Sync);
+    // RSV1 belongs on a message's first frame only.
+    let mut input = client_frame(RSV1 | OP_TEXT, &payload[..4]);
+    input.extend(client_frame(FIN | RSV1 | OP_CONTINUATION, &payload[4..]));
+    assert!(decode(&mut Codec::new(negotiated(0)), &input).is_err());
+    // Clients must mask their frames.
+    let mut input = vec![];
+    FrameHead::write(&mut input, FIN | RSV1 | OP_TEXT, None, payload.len());
+    input.extend_from_slice(&payload);
+    assert!(decode(&mut Codec::new(negotiated(0)), &input).is_err());
+    // A message can't start before the last one has finished.
+    let mut input = client_frame(RSV1 | OP_TEXT, &payload[..4]);
+    input.extend(client_frame(FIN | RSV1 | OP_TEXT, &payload));
+    assert!(decode(&mut Codec::new(negotiated(0)), &input).is_err());
+  }
+
+  #[test]
+  fn restarts_after_a_finished_stream() {
+    let mut codec = Codec::new(negotiated(0));
+    let first = client_deflate(&mut Compress::new(Compression::default(), false), b"first", FlushCompress::Finish);
+    let second = client_deflate(&mut Compress::new(Compression::default(), false), b"second", FlushCompress::Sync);
+    let mut input = client_frame(FIN | RSV1 | OP_TEXT, &first);
+    input.extend(client_frame(FIN | RSV1 | OP_TEXT, &second));
+    assert_eq!(decode(&mut codec, &input).unwrap(), vec![(FIN | OP_TEXT, b"first".to_vec()), (FIN | OP_TEXT, b"second".to_vec())]);
+  }
+
+  #[test]
+  fn rejects_data_after_a_finished_stream() {
+    let mut payload = client_deflate(&mut Compress::new(Compression::default(), false), b"first", FlushCompress::Finish);
+    payload.extend_from_slice(&client_deflate(&mut Compress::new(Compression::default(), false), b"dropped", FlushCompress::Sync));
+    let err = decode(&mut Codec::new(negotiated(0)), &client_frame(FIN | RSV1 | OP_TEXT, &payload)).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn compresses_messages_from_the_threshold() {
+    let mut codec = Codec::new(negotiated(100));
+    let mut below = vec![];
+    FrameHead::write(&mut below, FIN | OP_TEXT, None, 99);
+    below.extend_from_slice(&[b'a'; 99]);
+    assert_eq!(encode(&mut codec, &below), vec![(FIN | OP_TEXT, vec![b'a'; 99])]);
+
+    let mut at = vec![];
+    FrameHead::write(&mut at, FIN | OP_BINARY, None, 100);
+    at.extend_from_slice(&[b'a'; 100]);
+    let encoded = encode(&mut codec, &at);
+    assert_eq!(encoded.len(), 1);
+    assert_eq!(encoded[0].0, FIN | RSV1 | OP_BINARY);
+    let mut payload = encoded[0].1.clone();
+    payload.extend_from_slice(&DEFLATE_TAIL);
+    let mut message = Vec::with_capacity(200);
+    Decompress::new(false).decompress_vec(&payload, &mut message, FlushDecompress::Sync).unwrap();
+    assert_eq!(message, vec![b'a'; 100]);
+
+    // Control frames are never compressed, whatever their size.
+    let mut ping = vec![];
+    FrameHead::write(&mut ping, FIN | 0x9, None, 125);
+    ping.extend_from_slice(&[b'a'; 125]);
+    assert_eq!(encode(&mut codec, &ping), vec![(FIN | 0x9, vec![b'a'; 125])]);
+  }
+
+  #[test]
+  fn negotiates_client_offers() {
+    let config = CompressionConfig { client_max_window_bits: 12, ..CompressionConfig::default() };
+    let (negotiated, response) = config.negotiate(["permessage-deflate; client_max_window_bits; server_no_context_takeover"].iter().copied()).unwrap();
+    assert_eq!(response, "permessage-deflate; server_no_context_takeover; client_max_window_bits=12");
+    assert_eq!(negotiated, Negotiated { server_window_bits: 15, server_no_context_takeover: true, threshold: DEFAULT_THRESHOLD });
+
+    // The first offer the server can honour is taken, across header values too.
+    let offers = ["x-webkit-deflate-frame, permessage-deflate; server_max_window_bits=8", "permessage-deflate; server_max_window_bits=\"10\""];
+    let (negotiated, response) = CompressionConfig::default().negotiate(offers.iter().copied()).unwrap();
+    assert_eq!(response, "permessage-deflate; server_max_window_bits=10");
+    assert_eq!(negotiated.server_window_bits, 10);
+
+    // A client that doesn't offer to limit its window is never asked to.
+    assert_eq!(config.negotiate(["permessage-deflate"].iter().copied()).unwrap().1, "permessage-deflate");
+  }
+
+  #[test]
+  fn declines_offers_it_cannot_honour() {
+    let config = CompressionConfig::default();
+    for offer in &[
+      "deflate-frame",
+      "permessage-deflate; bogus",
+      "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+      "permessage-deflate; server_max_window_bits=8",
+      "permessage-deflate; server_max_window_bits=16",
+      "permessage-deflate; server_max_window_bits",
+      "permessage-deflate; client_max_window_bits=7",
+      "permessage-deflate; server_no_context_takeover=1",
+    ] {
+      assert!(config.negotiate(std::iter::once(*offer)).is_none(), "accepted {:?}", offer);
+    }
+  }
+}
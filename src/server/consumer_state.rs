@@ -1,141 +1,187 @@
 // consumer_state.rs
 //
-// Internal handlers for managing static-scope (singleton) server state in a thread-safe manner.
+// Internal handlers for managing per-server consumer-side state in a thread-safe manner.
 //
-// Static server state is guarded for thread-safe access using a blocking RwLock. This is definitely not optimal, and it'd probably be better to use tokio async locks and keep everything async, but I'm not sure what the best design for that is yet for a library receiving calls from the Python consumer thread. -Nick 2021-02-24
+// Server state is guarded for thread-safe access using a blocking RwLock. This is definitely not optimal, and it'd probably be better to use tokio async locks and keep everything async, but I'm not sure what the best design for that is yet for a library receiving calls from the Python consumer thread. -Nick 2021-02-24
 
 use std::{sync::{RwLock}};
-use tokio::sync::{broadcast, mpsc, watch};
+use tokio::sync::watch;
 
-type CS<T> = RwLock<Option<T>>;
+use super::{ClientId, clients::ClientRegistry, endpoints::EndpointRegistry, topics::TopicRegistry, errors::{ErrorKind, ErrorQueue}, events::{ConnectEvent, DisconnectEvent, EventSignal, LagEvent}, metrics::MetricsSource, queue::{BoundedQueue, BroadcastQueue, ResyncSnapshot}};
+
+pub type CS<T> = RwLock<Option<T>>;
 type WsMessage = tokio_tungstenite::tungstenite::Message;
 
-// Lazy Static
-// -----------
+// Consumer State
+// --------------
 //
 // Various RwLock<Option<T>>s (here short-handed to CS, for Consumer State) guard against thread-unsafe access to consumer state -- consisting mostly of channels used to communicate with the tokio runtime thread. Channels as a whole are thread-safe, but the channel ends -- Senders, Receivers -- are expected to be used from just one thread at a time! Hence the RwLock, so the consumer can't get cheeky with the channels when invoking library functions from different threads.
 //
 // However, it's totally OK for the consumer to access *different* channel ends from different threads; e.g., to check for client messages on one thread while sending server messages from another.
+//
+// Each server instance owns one ConsumerState, so several servers can run side by side in one process without sharing any channels.
 
-lazy_static! {
+pub struct ConsumerState {
   /// Consumer thread(s) receiver for whether the Tokio server thread is alive.
-  pub static ref CS_SER_ALIVE_RX: CS<watch::Receiver<bool>> =
-    RwLock::new(None);
+  pub ser_alive_rx: CS<watch::Receiver<bool>>,
 
-  /// Consumer thread(s) receiver for events indicating newly-connected clients. The server-side consumer should drain this receiver regularly.
-  pub static ref CS_CLI_CONN_RX: CS<mpsc::Receiver<String>> =
-    RwLock::new(None);
+  /// Consumer thread(s) end of the queue of events indicating newly-connected clients, with their client IDs, peer addresses and upgrade requests. The server-side consumer should drain this queue regularly.
+  pub cli_conn_queue: CS<BoundedQueue<ConnectEvent>>,
 
-  /// Consumer thread(s) clone of the server message transmitter, used to subscribe new receivers for any new connections.
+  /// Consumer thread(s) clone of the server message broadcast queue, used to send messages to every connection.
   ///
-  /// This is a clone of the Sender owned by the TokioState struct.
-  pub static ref CS_SER_MSG_TX: CS<broadcast::Sender<Vec<WsMessage>>> =
-    RwLock::new(None);
+  /// This is a clone of the BroadcastQueue owned by the tokio server thread.
+  pub ser_msg_queue: CS<BroadcastQueue>,
+
+  /// Consumer thread(s) end of the queue of text and binary messages from any connected clients, each tagged with the ID of the client that sent it. The server-side consumer should drain this queue regularly.
+  pub cli_msg_queue: CS<BoundedQueue<(ClientId, WsMessage)>>,
 
-  /// Consumer thread(s) receiver for messages from any connected clients. The server-side consumer should drain this receiver regularly.
-  pub static ref CS_CLI_MSG_RX: CS<mpsc::Receiver<WsMessage>> =
-    RwLock::new(None);
+  /// Consumer thread(s) end of the queue of events indicating clients that fell behind the server broadcast. The server-side consumer should drain this queue regularly; if it doesn't, the oldest events are dropped.
+  pub cli_lag_queue: CS<BoundedQueue<LagEvent>>,
+
+  /// Consumer thread(s) end of the queue of events indicating disconnected clients. The server-side consumer should drain this queue regularly; what happens when it's full depends on its overflow policy.
+  pub cli_disconn_queue: CS<BoundedQueue<DisconnectEvent>>,
 
   /// Consumer thread(s) transmitter for requesting tokio to shut down.
-  pub static ref CS_SER_REQ_SHUTDOWN_TX: CS<watch::Sender<bool>> =
-    RwLock::new(None);
+  pub ser_req_shutdown_tx: CS<watch::Sender<bool>>,
 
-  /// Very coarse way of providing some quick error reporting to the consumer without panicking.
-  static ref LAST_ERROR: CS<String> = RwLock::new(None);
-}
+  /// Consumer thread(s) view of the currently-open connections, used to queue messages for specific clients.
+  pub clients: CS<ClientRegistry>,
 
-// Error API
-// ---------
-//
-// This is probably not a very good error API. -Nick 2021-04-21
+  /// Consumer thread(s) view of the server's topics, used to publish to them and to subscribe clients.
+  pub topics: CS<TopicRegistry>,
 
-/// Attempts to record the passed &str to the thread-safe LAST_ERROR storage. The method may fail silently if it can't get write access to LAST_ERROR, hence, "weakly". This is very likely to clobber errors if more than one propagates in short succession across more than one thread.
-pub fn weakly_record_error(msg: String) {
-  let last_err = LAST_ERROR.try_write();
-  if last_err.is_err() { return; /* silently fail. */ }
-  let mut last_err = last_err.unwrap();
+  /// Consumer thread(s) view of the server's endpoints, used to drain and broadcast to endpoints other than the default. The default endpoint's queues are also cli_msg_queue and ser_msg_queue above.
+  pub endpoints: CS<EndpointRegistry>,
 
-  *last_err = Some(msg);
-}
+  /// Consumer thread(s) view of the server-wide counters kept by tokio, plus the registries and queues the stats are read from.
+  pub metrics: CS<MetricsSource>,
 
-/// Returns the last error if possible. Returns None only if there is no last error.
-pub fn try_get_last_error() -> Option<String> {
-  // Attempt to lock and unwrap.
-  let err_store = LAST_ERROR.read();
-  if err_store.is_err() { return Some("Couldn't get last error.".to_string()); }
+  /// Errors from both the consumer side and the tokio side, for the consumer to drain. Unlike the channels above, this lives as long as the ConsumerState, so errors from a failed start() are kept too.
+  pub errors: ErrorQueue,
 
-  // Convert (possibly poisoned) lock into a Result.
-  let err_store = err_store.as_deref();
-  if err_store.is_err() { return Some("Couldn't get last error.".to_string()); }
+  /// Notified by the tokio side whenever it sends the consumer an event, so consumer threads can block until there's something to drain. Like the error queue, this lives as long as the ConsumerState.
+  pub event_signal: EventSignal,
 
-  // If the error is None, great! Otherwise return a copy of the string content.
-  let err_store = err_store.unwrap().as_ref();
-  err_store.map_or(None, |s| Some(String::from(s)))
+  /// Sent to clients that fall behind the server broadcast, under the resync lag policy. Set by the consumer whenever it likes, so like the error queue, this lives as long as the ConsumerState.
+  pub resync_snapshot: ResyncSnapshot,
 }
 
-// State API
-// ---------
-//
-
-/// Pass one of the "CS_" (consumer state) statics available in the consumer_state module and an operating function to do something with read access to that static (e.g. receive a message from a consumer channel).
-pub fn read<T, U, R, F>(lazy_static_item: &R, f: F) -> Option<U>
-where
-  F: FnOnce(&T) -> U,
-  R: std::ops::Deref<Target = RwLock<Option<T>>>
-{
-  let read_guard = lazy_static_item.read();
-  if read_guard.is_err() {
-    weakly_record_error(format!("Failed to get read access to {}.", std::any::type_name::<R>()));
-    return None;
+impl ConsumerState {
+  pub fn new() -> Self {
+    ConsumerState {
+      ser_alive_rx: RwLock::new(None),
+      cli_conn_queue: RwLock::new(None),
+      ser_msg_queue: RwLock::new(None),
+      cli_msg_queue: RwLock::new(None),
+      cli_lag_queue: RwLock::new(None),
+      cli_disconn_queue: RwLock::new(None),
+      ser_req_shutdown_tx: RwLock::new(None),
+      clients: RwLock::new(None),
+      topics: RwLock::new(None),
+      endpoints: RwLock::new(None),
+      metrics: RwLock::new(None),
+      errors: ErrorQueue::new(),
+      event_signal: EventSignal::new(),
+      resync_snapshot: ResyncSnapshot::new(),
+    }
   }
-  let read_guard = read_guard.unwrap();
 
-  let item = read_guard.as_ref();
-  if item.is_none() {
-    weakly_record_error(format!("Failed to get read access to {}, as it hasn't been created yet. Is the server running?", std::any::type_name::<R>()));
-    return None;
+  // Error API
+  // ---------
+  //
+
+  /// Records an error to the error queue.
+  pub fn record_error(&self, kind: ErrorKind, msg: String) {
+    self.errors.record(kind, msg, None);
   }
-  let item = item.unwrap();
 
-  Some(f(item))
-}
+  /// Records an error concerning a specific client to the error queue.
+  pub fn record_client_error(&self, kind: ErrorKind, msg: String, client_id: ClientId) {
+    self.errors.record(kind, msg, Some(client_id));
+  }
 
-/// Pass one of the "CS_" (consumer state) statics available in the consumer_state module and an operating function to do something with mutable access to that static (e.g. send a message using a Sender).
-pub fn mutate<T, U, R, F>(lazy_static_item: &R, f: F) -> Option<U>
-where
-  F: FnOnce(&mut T) -> U,
-  R: std::ops::Deref<Target = RwLock<Option<T>>>
-{
-  let write_guard = lazy_static_item.write();
-  if write_guard.is_err() {
-    weakly_record_error(format!("Failed to get write access to {}.", std::any::type_name::<R>()));
-    return None;
+  /// Returns the message of the last error recorded, or None if there hasn't been one.
+  pub fn try_get_last_error(&self) -> Option<String> {
+    self.errors.last_message()
   }
-  let mut write_guard = write_guard.unwrap();
 
-  let state = write_guard.as_mut();
-  if state.is_none() {
-    weakly_record_error(format!("Failed to mutable ref for {}, as it hasn't been created yet. Is the server running?", std::any::type_name::<R>()));
-    return None;
+  // Status API
+  // ----------
+  //
+
+  /// Whether the server has been started and its tokio thread hasn't exited or been asked to shut down. Unlike the alive flag, this is already true before the tokio runtime has finished starting up, so it's what consumers waiting for events should check.
+  pub fn is_serving(&self) -> bool {
+    let shutdown_requested = self.read(&self.ser_req_shutdown_tx, |tx| *tx.borrow()).unwrap_or(true);
+    // The tokio thread drops its end of the alive channel when it exits.
+    let thread_exited = self.read(&self.ser_alive_rx, |rx| rx.has_changed().is_err()).unwrap_or(true);
+    !shutdown_requested && !thread_exited
   }
-  let state = state.unwrap();
 
-  Some(f(state))
-}
+  // State API
+  // ---------
+  //
+
+  /// Pass one of this ConsumerState's fields and an operating function to do something with read access to that field (e.g. receive a message from a consumer channel).
+  ///
+  /// Fails with LockPoisoned, which is also recorded as an error, or with NotRunning if the server has never been started, which isn't.
+  pub fn read<T, U, F>(&self, item: &CS<T>, f: F) -> Result<U, ErrorKind>
+  where
+    F: FnOnce(&T) -> U
+  {
+    let read_guard = item.read();
+    if read_guard.is_err() {
+      self.record_error(ErrorKind::LockPoisoned, format!("Failed to get read access to {}.", std::any::type_name::<T>()));
+      return Err(ErrorKind::LockPoisoned);
+    }
+    let read_guard = read_guard.unwrap();
+
+    let item = read_guard.as_ref();
+    if item.is_none() {
+      return Err(ErrorKind::NotRunning);
+    }
+    let item = item.unwrap();
+
+    Ok(f(item))
+  }
 
+  /// Pass one of this ConsumerState's fields and an operating function to do something with mutable access to that field (e.g. send a message using a Sender).
+  ///
+  /// Fails with LockPoisoned, which is also recorded as an error, or with NotRunning if the server has never been started, which isn't.
+  pub fn mutate<T, U, F>(&self, item: &CS<T>, f: F) -> Result<U, ErrorKind>
+  where
+    F: FnOnce(&mut T) -> U
+  {
+    let write_guard = item.write();
+    if write_guard.is_err() {
+      self.record_error(ErrorKind::LockPoisoned, format!("Failed to get write access to {}.", std::any::type_name::<T>()));
+      return Err(ErrorKind::LockPoisoned);
+    }
+    let mut write_guard = write_guard.unwrap();
+
+    let state = write_guard.as_mut();
+    if state.is_none() {
+      return Err(ErrorKind::NotRunning);
+    }
+    let state = state.unwrap();
+
+    Ok(f(state))
+  }
 
-/// Pass one of the "CS_" (consumer state) statics available in the consumer_state module and a value of the inner type to set the RwLock<Option<T>> with Some<T>. This is used internally by the server::start() function to initialize consumer-side channels.
-pub fn set_value<T, R>(lazy_static_item: &R, new_val: T) -> Result<(), ()>
-where
-  R: std::ops::Deref<Target = RwLock<Option<T>>>
-{
-  let write_guard = lazy_static_item.write();
-  if write_guard.is_err() {
-    weakly_record_error(format!("Failed to get write access to {} to set its value.", std::any::type_name::<R>()));
-    return Err(());
+  /// Pass one of this ConsumerState's fields and a value of the inner type to set the RwLock<Option<T>> with Some<T>. This is used internally by the server::start() function to initialize consumer-side channels.
+  pub fn set_value<T>(&self, item: &CS<T>, new_val: T) -> Result<(), ErrorKind> {
+    let write_guard = item.write();
+    if write_guard.is_err() {
+      self.record_error(ErrorKind::LockPoisoned, format!("Failed to get write access to {} to set its value.", std::any::type_name::<T>()));
+      return Err(ErrorKind::LockPoisoned);
+    }
+    let mut write_guard = write_guard.unwrap();
+
+    (*write_guard) = Some(new_val);
+    Ok(())
   }
-  let mut write_guard = write_guard.unwrap();
+}
 
-  (*write_guard) = Some(new_val);
-  Ok(())
+impl Default for ConsumerState {
+  fn default() -> Self { Self::new() }
 }
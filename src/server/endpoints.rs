@@ -0,0 +1,70 @@
+// endpoints.rs
+//
+// Path-based routing of incoming connections. Each endpoint is a request path with its own client message queue and broadcast, so e.g. clients of ws://host/hands and ws://host/debug can be told apart and served separately.
+//
+// The default endpoint is the server's main queues. It serves the root path "/", or every path if no other endpoints are registered; with endpoints registered, requests for any other path are rejected with a 404.
+
+use std::{collections::HashMap, sync::Arc};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{ClientId, queue::{BoundedQueue, BroadcastQueue, QueueConfig, ResyncSnapshot}};
+
+/// The path of the default endpoint.
+pub const DEFAULT_ENDPOINT_PATH: &str = "/";
+
+/// A single endpoint: the queues its clients' messages arrive on and its broadcast goes out on.
+#[derive(Clone)]
+pub struct Endpoint {
+  pub cli_msg_queue: BoundedQueue<(ClientId, Message)>,
+  pub ser_msg_queue: BroadcastQueue,
+  /// Sent to clients that fall behind this endpoint's broadcast, under LagPolicy::Resync.
+  pub snapshot: ResyncSnapshot,
+}
+
+/// Cheaply cloneable map of request path -> Endpoint. Fixed when the server starts.
+#[derive(Clone)]
+pub struct EndpointRegistry {
+  default: Endpoint,
+  registered: Arc<HashMap<String, Endpoint>>,
+}
+
+impl EndpointRegistry {
+  /// Sets up the default endpoint along with one endpoint per registered path. The registered endpoints' queues are configured like the default's, and their broadcasts share its drop count.
+  pub fn new(default: Endpoint, paths: &[String], client_message_queue: QueueConfig) -> Self {
+    let registered = paths.iter().map(|path| {
+      let endpoint = Endpoint {
+        cli_msg_queue: BoundedQueue::new(client_message_queue),
+        ser_msg_queue: default.ser_msg_queue.sibling(),
+        snapshot: ResyncSnapshot::new(),
+      };
+      (path.clone(), endpoint)
+    }).collect();
+    EndpointRegistry { default, registered: Arc::new(registered) }
+  }
+
+  /// The endpoint serving a request path (without its query string), or None if the path should be rejected.
+  pub fn route(&self, path: &str) -> Option<&Endpoint> {
+    if self.registered.is_empty() || path == DEFAULT_ENDPOINT_PATH { return Some(&self.default); }
+    self.registered.get(path)
+  }
+
+  /// The endpoint registered at exactly this path, or the default endpoint for None (or "/").
+  pub fn get(&self, path: Option<&str>) -> Option<&Endpoint> {
+    match path {
+      None | Some(DEFAULT_ENDPOINT_PATH) => Some(&self.default),
+      Some(path) => self.registered.get(path),
+    }
+  }
+
+  /// Every endpoint with its path, the default endpoint first and the rest in path order.
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &Endpoint)> {
+    let mut registered: Vec<(&str, &Endpoint)> = self.registered.iter().map(|(path, endpoint)| (path.as_str(), endpoint)).collect();
+    registered.sort_by_key(|(path, _)| *path);
+    std::iter::once((DEFAULT_ENDPOINT_PATH, &self.default)).chain(registered)
+  }
+
+  /// The number of client messages dropped so far, across every endpoint.
+  pub fn client_messages_dropped(&self) -> u64 {
+    self.default.cli_msg_queue.dropped() + self.registered.values().map(|endpoint| endpoint.cli_msg_queue.dropped()).sum::<u64>()
+  }
+}
@@ -0,0 +1,105 @@
+// errors.rs
+//
+// Bounded queue of typed errors, shared between the consumer and the tokio server thread so that problems on either side (a port already in use, a failed handshake, a closed channel) reach the consumer without panicking and without later errors clobbering earlier ones.
+
+use std::{collections::VecDeque, sync::{Arc, Mutex}, time::SystemTime};
+
+use super::ClientId;
+
+/// How many errors are kept before the oldest are dropped to make room.
+pub const ERROR_QUEUE_CAPACITY: usize = 256;
+
+/// The kinds of error the server reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+  /// A listen address couldn't be resolved or bound.
+  Bind,
+  /// A TLS certificate or key couldn't be loaded, or a client's TLS handshake failed.
+  Tls,
+  /// A client's websocket handshake failed.
+  Handshake,
+  /// A client's connection failed after the handshake.
+  Connection,
+  /// A lock guarding server state was poisoned by a panic on another thread.
+  LockPoisoned,
+  /// A channel between the consumer and the tokio thread was closed.
+  ChannelClosed,
+  /// The operation needs a running server.
+  NotRunning,
+  /// The addressed client isn't connected.
+  ClientNotConnected,
+  /// A queue was full, so something was dropped.
+  QueueFull,
+  /// The server was asked to do something that can't be done, e.g. start twice.
+  InvalidRequest,
+  /// A Python handler called by the dispatcher raised an exception.
+  Callback,
+  /// A client was turned away by a security check, e.g. for an Origin that isn't allowed.
+  Security,
+}
+
+impl ErrorKind {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ErrorKind::Bind               => "bind",
+      ErrorKind::Tls                => "tls",
+      ErrorKind::Handshake          => "handshake",
+      ErrorKind::Connection         => "connection",
+      ErrorKind::LockPoisoned       => "lock_poisoned",
+      ErrorKind::ChannelClosed      => "channel_closed",
+      ErrorKind::NotRunning         => "not_running",
+      ErrorKind::ClientNotConnected => "client_not_connected",
+      ErrorKind::QueueFull          => "queue_full",
+      ErrorKind::InvalidRequest     => "invalid_request",
+      ErrorKind::Callback           => "callback",
+      ErrorKind::Security           => "security",
+    }
+  }
+}
+
+/// A single recorded error.
+#[derive(Clone, Debug)]
+pub struct ServerError {
+  pub kind: ErrorKind,
+  pub message: String,
+  pub timestamp: SystemTime,
+  /// The client the error concerns, if any.
+  pub client_id: Option<ClientId>,
+}
+
+/// Cheaply cloneable, thread-safe, bounded error queue. When full, the oldest error is dropped.
+#[derive(Clone, Default)]
+pub struct ErrorQueue {
+  inner: Arc<Mutex<ErrorQueueInner>>,
+}
+
+#[derive(Default)]
+struct ErrorQueueInner {
+  errors: VecDeque<ServerError>,
+  /// Kept apart from the queue so draining doesn't forget it.
+  last_message: Option<String>,
+}
+
+impl ErrorQueue {
+  pub fn new() -> Self { Self::default() }
+
+  /// Records an error. If the queue lock was poisoned the queue is recovered rather than losing the error.
+  pub fn record(&self, kind: ErrorKind, message: String, client_id: Option<ClientId>) {
+    let mut inner = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if inner.errors.len() >= ERROR_QUEUE_CAPACITY { inner.errors.pop_front(); }
+    inner.last_message = Some(message.clone());
+    inner.errors.push_back(ServerError { kind, message, timestamp: SystemTime::now(), client_id });
+  }
+
+  /// Removes and returns every queued error, oldest first.
+  pub fn drain(&self) -> Vec<ServerError> {
+    let mut inner = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    inner.errors.drain(..).collect()
+  }
+
+  /// The message of the most recently recorded error, whether or not it has been drained.
+  pub fn last_message(&self) -> Option<String> {
+    let inner = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    inner.last_message.clone()
+  }
+}
@@ -0,0 +1,169 @@
+// events.rs
+//
+// Events reported by the tokio server thread to the consumer, beyond plain client messages.
+
+use std::{sync::{Arc, Condvar, Mutex}, time::Instant};
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+use super::{ClientId, handshake::HandshakeInfo};
+
+/// Close code reported when the client's close frame carried no status code (RFC 6455 section 7.4.1).
+pub const CLOSE_CODE_NO_STATUS: u16 = 1005;
+/// Close code reported when the connection dropped without a close frame (RFC 6455 section 7.4.1).
+pub const CLOSE_CODE_ABNORMAL: u16 = 1006;
+
+/// How a connection ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectKind {
+  /// The close handshake completed.
+  Clean,
+  /// The connection stopped responding.
+  TimedOut,
+  /// The connection failed or dropped without a close handshake.
+  Error,
+}
+
+impl DisconnectKind {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      DisconnectKind::Clean    => "clean",
+      DisconnectKind::TimedOut => "timed_out",
+      DisconnectKind::Error    => "error",
+    }
+  }
+}
+
+/// Reported once for every client connection that completes its handshake.
+#[derive(Clone, Debug)]
+pub struct ConnectEvent {
+  pub client_id: ClientId,
+  pub peer: String,
+  /// The client's upgrade request.
+  pub request: HandshakeInfo,
+  /// The subprotocol selected for the connection, if the server has any configured.
+  pub subprotocol: Option<String>,
+}
+
+/// Reported once for every client connection that completed its handshake, after the connection has closed.
+#[derive(Clone, Debug)]
+pub struct DisconnectEvent {
+  pub client_id: ClientId,
+  pub peer: String,
+  pub kind: DisconnectKind,
+  /// The close code from the close frame, or one of the reserved 1005/1006 codes if there wasn't a (complete) close frame.
+  pub code: u16,
+  pub reason: String,
+}
+
+/// Why a connection ended, as observed by the connection's tasks. Combined with the client ID and peer address to make a DisconnectEvent.
+#[derive(Clone, Debug)]
+pub struct DisconnectCause {
+  pub kind: DisconnectKind,
+  pub code: u16,
+  pub reason: String,
+}
+
+impl DisconnectCause {
+  pub fn clean(frame: Option<CloseFrame>) -> Self {
+    match frame {
+      Some(frame) => DisconnectCause { kind: DisconnectKind::Clean, code: frame.code.into(), reason: frame.reason.into_owned() },
+      None        => DisconnectCause { kind: DisconnectKind::Clean, code: CLOSE_CODE_NO_STATUS, reason: String::new() },
+    }
+  }
+
+  pub fn abnormal(kind: DisconnectKind, reason: String) -> Self {
+    DisconnectCause { kind, code: CLOSE_CODE_ABNORMAL, reason }
+  }
+
+  pub fn into_event(self, client_id: ClientId, peer: String) -> DisconnectEvent {
+    DisconnectEvent { client_id, peer, kind: self.kind, code: self.code, reason: self.reason }
+  }
+}
+
+/// What the server did about a client that fell behind the server broadcast.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LagAction {
+  /// The client carries on from the next batch.
+  Continued,
+  /// The client was sent the resync snapshot, then carries on from the next batch.
+  Resynced,
+  /// The client is being disconnected.
+  Disconnected,
+}
+
+impl LagAction {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      LagAction::Continued    => "continued",
+      LagAction::Resynced     => "resynced",
+      LagAction::Disconnected => "disconnected",
+    }
+  }
+}
+
+/// Reported each time a client falls a full broadcast queue behind (on the server broadcast or one of its topics) and skips some batches.
+#[derive(Clone, Debug)]
+pub struct LagEvent {
+  pub client_id: ClientId,
+  /// The topic the client fell behind on, or None for the server broadcast.
+  pub topic: Option<String>,
+  /// How many batches the client just skipped.
+  pub skipped: u64,
+  /// How many batches the client has skipped since it connected, including these.
+  pub total_skipped: u64,
+  pub action: LagAction,
+}
+
+/// Wakes consumers waiting for events, whether they're threads blocked in wait_for_events or async tasks. The tokio side notifies it after every event it sends to the consumer (a new client, a client message, a lagging client, a disconnect) and when the server stops.
+///
+/// It counts notifications rather than holding a flag, so a consumer that reads the count before draining its channels can't miss an event that arrives between draining and waiting.
+#[derive(Clone, Default)]
+pub struct EventSignal {
+  inner: Arc<EventSignalInner>,
+}
+
+#[derive(Default)]
+struct EventSignalInner {
+  count: Mutex<u64>,
+  condvar: Condvar,
+  notify: Notify,
+}
+
+impl EventSignal {
+  pub fn new() -> Self { Self::default() }
+
+  /// Wakes every waiting thread and task.
+  pub fn notify(&self) {
+    let mut count = self.inner.count.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    *count = count.wrapping_add(1);
+    self.inner.condvar.notify_all();
+    self.inner.notify.notify_waiters();
+  }
+
+  /// The number of notifications so far, to pass to wait_past.
+  pub fn count(&self) -> u64 {
+    *self.inner.count.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  /// Blocks until there has been a notification since `seen` was read, or until the deadline. Returns false if the deadline passed first.
+  pub fn wait_past(&self, seen: u64, deadline: Instant) -> bool {
+    let mut count = self.inner.count.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    while *count == seen {
+      let now = Instant::now();
+      if now >= deadline { return false; }
+      count = self.inner.condvar.wait_timeout(count, deadline - now).unwrap_or_else(|poisoned| poisoned.into_inner()).0;
+    }
+    true
+  }
+
+  /// Like wait_past, but asynchronous and without a deadline.
+  pub async fn notified_past(&self, seen: u64) {
+    loop {
+      // Created before checking the count, so a notification in between isn't missed.
+      let notified = self.inner.notify.notified();
+      if self.count() != seen { return; }
+      notified.await;
+    }
+  }
+}
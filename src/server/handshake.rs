@@ -0,0 +1,139 @@
+// handshake.rs
+//
+// What the server captures from each client's HTTP upgrade request, and the optional hook that decides whether to accept it. The hook runs during the websocket handshake, after the request has been routed to an endpoint, so it can turn clients away (e.g. for a missing session cookie) before they're ever reported to the consumer.
+
+use std::fmt;
+use tokio_tungstenite::tungstenite::{handshake::server::{ErrorResponse, Request, Response}, http::{HeaderMap, HeaderName, HeaderValue, StatusCode}};
+
+/// Status code for rejecting a client whose handshake hook failed.
+pub const HOOK_FAILED_STATUS: u16 = 500;
+
+/// The parts of a client's upgrade request the server keeps: where it asked to connect, and its headers.
+#[derive(Clone, Debug, Default)]
+pub struct HandshakeInfo {
+  /// The request path, without the query string.
+  pub path: String,
+  /// The query string, without the leading "?", if there was one.
+  pub query: Option<String>,
+  /// Every request header, in the order received. Names are lowercase.
+  pub headers: Vec<(String, String)>,
+}
+
+impl HandshakeInfo {
+  pub fn from_request(request: &Request) -> Self {
+    let headers = request.headers().iter()
+      .map(|(name, value)| (name.as_str().to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+      .collect();
+    HandshakeInfo { path: request.uri().path().to_string(), query: request.uri().query().map(str::to_string), headers }
+  }
+
+  /// The path and query string together, as requested, e.g. "/hands?user=3".
+  pub fn path_and_query(&self) -> String {
+    match &self.query {
+      Some(query) => format!("{}?{}", self.path, query),
+      None => self.path.clone(),
+    }
+  }
+
+  /// The values of a header, by lowercase name.
+  pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    self.headers.iter().filter(move |(header, _)| header == name).map(|(_, value)| value.as_str())
+  }
+
+  /// The subprotocols the client offered in its Sec-WebSocket-Protocol header(s), in its order of preference.
+  pub fn subprotocols(&self) -> Vec<&str> {
+    self.header_values("sec-websocket-protocol")
+      .flat_map(|protocols| protocols.split(','))
+      .map(str::trim)
+      .filter(|protocol| !protocol.is_empty())
+      .collect()
+  }
+
+  /// Picks the first of the server's `supported` subprotocols (in its priority order) that the client offered, or None if it offered none of them.
+  pub fn select_subprotocol(&self, supported: &[String]) -> Option<String> {
+    let offered = self.subprotocols();
+    supported.iter().find(|protocol| offered.contains(&protocol.as_str())).cloned()
+  }
+
+  /// The cookies sent in the request's Cookie header(s), as (name, value) pairs.
+  pub fn cookies(&self) -> Vec<(String, String)> {
+    self.header_values("cookie")
+      .flat_map(|cookies| cookies.split(';'))
+      .filter_map(|cookie| {
+        let (name, value) = cookie.split_once('=')?;
+        Some((name.trim().to_string(), value.trim().to_string()))
+      })
+      .collect()
+  }
+}
+
+/// Whether a request's Origin is allowed by an allowlist of origins, as checked by ServerConfig::check_origins. Each entry is either an exact origin such as "https://viz.example.com", or a wildcard such as "https://*.example.com", which allows any subdomain (but not example.com itself).
+pub fn origin_allowed(allowed: &[String], origin: &str) -> bool {
+  let origin = origin.trim_end_matches('/').to_ascii_lowercase();
+  allowed.iter().any(|pattern| match pattern.split_once("://*.") {
+    Some((scheme, domain)) => origin.strip_prefix(scheme)
+      .and_then(|rest| rest.strip_prefix("://"))
+      .and_then(|host| host.strip_suffix(domain))
+      .and_then(|subdomain| subdomain.strip_suffix('.'))
+      .is_some_and(|subdomain| !subdomain.is_empty() && !subdomain.contains([':', '/'])),
+    None => *pattern == origin,
+  })
+}
+
+/// What to do with a client's upgrade request.
+#[derive(Clone, Debug)]
+pub enum HandshakeDecision {
+  /// Complete the upgrade, adding these headers to the response.
+  Accept { headers: Vec<(String, String)> },
+  /// Turn the client away with this HTTP status, body and headers.
+  Reject { status: u16, body: Option<String>, headers: Vec<(String, String)> },
+}
+
+impl HandshakeDecision {
+  /// Applies the decision to tungstenite's response. Headers that aren't valid HTTP are skipped, and are returned as errors to report; an invalid status rejects with a 500.
+  pub fn respond(self, mut response: Response) -> (Result<Response, ErrorResponse>, Vec<String>) {
+    match self {
+      HandshakeDecision::Accept { headers } => {
+        let errors = add_headers(response.headers_mut(), headers);
+        (Ok(response), errors)
+      }
+      HandshakeDecision::Reject { status, body, headers } => {
+        let mut rejection = ErrorResponse::new(body);
+        let mut errors = add_headers(rejection.headers_mut(), headers);
+        *rejection.status_mut() = StatusCode::from_u16(status).unwrap_or_else(|_| {
+          errors.push(format!("Invalid handshake rejection status {}.", status));
+          StatusCode::INTERNAL_SERVER_ERROR
+        });
+        (Err(rejection), errors)
+      }
+    }
+  }
+}
+
+fn add_headers(map: &mut HeaderMap, headers: Vec<(String, String)>) -> Vec<String> {
+  let mut errors = vec![];
+  for (name, value) in headers {
+    match (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(&value)) {
+      (Ok(name), Ok(value)) => { map.append(name, value); }
+      _ => errors.push(format!("Invalid handshake response header {:?}: {:?}.", name, value)),
+    }
+  }
+  errors
+}
+
+/// Decides whether to accept each client's upgrade request. Called from the tokio server thread, once per handshake, and allowed to block (e.g. to take the GIL for a Python hook). Any `Fn(&HandshakeInfo) -> HandshakeDecision` closure is one.
+pub trait HandshakeHook: Send + Sync {
+  fn decide(&self, info: &HandshakeInfo) -> HandshakeDecision;
+}
+
+impl<F> HandshakeHook for F where F: Fn(&HandshakeInfo) -> HandshakeDecision + Send + Sync {
+  fn decide(&self, info: &HandshakeInfo) -> HandshakeDecision {
+    self(info)
+  }
+}
+
+impl fmt::Debug for dyn HandshakeHook {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("HandshakeHook")
+  }
+}
@@ -0,0 +1,196 @@
+// http.rs
+//
+// Plain HTTP on the websocket port. When the server has a static directory (or serves metrics on its main port), each connection's request head is read up front: websocket upgrade requests are replayed into the usual websocket handshake, and anything else is served by hyper, so one port can ship a whole web app along with its socket. Metrics can also be served on a listener of their own.
+
+use std::{convert::Infallible, future::Future, io, path::{Component, Path, PathBuf}, pin::Pin, sync::Arc, task::{Context, Poll}};
+use hyper::{Body, Method, Request, Response, Server, StatusCode, header, server::conn::Http, service::{make_service_fn, service_fn}};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use super::metrics::{METRICS_PATH, MetricsSource};
+
+/// The most bytes read looking for the end of a request head. Longer heads are handed on as they are, to be rejected by whichever side gets them.
+const MAX_REQUEST_HEAD: usize = 16 * 1024;
+
+/// The file served for a request for a directory.
+const INDEX_FILE: &str = "index.html";
+
+/// Reads from a stream until the end of the HTTP request head (or MAX_REQUEST_HEAD bytes, or EOF), and returns everything read.
+pub async fn read_request_head<S>(stream: &mut S) -> io::Result<Vec<u8>> where S: AsyncRead + Unpin {
+  let mut head = Vec::with_capacity(1024);
+  let mut chunk = [0u8; 1024];
+  while head.len() < MAX_REQUEST_HEAD && !head.windows(4).any(|window| window == b"\r\n\r\n") {
+    let read = stream.read(&mut chunk).await?;
+    if read == 0 { break; }
+    head.extend_from_slice(&chunk[..read]);
+  }
+  if head.is_empty() {
+    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before sending a request"));
+  }
+  Ok(head)
+}
+
+/// Whether a request head asks to upgrade to a websocket.
+pub fn is_websocket_upgrade(head: &[u8]) -> bool {
+  String::from_utf8_lossy(head).split("\r\n").skip(1)
+    .take_while(|line| !line.is_empty())
+    .filter_map(|line| line.split_once(':'))
+    .any(|(name, value)| name.trim().eq_ignore_ascii_case("upgrade") && value.to_ascii_lowercase().contains("websocket"))
+}
+
+/// A stream whose first reads return bytes that were already read from it, so a request head read by read_request_head can be read again.
+pub struct Rewind<S> {
+  prefix: Vec<u8>,
+  pos: usize,
+  inner: S,
+}
+
+impl<S> Rewind<S> {
+  pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+    Rewind { prefix, pos: 0, inner }
+  }
+}
+
+impl<S> AsyncRead for Rewind<S> where S: AsyncRead + Unpin {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    if self.pos < self.prefix.len() {
+      let len = buf.remaining().min(self.prefix.len() - self.pos);
+      let start = self.pos;
+      buf.put_slice(&self.prefix[start..start + len]);
+      self.pos += len;
+      return Poll::Ready(Ok(()));
+    }
+    Pin::new(&mut self.inner).poll_read(cx, buf)
+  }
+}
+
+impl<S> AsyncWrite for Rewind<S> where S: AsyncWrite + Unpin {
+  fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.inner).poll_write(cx, buf)
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_shutdown(cx)
+  }
+}
+
+/// What plain HTTP requests are answered with. Cheaply cloneable.
+#[derive(Clone)]
+pub struct HttpRoutes {
+  /// Files are served from here, if set.
+  pub static_dir: Option<Arc<PathBuf>>,
+  /// Metrics are served at METRICS_PATH, if set.
+  pub metrics: Option<MetricsSource>,
+}
+
+impl HttpRoutes {
+  /// Answers a single request. Only GET and HEAD are allowed.
+  async fn respond(self, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+      return Ok(Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header(header::ALLOW, "GET, HEAD")
+        .body(Body::from("Method Not Allowed"))
+        .unwrap());
+    }
+    match (&self.metrics, &self.static_dir) {
+      (Some(metrics), _) if request.uri().path() == METRICS_PATH => Ok(respond_with(&request, "text/plain; version=0.0.4; charset=utf-8", metrics.render().into_bytes())),
+      (_, Some(root)) => Ok(serve_file(root, &request).await),
+      _ => Ok(not_found()),
+    }
+  }
+}
+
+/// Serves plain HTTP requests on a connection until the client closes it.
+pub async fn serve_http<S>(stream: S, routes: HttpRoutes) -> Result<(), hyper::Error> where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+  let service = service_fn(move |request| routes.clone().respond(request));
+  Http::new().http1_only(true).serve_connection(stream, service).await
+}
+
+/// Serves plain HTTP requests from a listener of its own until `shutdown` completes.
+pub async fn serve_listener(listener: std::net::TcpListener, routes: HttpRoutes, shutdown: impl Future<Output = ()>) -> Result<(), hyper::Error> {
+  let make_service = make_service_fn(move |_conn| {
+    let routes = routes.clone();
+    async move { Ok::<_, Infallible>(service_fn(move |request| routes.clone().respond(request))) }
+  });
+  Server::from_tcp(listener)?.http1_only(true).serve(make_service).with_graceful_shutdown(shutdown).await
+}
+
+/// Answers a request with the file it names under `root`. Paths that would leave `root` are not found.
+async fn serve_file(root: &Path, request: &Request<Body>) -> Response<Body> {
+  let mut path = match resolve_path(root, request.uri().path()) {
+    Some(path) => path,
+    None => return not_found(),
+  };
+  if tokio::fs::metadata(&path).await.map(|metadata| metadata.is_dir()).unwrap_or(false) {
+    path.push(INDEX_FILE);
+  }
+  match tokio::fs::read(&path).await {
+    Ok(contents) => respond_with(request, content_type(&path), contents),
+    Err(_) => not_found(),
+  }
+}
+
+/// A 200 response with the given content, or just its headers for a HEAD request.
+fn respond_with(request: &Request<Body>, content_type: &str, contents: Vec<u8>) -> Response<Body> {
+  let response = Response::builder()
+    .header(header::CONTENT_TYPE, content_type)
+    .header(header::CONTENT_LENGTH, contents.len());
+  let body = if request.method() == Method::HEAD { Body::empty() } else { Body::from(contents) };
+  response.body(body).unwrap()
+}
+
+fn not_found() -> Response<Body> {
+  Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("Not Found")).unwrap()
+}
+
+/// Maps a (percent-encoded) request path onto a file path under `root`, or None if it isn't a plain relative path.
+fn resolve_path(root: &Path, request_path: &str) -> Option<PathBuf> {
+  let decoded = percent_decode(request_path)?;
+  let relative = Path::new(decoded.trim_start_matches('/'));
+  let plain = relative.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+  if !plain || decoded.contains('\\') { return None; }
+  Some(root.join(relative))
+}
+
+/// Decodes %XX escapes, or returns None if the result isn't valid UTF-8.
+fn percent_decode(path: &str) -> Option<String> {
+  let bytes = path.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    let escaped = match bytes.get(i + 1..i + 3) {
+      Some(hex) if bytes[i] == b'%' => std::str::from_utf8(hex).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+      _ => None,
+    };
+    match escaped {
+      Some(byte) => { decoded.push(byte); i += 3; }
+      None => { decoded.push(bytes[i]); i += 1; }
+    }
+  }
+  String::from_utf8(decoded).ok()
+}
+
+/// The Content-Type for a file, by its extension.
+fn content_type(path: &Path) -> &'static str {
+  let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_ascii_lowercase();
+  match extension.as_str() {
+    "html" | "htm" => "text/html; charset=utf-8",
+    "js" | "mjs"   => "text/javascript; charset=utf-8",
+    "css"          => "text/css; charset=utf-8",
+    "json" | "map" => "application/json",
+    "txt"          => "text/plain; charset=utf-8",
+    "svg"          => "image/svg+xml",
+    "png"          => "image/png",
+    "jpg" | "jpeg" => "image/jpeg",
+    "gif"          => "image/gif",
+    "ico"          => "image/x-icon",
+    "wasm"         => "application/wasm",
+    "woff"         => "font/woff",
+    "woff2"        => "font/woff2",
+    _              => "application/octet-stream",
+  }
+}
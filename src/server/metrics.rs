@@ -0,0 +1,292 @@
+// metrics.rs
+//
+// Server-wide counters, kept by the tokio tasks as connections come and go and messages flow, and their rendering in the Prometheus text exposition format for the optional /metrics endpoint.
+
+use std::{fmt::Write, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant, SystemTime}};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{clients::ClientRegistry, endpoints::EndpointRegistry, events::{ConnectEvent, DisconnectEvent, LagEvent}, queue::BoundedQueue, topics::TopicRegistry};
+
+/// The path metrics are served on.
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Why a websocket handshake didn't complete.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandshakeFailure {
+  /// The TLS handshake failed.
+  Tls,
+  /// The Origin isn't allowed.
+  Origin,
+  /// No endpoint serves the request path.
+  UnknownPath,
+  /// The client offered none of the server's subprotocols.
+  Subprotocol,
+  /// The handshake hook rejected the request.
+  Hook,
+  /// The handshake failed for any other reason, e.g. a malformed request.
+  Error,
+}
+
+impl HandshakeFailure {
+  const ALL: [HandshakeFailure; 6] = [HandshakeFailure::Tls, HandshakeFailure::Origin, HandshakeFailure::UnknownPath, HandshakeFailure::Subprotocol, HandshakeFailure::Hook, HandshakeFailure::Error];
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      HandshakeFailure::Tls         => "tls",
+      HandshakeFailure::Origin      => "origin",
+      HandshakeFailure::UnknownPath => "unknown_path",
+      HandshakeFailure::Subprotocol => "subprotocol",
+      HandshakeFailure::Hook        => "hook",
+      HandshakeFailure::Error       => "error",
+    }
+  }
+}
+
+/// Cheaply cloneable set of server-wide counters, created when the server starts. Every count only goes up while the server runs.
+#[derive(Clone)]
+pub struct Counters {
+  inner: Arc<CountersInner>,
+}
+
+struct CountersInner {
+  started: Instant,
+  connections: AtomicU64,
+  peak_clients: AtomicU64,
+  handshake_failures: [AtomicU64; HandshakeFailure::ALL.len()],
+  messages_received: AtomicU64,
+  bytes_received: AtomicU64,
+  messages_sent: AtomicU64,
+  bytes_sent: AtomicU64,
+  lag_skipped: AtomicU64,
+}
+
+impl Default for Counters {
+  fn default() -> Self { Self::new() }
+}
+
+impl Counters {
+  pub fn new() -> Self {
+    Counters {
+      inner: Arc::new(CountersInner {
+        started: Instant::now(),
+        connections: AtomicU64::new(0),
+        peak_clients: AtomicU64::new(0),
+        handshake_failures: Default::default(),
+        messages_received: AtomicU64::new(0),
+        bytes_received: AtomicU64::new(0),
+        messages_sent: AtomicU64::new(0),
+        bytes_sent: AtomicU64::new(0),
+        lag_skipped: AtomicU64::new(0),
+      }),
+    }
+  }
+
+  /// Counts a client that completed its handshake, leaving `connected_clients` connected (including it).
+  pub fn connected(&self, connected_clients: usize) {
+    self.inner.connections.fetch_add(1, Ordering::Relaxed);
+    self.inner.peak_clients.fetch_max(connected_clients as u64, Ordering::Relaxed);
+  }
+
+  /// Counts a handshake that didn't complete.
+  pub fn handshake_failed(&self, failure: HandshakeFailure) {
+    self.inner.handshake_failures[failure as usize].fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Counts a text or binary message received from a client.
+  pub fn received(&self, msg: &Message) {
+    self.inner.messages_received.fetch_add(1, Ordering::Relaxed);
+    self.inner.bytes_received.fetch_add(msg.len() as u64, Ordering::Relaxed);
+  }
+
+  /// Counts messages written to a client.
+  pub fn sent(&self, messages: u64, bytes: u64) {
+    self.inner.messages_sent.fetch_add(messages, Ordering::Relaxed);
+    self.inner.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+  }
+
+  /// Counts broadcast batches a lagging client skipped.
+  pub fn lagged(&self, skipped: u64) {
+    self.inner.lag_skipped.fetch_add(skipped, Ordering::Relaxed);
+  }
+
+  /// How long since the server started.
+  pub fn uptime(&self) -> Duration { self.inner.started.elapsed() }
+  pub fn connections(&self) -> u64 { self.inner.connections.load(Ordering::Relaxed) }
+  pub fn peak_clients(&self) -> u64 { self.inner.peak_clients.load(Ordering::Relaxed) }
+  pub fn handshake_failures(&self, failure: HandshakeFailure) -> u64 { self.inner.handshake_failures[failure as usize].load(Ordering::Relaxed) }
+  pub fn messages_received(&self) -> u64 { self.inner.messages_received.load(Ordering::Relaxed) }
+  pub fn bytes_received(&self) -> u64 { self.inner.bytes_received.load(Ordering::Relaxed) }
+  pub fn messages_sent(&self) -> u64 { self.inner.messages_sent.load(Ordering::Relaxed) }
+  pub fn bytes_sent(&self) -> u64 { self.inner.bytes_sent.load(Ordering::Relaxed) }
+  pub fn lag_skipped(&self) -> u64 { self.inner.lag_skipped.load(Ordering::Relaxed) }
+}
+
+/// Cheaply cloneable counters for a single connection, kept by its tasks. Everything recorded here also counts towards the server-wide Counters.
+#[derive(Clone)]
+pub struct ClientStats {
+  counters: Counters,
+  inner: Arc<ClientStatsInner>,
+}
+
+struct ClientStatsInner {
+  connected_at: SystemTime,
+  messages_received: AtomicU64,
+  bytes_received: AtomicU64,
+  messages_sent: AtomicU64,
+  bytes_sent: AtomicU64,
+  lag_events: AtomicU64,
+  lag_skipped: AtomicU64,
+  /// Nanoseconds since connected_at.
+  last_activity: AtomicU64,
+  /// Nanoseconds, or NO_RTT until the first pong.
+  rtt: AtomicU64,
+}
+
+const NO_RTT: u64 = u64::MAX;
+
+impl ClientStats {
+  /// Stats for a client that just connected.
+  pub fn new(counters: &Counters) -> Self {
+    ClientStats {
+      counters: counters.clone(),
+      inner: Arc::new(ClientStatsInner {
+        connected_at: SystemTime::now(),
+        messages_received: AtomicU64::new(0),
+        bytes_received: AtomicU64::new(0),
+        messages_sent: AtomicU64::new(0),
+        bytes_sent: AtomicU64::new(0),
+        lag_events: AtomicU64::new(0),
+        lag_skipped: AtomicU64::new(0),
+        last_activity: AtomicU64::new(0),
+        rtt: AtomicU64::new(NO_RTT),
+      }),
+    }
+  }
+
+  /// Notes that something was just read from or written to the client.
+  pub fn touch(&self) {
+    let since = SystemTime::now().duration_since(self.inner.connected_at).map(|since| since.as_nanos() as u64).unwrap_or(0);
+    self.inner.last_activity.fetch_max(since, Ordering::Relaxed);
+  }
+
+  /// Counts a text or binary message received from the client.
+  pub fn received(&self, msg: &Message) {
+    self.counters.received(msg);
+    self.inner.messages_received.fetch_add(1, Ordering::Relaxed);
+    self.inner.bytes_received.fetch_add(msg.len() as u64, Ordering::Relaxed);
+    self.touch();
+  }
+
+  /// Counts messages written to the client.
+  pub fn sent(&self, messages: u64, bytes: u64) {
+    self.counters.sent(messages, bytes);
+    self.inner.messages_sent.fetch_add(messages, Ordering::Relaxed);
+    self.inner.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+    self.touch();
+  }
+
+  /// Counts a time the client fell a full queue behind and skipped broadcast batches.
+  pub fn lagged(&self, skipped: u64) {
+    self.counters.lagged(skipped);
+    self.inner.lag_events.fetch_add(1, Ordering::Relaxed);
+    self.inner.lag_skipped.fetch_add(skipped, Ordering::Relaxed);
+  }
+
+  /// Records the round-trip time measured from a keepalive ping's pong.
+  pub fn set_rtt(&self, rtt: Duration) {
+    self.inner.rtt.store((rtt.as_nanos() as u64).min(NO_RTT - 1), Ordering::Relaxed);
+  }
+
+  pub fn messages_received(&self) -> u64 { self.inner.messages_received.load(Ordering::Relaxed) }
+  pub fn bytes_received(&self) -> u64 { self.inner.bytes_received.load(Ordering::Relaxed) }
+  pub fn messages_sent(&self) -> u64 { self.inner.messages_sent.load(Ordering::Relaxed) }
+  pub fn bytes_sent(&self) -> u64 { self.inner.bytes_sent.load(Ordering::Relaxed) }
+  /// The round-trip time measured from the last keepalive pong, if any.
+  pub fn rtt(&self) -> Option<Duration> {
+    match self.inner.rtt.load(Ordering::Relaxed) {
+      NO_RTT => None,
+      nanos => Some(Duration::from_nanos(nanos)),
+    }
+  }
+  pub fn lag_events(&self) -> u64 { self.inner.lag_events.load(Ordering::Relaxed) }
+  pub fn lag_skipped(&self) -> u64 { self.inner.lag_skipped.load(Ordering::Relaxed) }
+  /// When the client completed its handshake.
+  pub fn connected_at(&self) -> SystemTime { self.inner.connected_at }
+  /// When something was last read from or written to the client (or when it connected, if nothing has been yet).
+  pub fn last_activity(&self) -> SystemTime { self.inner.connected_at + Duration::from_nanos(self.inner.last_activity.load(Ordering::Relaxed)) }
+}
+
+/// Everything the metrics are read from: the counters, plus the registries and queues the gauges are read from. Cheaply cloneable.
+#[derive(Clone)]
+pub struct MetricsSource {
+  pub counters: Counters,
+  pub clients: ClientRegistry,
+  pub endpoints: EndpointRegistry,
+  pub topics: TopicRegistry,
+  pub cli_conn_queue: BoundedQueue<ConnectEvent>,
+  pub cli_lag_queue: BoundedQueue<LagEvent>,
+  pub cli_disconn_queue: BoundedQueue<DisconnectEvent>,
+}
+
+impl MetricsSource {
+  /// Renders every metric in the Prometheus text exposition format (version 0.0.4).
+  pub fn render(&self) -> String {
+    let mut out = String::new();
+    let counters = &self.counters;
+
+    metric(&mut out, "quicksocket_connected_clients", "gauge", "Clients currently connected.");
+    sample(&mut out, "quicksocket_connected_clients", &[], self.clients.count() as u64);
+    metric(&mut out, "quicksocket_peak_connected_clients", "gauge", "The most clients connected at once since the server started.");
+    sample(&mut out, "quicksocket_peak_connected_clients", &[], counters.peak_clients());
+    metric(&mut out, "quicksocket_topics", "gauge", "Topics with subscribers or a resync snapshot.");
+    sample(&mut out, "quicksocket_topics", &[], self.topics.count() as u64);
+    metric(&mut out, "quicksocket_uptime_seconds", "gauge", "Seconds since the server started.");
+    sample(&mut out, "quicksocket_uptime_seconds", &[], counters.uptime().as_secs());
+    metric(&mut out, "quicksocket_connections_total", "counter", "Clients that completed the websocket handshake.");
+    sample(&mut out, "quicksocket_connections_total", &[], counters.connections());
+    metric(&mut out, "quicksocket_handshake_failures_total", "counter", "Connections that didn't complete the handshake, by reason.");
+    for failure in HandshakeFailure::ALL.iter() {
+      sample(&mut out, "quicksocket_handshake_failures_total", &[("reason", failure.as_str())], counters.handshake_failures(*failure));
+    }
+
+    metric(&mut out, "quicksocket_messages_received_total", "counter", "Text and binary messages received from clients.");
+    sample(&mut out, "quicksocket_messages_received_total", &[], counters.messages_received());
+    metric(&mut out, "quicksocket_received_bytes_total", "counter", "Payload bytes received from clients.");
+    sample(&mut out, "quicksocket_received_bytes_total", &[], counters.bytes_received());
+    metric(&mut out, "quicksocket_messages_sent_total", "counter", "Messages sent to clients.");
+    sample(&mut out, "quicksocket_messages_sent_total", &[], counters.messages_sent());
+    metric(&mut out, "quicksocket_sent_bytes_total", "counter", "Payload bytes sent to clients.");
+    sample(&mut out, "quicksocket_sent_bytes_total", &[], counters.bytes_sent());
+    metric(&mut out, "quicksocket_broadcast_lag_skipped_total", "counter", "Broadcast batches skipped by clients that fell a full queue behind.");
+    sample(&mut out, "quicksocket_broadcast_lag_skipped_total", &[], counters.lag_skipped());
+
+    metric(&mut out, "quicksocket_queue_depth", "gauge", "Events waiting to be drained by the consumer, by queue.");
+    sample(&mut out, "quicksocket_queue_depth", &[("queue", "new_client_events")], self.cli_conn_queue.depth() as u64);
+    sample(&mut out, "quicksocket_queue_depth", &[("queue", "client_lag_events")], self.cli_lag_queue.depth() as u64);
+    sample(&mut out, "quicksocket_queue_depth", &[("queue", "client_disconnect_events")], self.cli_disconn_queue.depth() as u64);
+    metric(&mut out, "quicksocket_endpoint_queue_depth", "gauge", "Client messages waiting to be drained, and broadcast batches the furthest-behind client has yet to be sent, by endpoint.");
+    for (path, endpoint) in self.endpoints.iter() {
+      sample(&mut out, "quicksocket_endpoint_queue_depth", &[("endpoint", path), ("queue", "client_messages")], endpoint.cli_msg_queue.depth() as u64);
+      sample(&mut out, "quicksocket_endpoint_queue_depth", &[("endpoint", path), ("queue", "broadcast")], endpoint.ser_msg_queue.depth() as u64);
+    }
+    out
+  }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+  let _ = writeln!(out, "# HELP {} {}", name, help);
+  let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: u64) {
+  let labels: Vec<String> = labels.iter().map(|(label, value)| format!("{}=\"{}\"", label, escape_label(value))).collect();
+  if labels.is_empty() {
+    let _ = writeln!(out, "{} {}", name, value);
+  } else {
+    let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+  }
+}
+
+fn escape_label(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -1,30 +1,292 @@
-use std::thread;
-use tokio::sync::{broadcast, mpsc, watch};
+use std::{net::{SocketAddr, TcpListener, ToSocketAddrs}, path::PathBuf, sync::Arc, thread, time::Duration};
+use log::debug;
+use tokio::sync::watch;
 
+pub mod clients;
+pub mod compression;
 pub mod consumer_state;
+pub mod endpoints;
+pub mod errors;
+pub mod events;
+pub mod handshake;
+mod http;
+pub mod metrics;
+pub mod queue;
+pub mod tls;
+pub mod topics;
 mod tokio_server;
 
-pub fn start(port: u32) -> Result<(), ()> {
+use clients::ClientRegistry;
+use compression::CompressionConfig;
+use consumer_state::ConsumerState;
+use endpoints::{DEFAULT_ENDPOINT_PATH, Endpoint, EndpointRegistry};
+use errors::ErrorKind;
+use events::{ConnectEvent, DisconnectEvent, LagEvent};
+use handshake::HandshakeHook;
+use metrics::{Counters, MetricsSource};
+use queue::{BoundedQueue, BroadcastQueue, DEFAULT_QUEUE_CAPACITY, LagPolicy, OverflowPolicy, QueueConfig};
+use tls::TlsConfig;
+use tokio_server::TokioChannels;
+use topics::TopicRegistry;
+
+/// Unique (per server) identifier assigned to each accepted client connection. IDs start at 1 and are never reused while the server runs.
+pub type ClientId = u64;
+
+/// Configuration for a single server instance, fixed for the lifetime of its tokio thread.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+  /// Every address to listen on. Each gets its own listener and accept loop; all of them feed the same client and message channels.
+  pub bind_addrs: Vec<SocketAddr>,
+  /// Serve wss:// instead of ws:// using this certificate and key.
+  pub tls: Option<TlsConfig>,
+  /// The queue of new client events (tokio -> consumer), reported once a client completes the websocket handshake. Under the Disconnect policy, connections that complete it while the queue is full are closed right away.
+  pub new_client_queue: QueueConfig,
+  /// The queue of client messages (tokio -> consumer), for each endpoint. Under the Disconnect policy, a client whose message arrives while its endpoint's queue is full is disconnected.
+  pub client_message_queue: QueueConfig,
+  /// The server message broadcast queue (consumer -> every client). Under the Disconnect policy, clients that fall a full queue behind are disconnected.
+  pub broadcast_queue: QueueConfig,
+  /// The queue of client disconnect events (tokio -> consumer), reported once a reported client's connection has closed. It can't use the Disconnect policy, as the client is already gone.
+  pub disconnect_queue: QueueConfig,
+  /// What to do with a client that falls behind the broadcast, when broadcast_queue lets it skip batches.
+  pub lag_policy: LagPolicy,
+  /// Whether clients can subscribe themselves to topics, by sending "quicksocket:subscribe:<topic>" (and "quicksocket:unsubscribe:<topic>") text messages. These are handled by the server and never reach the consumer.
+  pub topic_control: bool,
+  /// Request paths (e.g. "/hands") served as separate endpoints, each with its own client message queue and broadcast. If there are any, the default endpoint only serves "/", and requests for any other path are rejected with a 404.
+  pub endpoints: Vec<String>,
+  /// The origins browser clients may connect from, as exact origins or wildcard subdomains (see handshake::origin_allowed). If there are any, requests with any other Origin are rejected with a 403, and recorded as security errors. Requests without an Origin, which browsers always send, are allowed either way. If there are none, every origin is allowed.
+  pub allowed_origins: Vec<String>,
+  /// The subprotocols the server speaks, in priority order. If there are any, each handshake selects the first one the client offered, and clients that offered none of them are rejected with a 400. If there are none, clients' subprotocols are ignored.
+  pub subprotocols: Vec<String>,
+  /// Decides whether to accept each client's upgrade request, after it's been routed to an endpoint. Without one, every routed request is accepted.
+  pub handshake_hook: Option<Arc<dyn HandshakeHook>>,
+  /// Compress messages with clients that offer permessage-deflate. Without it, the extension is never agreed and every message is sent uncompressed.
+  pub compression: Option<CompressionConfig>,
+  /// A directory to serve plain HTTP GET requests from, on the same port as the websockets (e.g. a web app's HTML and JS). Without one, plain HTTP requests just fail the websocket handshake.
+  pub static_dir: Option<PathBuf>,
+  /// Serve Prometheus metrics at /metrics on the main listeners, answering plain HTTP requests there even without a static_dir.
+  pub metrics: bool,
+  /// Serve Prometheus metrics at /metrics on this address instead, with a listener of its own.
+  pub metrics_addr: Option<SocketAddr>,
+  /// Ping every client periodically, disconnecting those that don't answer in time. Without it, dead connections are only noticed once the OS gives up on them.
+  pub keepalive: Option<KeepaliveConfig>,
+}
+
+/// How often clients are pinged, and how long they have to answer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeepaliveConfig {
+  /// Time between pings.
+  pub interval: Duration,
+  /// Time a client has to answer a ping with a pong, counted from when the ping is written to its socket, before it's disconnected.
+  pub timeout: Duration,
+}
+
+impl ServerConfig {
+  /// Default for new_client_queue: senders wait, so new clients aren't served until the consumer catches up.
+  pub const DEFAULT_NEW_CLIENT_QUEUE: QueueConfig = QueueConfig { capacity: DEFAULT_QUEUE_CAPACITY, overflow: OverflowPolicy::Block };
+  /// Default for client_message_queue: a client's reads wait, so a client outpacing the consumer is slowed down rather than losing messages.
+  pub const DEFAULT_CLIENT_MESSAGE_QUEUE: QueueConfig = QueueConfig { capacity: DEFAULT_QUEUE_CAPACITY, overflow: OverflowPolicy::Block };
+  /// Default for broadcast_queue: clients that fall behind skip the oldest batches, so a slow client never holds up the consumer or the other clients.
+  pub const DEFAULT_BROADCAST_QUEUE: QueueConfig = QueueConfig { capacity: DEFAULT_QUEUE_CAPACITY, overflow: OverflowPolicy::DropOldest };
+  /// Default for disconnect_queue: closing connections wait, so every client reported as connected is also reported as disconnected.
+  pub const DEFAULT_DISCONNECT_QUEUE: QueueConfig = QueueConfig { capacity: DEFAULT_QUEUE_CAPACITY, overflow: OverflowPolicy::Block };
+
+
+  /// Resolves the addresses to listen on, either from a host name or IP plus a port, or from a list of "host:port" strings (e.g. "0.0.0.0:9000", "[::1]:9000").
+  pub fn resolve_bind_addrs(port: Option<u16>, host: &str, addresses: Option<Vec<String>>) -> Result<Vec<SocketAddr>, String> {
+    let mut bind_addrs: Vec<SocketAddr> = vec![];
+    let mut add_resolved = |resolved: std::io::Result<std::vec::IntoIter<SocketAddr>>, desc: &str| {
+      let resolved = resolved.map_err(|err| format!("Failed to resolve bind address {}: {}", desc, err))?;
+      for addr in resolved {
+        if !bind_addrs.contains(&addr) { bind_addrs.push(addr); }
+      }
+      Ok::<(), String>(())
+    };
+
+    match (addresses, port) {
+      (Some(addresses), _) => {
+        for address in addresses { add_resolved(address.to_socket_addrs(), &address)?; }
+      }
+      (None, Some(port)) => {
+        add_resolved((host, port).to_socket_addrs(), &format!("{}:{}", host, port))?;
+      }
+      (None, None) => {
+        return Err("Either a port or a list of addresses is required to start the server.".to_string());
+      }
+    }
+
+    if bind_addrs.is_empty() { return Err("No addresses to bind to.".to_string()); }
+    Ok(bind_addrs)
+  }
+
+  /// Checks the disconnect queue's config: any overflow policy but Disconnect.
+  pub fn check_disconnect_queue(queue: QueueConfig) -> Result<QueueConfig, String> {
+    if queue.overflow == OverflowPolicy::Disconnect {
+      return Err("The disconnect queue can't use the \"disconnect\" overflow policy: its clients are already disconnected.".to_string());
+    }
+    Ok(queue)
+  }
+
+  /// Checks a list of endpoint paths: each must start with "/", with no query string, and can't be "/" itself (the default endpoint's path). Duplicates are dropped.
+  pub fn check_endpoints(paths: Vec<String>) -> Result<Vec<String>, String> {
+    let mut endpoints: Vec<String> = vec![];
+    for path in paths {
+      if !path.starts_with('/') || path.contains('?') {
+        return Err(format!("Invalid endpoint path {:?}; expected a path starting with \"/\", without a query string.", path));
+      }
+      if path == DEFAULT_ENDPOINT_PATH {
+        return Err(format!("{:?} is always served by the default endpoint, so it can't be registered.", path));
+      }
+      if !endpoints.contains(&path) { endpoints.push(path); }
+    }
+    Ok(endpoints)
+  }
+
+  /// Checks an Origin allowlist: each entry must be "scheme://host" or "scheme://host:port", where the host may start with "*." to allow its subdomains. Entries are lowercased and duplicates are dropped.
+  pub fn check_origins(origins: Vec<String>) -> Result<Vec<String>, String> {
+    let mut allowed: Vec<String> = vec![];
+    for origin in origins {
+      let normalized = origin.trim_end_matches('/').to_ascii_lowercase();
+      let valid = match normalized.split_once("://") {
+        Some((scheme, host)) => {
+          let host = host.strip_prefix("*.").unwrap_or(host);
+          !scheme.is_empty() && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+            && !host.is_empty() && !host.contains(|c: char| c == '/' || c == '*' || c == '?' || c == '#' || c.is_whitespace())
+        }
+        None => false,
+      };
+      if !valid {
+        return Err(format!("Invalid origin {:?}; expected e.g. \"https://example.com\" or \"https://*.example.com\".", origin));
+      }
+      if !allowed.contains(&normalized) { allowed.push(normalized); }
+    }
+    Ok(allowed)
+  }
+
+  /// Checks a list of subprotocol names: each must be a non-empty HTTP token (so no spaces or commas). Duplicates are dropped.
+  pub fn check_subprotocols(protocols: Vec<String>) -> Result<Vec<String>, String> {
+    let mut subprotocols: Vec<String> = vec![];
+    for protocol in protocols {
+      let is_token = !protocol.is_empty() && protocol.bytes().all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b));
+      if !is_token {
+        return Err(format!("Invalid subprotocol {:?}; expected a name without spaces or separators such as \",\".", protocol));
+      }
+      if !subprotocols.contains(&protocol) { subprotocols.push(protocol); }
+    }
+    Ok(subprotocols)
+  }
+
+  /// Checks a ping interval and pong timeout, in seconds: both must be positive. The timeout defaults to the interval. No interval means no keepalive.
+  pub fn check_keepalive(interval_s: Option<f64>, timeout_s: Option<f64>) -> Result<Option<KeepaliveConfig>, String> {
+    let seconds = |name: &str, s: f64| match Duration::try_from_secs_f64(s) {
+      Ok(duration) if !duration.is_zero() => Ok(duration),
+      _ => Err(format!("Invalid {} {}; expected a positive number of seconds.", name, s)),
+    };
+    let interval = match interval_s {
+      Some(interval_s) => seconds("ping interval", interval_s)?,
+      None if timeout_s.is_some() => return Err("A pong timeout needs a ping interval.".to_string()),
+      None => return Ok(None),
+    };
+    let timeout = match timeout_s {
+      Some(timeout_s) => seconds("pong timeout", timeout_s)?,
+      None => interval,
+    };
+    Ok(Some(KeepaliveConfig { interval, timeout }))
+  }
+}
+
+pub type ServerThreadHandle = thread::JoinHandle<Result<String, String>>;
+
+/// Binds the listeners and launches the server's tokio thread. On failure, the error has been recorded to the consumer's error queue, and its kind is returned.
+pub fn start(config: ServerConfig, cs: &ConsumerState) -> Result<ServerThreadHandle, ErrorKind> {
+  // Load the TLS certificate and key (if any) before binding, so bad paths are reported right away too.
+  let tls_acceptor = match &config.tls {
+    Some(tls_config) => match tls::load_acceptor(tls_config) {
+      Ok(acceptor) => Some(acceptor),
+      Err(err) => {
+        cs.record_error(ErrorKind::Tls, err);
+        return Err(ErrorKind::Tls);
+      }
+    },
+    None => None,
+  };
+
+  // Likewise, check the static directory (if any) exists before binding.
+  if let Some(static_dir) = &config.static_dir {
+    if !static_dir.is_dir() {
+      cs.record_error(ErrorKind::InvalidRequest, format!("Can't serve static files from {}: it isn't a directory.", static_dir.display()));
+      return Err(ErrorKind::InvalidRequest);
+    }
+  }
+
+  // Bind every listener up front on the calling thread, so that e.g. a port already in use is reported to the consumer right away.
+  let mut listeners = vec![];
+  for addr in &config.bind_addrs {
+    debug!("Attempting to bind TcpListener at: {}", addr);
+    let listener = TcpListener::bind(addr).and_then(|listener| {
+      listener.set_nonblocking(true)?;
+      Ok(listener)
+    });
+    if let Err(err) = listener {
+      cs.record_error(ErrorKind::Bind, format!("Failed to bind TcpListener at {}. It's possible that the port is already in use. Details: {}", addr, err));
+      return Err(ErrorKind::Bind);
+    }
+    listeners.push(listener.unwrap());
+  }
+  let metrics_listener = match config.metrics_addr {
+    Some(addr) => match TcpListener::bind(addr).and_then(|listener| { listener.set_nonblocking(true)?; Ok(listener) }) {
+      Ok(listener) => Some(listener),
+      Err(err) => {
+        cs.record_error(ErrorKind::Bind, format!("Failed to bind the metrics listener at {}. It's possible that the port is already in use. Details: {}", addr, err));
+        return Err(ErrorKind::Bind);
+      }
+    },
+    None => None,
+  };
+
   // Server thread-alive channel.
   let (ser_thread_alive_tokio_tx, ser_alive_consumer_rx) = {
     watch::channel::<bool>(false)
   };
 
-  // Client connection event channel.
-  let (cli_conn_tokio_tx, cli_conn_consumer_rx) = {
-    mpsc::channel::<String>(16)
-  };
+  // Client connection event queue. Both sides share it: tokio pushes, the consumer takes.
+  let cli_conn_queue = BoundedQueue::<ConnectEvent>::new(config.new_client_queue);
 
-  // Server message broadcast channel (consumer -> server -> client(s)).
-  let (ser_msg_tokio_tx, _) = {
-    broadcast::channel::<Vec<tokio_tungstenite::tungstenite::Message>>(16)
+  // Server message broadcast queue (consumer -> server -> client(s)).
+  // Both the consumer thread(s) and the tokio thread(s) will have their own copies. The consumer thread uses its copy to send() messages. The tokio thread uses its copy to create per-connection receivers.
+  let ser_msg_queue = BroadcastQueue::new(config.broadcast_queue);
+
+  // Client message queue.
+  let cli_msg_queue = BoundedQueue::<(ClientId, tokio_tungstenite::tungstenite::Message)>::new(config.client_message_queue);
+
+  // Client lag event queue. These are only informational, so the oldest are dropped rather than ever holding up a connection.
+  let cli_lag_queue = BoundedQueue::<LagEvent>::new(QueueConfig::new(DEFAULT_QUEUE_CAPACITY, OverflowPolicy::DropOldest));
+
+  // Client disconnect event queue.
+  let cli_disconn_queue = BoundedQueue::<DisconnectEvent>::new(config.disconnect_queue);
+
+  // Registry of open connections, shared by the consumer (to address individual clients) and tokio (to add and remove them).
+  let clients = ClientRegistry::new();
+
+  // Endpoints, each with its own client message queue and broadcast. The default endpoint uses the queues above, and resyncs with the consumer's lifetime-long snapshot.
+  let default_endpoint = Endpoint {
+    cli_msg_queue: cli_msg_queue.clone(),
+    ser_msg_queue: ser_msg_queue.clone(),
+    snapshot: cs.resync_snapshot.clone(),
   };
-  // Both the consumer thread(s) and the tokio thread(s) will have their own copies of the transmitter. The consumer thread uses its copy to send() messages. The tokio thread uses its copy to create per-connection receivers.
-  let ser_msg_consumer_tx = ser_msg_tokio_tx.clone();
+  let endpoints = EndpointRegistry::new(default_endpoint, &config.endpoints, config.client_message_queue);
+
+  // Registry of topics, each with its own broadcast fan-out configured like the server broadcast. Shared by the consumer (to publish) and tokio (to subscribe clients that ask).
+  let topics = TopicRegistry::new(ser_msg_queue.clone());
 
-  // Client message channel.
-  let (cli_msg_store_tokio_tx, cli_msg_store_consumer_rx) = {
-    mpsc::channel::<tokio_tungstenite::tungstenite::Message>(16)
+  // Server-wide counters, kept by the tokio tasks, and everything else the metrics and stats are read from.
+  let metrics = MetricsSource {
+    counters: Counters::new(),
+    clients: clients.clone(),
+    endpoints: endpoints.clone(),
+    topics: topics.clone(),
+    cli_conn_queue: cli_conn_queue.clone(),
+    cli_lag_queue: cli_lag_queue.clone(),
+    cli_disconn_queue: cli_disconn_queue.clone(),
   };
 
   // Shutdown channel.
@@ -32,29 +294,34 @@ pub fn start(port: u32) -> Result<(), ()> {
     watch::channel::<bool>(false)
   };
 
-  // Set the consumer thread statics with all its relevant comms channels.
-  use consumer_state as cs;
-  cs::set_value(&cs::CS_SER_ALIVE_RX, ser_alive_consumer_rx)
-    .expect("Failed to set consumer state channel!");
-  cs::set_value(&cs::CS_CLI_CONN_RX, cli_conn_consumer_rx)
-    .expect("Failed to set consumer state channel!");
-  cs::set_value(&cs::CS_SER_MSG_TX, ser_msg_consumer_tx)
-    .expect("Failed to set consumer state channel!");
-  cs::set_value(&cs::CS_CLI_MSG_RX, cli_msg_store_consumer_rx)
-    .expect("Failed to set consumer state channel!");
-  cs::set_value(&cs::CS_SER_REQ_SHUTDOWN_TX, ser_req_shutdown_consumer_tx)
-    .expect("Failed to set consumer state channel!");
+  // Set the consumer-side state with all its relevant comms channels.
+  cs.set_value(&cs.ser_alive_rx, ser_alive_consumer_rx)?;
+  cs.set_value(&cs.cli_conn_queue, cli_conn_queue.clone())?;
+  cs.set_value(&cs.ser_msg_queue, ser_msg_queue.clone())?;
+  cs.set_value(&cs.cli_msg_queue, cli_msg_queue.clone())?;
+  cs.set_value(&cs.cli_lag_queue, cli_lag_queue.clone())?;
+  cs.set_value(&cs.cli_disconn_queue, cli_disconn_queue.clone())?;
+  cs.set_value(&cs.ser_req_shutdown_tx, ser_req_shutdown_consumer_tx)?;
+  cs.set_value(&cs.clients, clients.clone())?;
+  cs.set_value(&cs.topics, topics.clone())?;
+  cs.set_value(&cs.endpoints, endpoints.clone())?;
+  cs.set_value(&cs.metrics, metrics.clone())?;
 
   // Launch the tokio thread, passing ownership of all the tokio-side channels.
-  // Launch the tokio thread.
-  let _thread_handle = thread::spawn(move || tokio_server::main(
-    port,
-    ser_thread_alive_tokio_tx,
-    cli_conn_tokio_tx,
-    ser_msg_tokio_tx,
-    cli_msg_store_tokio_tx,
-    ser_req_shutdown_tokio_rx
-  ));
-
-  Ok(())
+  let channels = TokioChannels {
+    ser_thread_alive_tx: ser_thread_alive_tokio_tx,
+    cli_conn_queue,
+    cli_lag_queue,
+    cli_disconn_queue,
+    clients,
+    topics,
+    endpoints,
+    metrics,
+    ser_req_shutdown_rx: ser_req_shutdown_tokio_rx,
+    errors: cs.errors.clone(),
+    event_signal: cs.event_signal.clone(),
+  };
+  let thread_handle = thread::spawn(move || tokio_server::main(config, listeners, metrics_listener, tls_acceptor, channels));
+
+  Ok(thread_handle)
 }
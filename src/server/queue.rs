@@ -0,0 +1,281 @@
+// queue.rs
+//
+// The bounded queues between the tokio server thread and the consumer, and what happens when one of them fills up.
+//
+// Events and client messages travel tokio -> consumer through BoundedQueues, which (unlike tokio's mpsc) let the producer drop the oldest item to make room. Server messages travel consumer -> tokio through a BroadcastQueue, a broadcast channel whose overflow policy is applied partly by the consumer (when sending) and partly by each connection (when it falls behind).
+
+use std::{collections::VecDeque, sync::{Arc, Mutex, MutexGuard, RwLock, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}};
+use tokio::sync::{Notify, broadcast};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::events::EventSignal;
+
+/// Default capacity of each queue, in events, messages or broadcast batches.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 16;
+
+/// How often a consumer blocked on a full broadcast queue re-checks it even without being woken, e.g. to notice that the server stopped.
+const BROADCAST_BLOCK_RECHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What to do with an item that arrives when its queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+  /// Wait for room. On the tokio side this holds up whoever produced the item (a connection's reads, or the report of a new connection); for the broadcast queue, the consumer's send blocks.
+  Block,
+  /// Drop the oldest queued item to make room for the new one.
+  DropOldest,
+  /// Drop the new item.
+  DropNewest,
+  /// Drop the new item and disconnect the client responsible for it.
+  Disconnect,
+}
+
+impl OverflowPolicy {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      OverflowPolicy::Block      => "block",
+      OverflowPolicy::DropOldest => "drop_oldest",
+      OverflowPolicy::DropNewest => "drop_newest",
+      OverflowPolicy::Disconnect => "disconnect",
+    }
+  }
+
+  /// Parses a policy from its as_str() name.
+  pub fn parse(name: &str) -> Result<Self, String> {
+    match name {
+      "block"       => Ok(OverflowPolicy::Block),
+      "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+      "drop_newest" => Ok(OverflowPolicy::DropNewest),
+      "disconnect"  => Ok(OverflowPolicy::Disconnect),
+      _ => Err(format!("Unknown overflow policy {:?}; expected \"block\", \"drop_oldest\", \"drop_newest\" or \"disconnect\".", name)),
+    }
+  }
+}
+
+/// Capacity and overflow policy for one queue.
+#[derive(Clone, Copy, Debug)]
+pub struct QueueConfig {
+  pub capacity: usize,
+  pub overflow: OverflowPolicy,
+}
+
+impl QueueConfig {
+  pub fn new(capacity: usize, overflow: OverflowPolicy) -> Self {
+    QueueConfig { capacity, overflow }
+  }
+}
+
+/// What to do with a client that falls a full broadcast queue behind, when the broadcast queue's overflow policy lets it skip the batches it missed (DropOldest). Every time, the client's skipped batches are counted and reported to the consumer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LagPolicy {
+  /// Carry on from the next batch.
+  Continue,
+  /// Send the client the consumer's resync snapshot (if one has been set) before carrying on, so it can rebuild whatever state the skipped batches held.
+  Resync,
+  /// Disconnect the client once it has skipped more than `threshold` batches in total.
+  Disconnect { threshold: u64 },
+}
+
+impl LagPolicy {
+  /// Parses a policy from its name: "continue", "resync" or "disconnect". The threshold only applies to "disconnect".
+  pub fn parse(name: &str, threshold: u64) -> Result<Self, String> {
+    match name {
+      "continue"   => Ok(LagPolicy::Continue),
+      "resync"     => Ok(LagPolicy::Resync),
+      "disconnect" => Ok(LagPolicy::Disconnect { threshold }),
+      _ => Err(format!("Unknown lag policy {:?}; expected \"continue\", \"resync\" or \"disconnect\".", name)),
+    }
+  }
+}
+
+/// The messages sent to a client that fell behind under LagPolicy::Resync, in place of the batches it skipped. Set by the consumer at any time and shared with every connection; cheaply cloneable.
+#[derive(Clone, Default)]
+pub struct ResyncSnapshot {
+  messages: Arc<RwLock<Option<Vec<Message>>>>,
+}
+
+impl ResyncSnapshot {
+  pub fn new() -> Self { Self::default() }
+
+  /// Replaces the snapshot, or clears it with None.
+  pub fn set(&self, messages: Option<Vec<Message>>) {
+    let mut current = self.messages.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    *current = messages;
+  }
+
+  pub fn get(&self) -> Option<Vec<Message>> {
+    self.messages.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+  }
+}
+
+/// What became of an item pushed to a full queue (or of a broadcast).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pushed {
+  /// The item was queued without dropping anything.
+  Queued,
+  /// The item was queued after dropping the oldest item.
+  DroppedOldest,
+  /// The item was dropped.
+  DroppedNewest,
+  /// The item was dropped, and the client responsible should be disconnected.
+  Rejected,
+}
+
+/// A bounded FIFO queue from the tokio side to the consumer. Cheaply cloneable; clones share the same queue.
+pub struct BoundedQueue<T> {
+  inner: Arc<BoundedQueueInner<T>>,
+}
+
+struct BoundedQueueInner<T> {
+  items: Mutex<VecDeque<T>>,
+  config: QueueConfig,
+  /// Notified whenever the consumer takes items, waking producers blocked on a full queue.
+  space: Notify,
+  dropped: AtomicU64,
+}
+
+impl<T> Clone for BoundedQueue<T> {
+  fn clone(&self) -> Self { BoundedQueue { inner: self.inner.clone() } }
+}
+
+impl<T> BoundedQueue<T> {
+  pub fn new(config: QueueConfig) -> Self {
+    BoundedQueue { inner: Arc::new(BoundedQueueInner {
+      items: Mutex::new(VecDeque::with_capacity(config.capacity)),
+      config,
+      space: Notify::new(),
+      dropped: AtomicU64::new(0),
+    })}
+  }
+
+  /// The number of items dropped so far, by any policy.
+  pub fn dropped(&self) -> u64 { self.inner.dropped.load(Ordering::Relaxed) }
+
+  /// The number of items waiting to be taken.
+  pub fn depth(&self) -> usize { self.lock().len() }
+
+  /// Queues an item, applying the overflow policy if the queue is full. Only waits under the Block policy.
+  pub async fn push(&self, item: T) -> Pushed {
+    loop {
+      // Created before checking for room, so room made in between still wakes us.
+      let space = self.inner.space.notified();
+      {
+        let mut items = self.lock();
+        if items.len() < self.inner.config.capacity {
+          items.push_back(item);
+          return Pushed::Queued;
+        }
+        match self.inner.config.overflow {
+          OverflowPolicy::Block => {}
+          OverflowPolicy::DropOldest => {
+            items.pop_front();
+            items.push_back(item);
+            self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+            return Pushed::DroppedOldest;
+          }
+          OverflowPolicy::DropNewest => {
+            self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+            return Pushed::DroppedNewest;
+          }
+          OverflowPolicy::Disconnect => {
+            self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+            return Pushed::Rejected;
+          }
+        }
+      }
+      space.await;
+    }
+  }
+
+  /// Takes the oldest item, if there is one.
+  pub fn pop(&self) -> Option<T> {
+    let item = self.lock().pop_front();
+    if item.is_some() { self.inner.space.notify_one(); }
+    item
+  }
+
+  /// Takes every queued item, oldest first.
+  pub fn drain(&self) -> Vec<T> {
+    let items: Vec<T> = self.lock().drain(..).collect();
+    // One wake per freed slot, so every blocked producer gets a turn.
+    for _ in 0..items.len() { self.inner.space.notify_one(); }
+    items
+  }
+
+  fn lock(&self) -> MutexGuard<'_, VecDeque<T>> {
+    self.inner.items.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+}
+
+/// The server message broadcast channel (consumer -> every connection), along with its overflow policy. Cheaply cloneable; the consumer sends through its clone, and the tokio side subscribes each new connection through its own.
+///
+/// The channel is full once some connection is a full queue behind. Block and DropNewest are applied by the consumer when sending. Under DropOldest the lagging connections skip the batches they missed, and under Disconnect they're disconnected; either way each connection counts what it skipped.
+#[derive(Clone)]
+pub struct BroadcastQueue {
+  tx: broadcast::Sender<Vec<Message>>,
+  config: QueueConfig,
+  /// Notified by connections as they take batches, under the Block policy.
+  space: EventSignal,
+  dropped: Arc<AtomicU64>,
+}
+
+impl BroadcastQueue {
+  /// The capacity is rounded up to a power of two, as the underlying broadcast channel does.
+  pub fn new(config: QueueConfig) -> Self {
+    let config = QueueConfig { capacity: config.capacity.next_power_of_two(), ..config };
+    let (tx, _) = broadcast::channel(config.capacity);
+    BroadcastQueue { tx, config, space: EventSignal::new(), dropped: Arc::new(AtomicU64::new(0)) }
+  }
+
+  pub fn config(&self) -> QueueConfig { self.config }
+
+  /// A new, separate broadcast channel with the same config, which shares this one's drop count and wakes the same blocked senders.
+  pub fn sibling(&self) -> Self {
+    let (tx, _) = broadcast::channel(self.config.capacity);
+    BroadcastQueue { tx, config: self.config, space: self.space.clone(), dropped: self.dropped.clone() }
+  }
+
+  /// The number of batches the furthest-behind connection has yet to take.
+  pub fn depth(&self) -> usize { self.tx.len() }
+
+  /// The number of connections currently receiving from this channel.
+  pub fn receiver_count(&self) -> usize { self.tx.receiver_count() }
+
+  /// The number of batches dropped so far: batches refused by DropNewest, plus every batch a lagging connection skipped.
+  pub fn dropped(&self) -> u64 { self.dropped.load(Ordering::Relaxed) }
+
+  /// A receiver for a new connection, starting from the next batch sent.
+  pub fn subscribe(&self) -> broadcast::Receiver<Vec<Message>> { self.tx.subscribe() }
+
+  /// Sends a batch to every connection. Under the Block policy, waits until no connection is a full queue behind, giving up and sending anyway once `keep_waiting` returns false (e.g. because the server stopped).
+  ///
+  /// Sending with no connections isn't an error; the batch simply goes nowhere.
+  pub fn send(&self, msgs: Vec<Message>, keep_waiting: impl Fn() -> bool) -> Pushed {
+    match self.config.overflow {
+      OverflowPolicy::Block => loop {
+        // Read before checking, so a connection catching up in between still wakes us.
+        let seen = self.space.count();
+        if self.tx.len() < self.config.capacity || !keep_waiting() { break; }
+        self.space.wait_past(seen, Instant::now() + BROADCAST_BLOCK_RECHECK_INTERVAL);
+      },
+      OverflowPolicy::DropNewest => {
+        if self.tx.len() >= self.config.capacity {
+          self.dropped.fetch_add(1, Ordering::Relaxed);
+          return Pushed::DroppedNewest;
+        }
+      }
+      OverflowPolicy::DropOldest | OverflowPolicy::Disconnect => {}
+    }
+    let _ = self.tx.send(msgs);
+    Pushed::Queued
+  }
+
+  /// Called by a connection after taking a batch, or once it's done taking them, so a blocked sender can re-check for room.
+  pub fn taken(&self) {
+    if self.config.overflow == OverflowPolicy::Block { self.space.notify(); }
+  }
+
+  /// Called by a connection that fell behind and skipped `skipped` batches.
+  pub fn lagged(&self, skipped: u64) {
+    self.dropped.fetch_add(skipped, Ordering::Relaxed);
+  }
+}
@@ -0,0 +1,88 @@
+// tls.rs
+//
+// Optional TLS (wss://) support. With the "tls" feature, connections are wrapped in a rustls server session before the websocket handshake. Without it, TlsAcceptor is uninhabited and asking for TLS is an error at start, so the rest of the server doesn't need to care which build it's in.
+
+use std::path::PathBuf;
+use tokio::net::TcpStream;
+
+/// Paths to the PEM files a TLS server is started with.
+#[derive(Clone, Debug)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub struct TlsConfig {
+  /// The certificate chain, leaf certificate first.
+  pub cert_path: PathBuf,
+  /// The private key for the leaf certificate (PKCS#8, RSA or SEC1 EC).
+  pub key_path: PathBuf,
+}
+
+#[cfg(feature = "tls")]
+pub use enabled::*;
+#[cfg(not(feature = "tls"))]
+pub use disabled::*;
+
+#[cfg(feature = "tls")]
+mod enabled {
+  use std::{fs::File, io::{self, BufReader}, sync::Arc};
+  use tokio_rustls::rustls;
+
+  use super::{TcpStream, TlsConfig};
+
+  pub type TlsAcceptor = tokio_rustls::TlsAcceptor;
+  pub type TlsStream = tokio_rustls::server::TlsStream<TcpStream>;
+
+  /// Reads the certificate chain and private key and builds an acceptor from them.
+  pub fn load_acceptor(config: &TlsConfig) -> Result<TlsAcceptor, String> {
+    let open = |path: &std::path::Path| {
+      File::open(path).map(BufReader::new).map_err(|err| format!("Failed to open {}: {}", path.display(), err))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(&config.cert_path)?)
+      .map_err(|err| format!("Failed to read certificates from {}: {}", config.cert_path.display(), err))?;
+    if certs.is_empty() {
+      return Err(format!("No certificates found in {}.", config.cert_path.display()));
+    }
+    let certs = certs.into_iter().map(rustls::Certificate).collect();
+
+    let key = rustls_pemfile::read_all(&mut open(&config.key_path)?)
+      .map_err(|err| format!("Failed to read private key from {}: {}", config.key_path.display(), err))?
+      .into_iter()
+      .find_map(|item| match item {
+        rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => Some(key),
+        _ => None,
+      })
+      .ok_or_else(|| format!("No private key found in {}.", config.key_path.display()))?;
+
+    let server_config = rustls::ServerConfig::builder()
+      .with_safe_defaults()
+      .with_no_client_auth()
+      .with_single_cert(certs, rustls::PrivateKey(key))
+      .map_err(|err| format!("Invalid TLS certificate or key: {}", err))?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+  }
+
+  /// Runs the TLS handshake on a freshly-accepted connection.
+  pub async fn accept(acceptor: &TlsAcceptor, stream: TcpStream) -> io::Result<TlsStream> {
+    acceptor.accept(stream).await
+  }
+}
+
+#[cfg(not(feature = "tls"))]
+mod disabled {
+  use std::io;
+
+  use super::{TcpStream, TlsConfig};
+
+  /// Can't be constructed: this build has no TLS support.
+  #[derive(Clone)]
+  pub enum TlsAcceptor {}
+  pub type TlsStream = TcpStream;
+
+  pub fn load_acceptor(_config: &TlsConfig) -> Result<TlsAcceptor, String> {
+    Err("quicksocket was built without TLS support (the \"tls\" cargo feature).".to_string())
+  }
+
+  pub async fn accept(acceptor: &TlsAcceptor, _stream: TcpStream) -> io::Result<TlsStream> {
+    match *acceptor {}
+  }
+}
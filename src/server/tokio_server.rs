@@ -1,104 +1,454 @@
-use std::{net::SocketAddr};
+use std::{borrow::Cow, collections::{BTreeSet, HashMap}, io, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::Duration};
 use futures_util::{SinkExt, StreamExt, stream::{SplitSink, SplitStream}};
-use tokio::{net::{TcpListener, TcpStream}, sync::{broadcast, mpsc, watch}};
-use tokio_tungstenite::{WebSocketStream, tungstenite::Message};
+use log::{debug, error, info, trace, warn};
+use std::net::SocketAddr;
+use tokio::{io::{AsyncRead, AsyncWrite}, net::{TcpListener, TcpStream}, sync::{broadcast, mpsc, watch}};
+use tokio_stream::{StreamMap, wrappers::{BroadcastStream, errors::BroadcastStreamRecvError}};
+use tokio_tungstenite::{WebSocketStream, tungstenite::{self, Message, handshake::server::{ErrorResponse, Request, Response}, http::{HeaderValue, StatusCode, header::{SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_PROTOCOL}}, protocol::{CloseFrame, frame::coding::CloseCode}}};
+
+use super::{ClientId, KeepaliveConfig, ServerConfig, compression::{CompressionConfig, DeflateStream}, handshake::{self, HandshakeHook, HandshakeInfo}, http::{self, HttpRoutes, Rewind}, metrics::{ClientStats, Counters, HandshakeFailure, MetricsSource}, tls::{self, TlsAcceptor}, clients::{CLIENT_OUTBOUND_CAPACITY, ClientHandle, ClientRegistry, ClientSendError}, endpoints::{Endpoint, EndpointRegistry}, errors::{ErrorKind, ErrorQueue}, events::{ConnectEvent, DisconnectCause, DisconnectEvent, DisconnectKind, EventSignal, LagAction, LagEvent}, queue::{BoundedQueue, BroadcastQueue, LagPolicy, OverflowPolicy, Pushed, ResyncSnapshot}, topics::{SUBSCRIBE_PREFIX, Topic, TopicCommand, TopicRegistry, UNSUBSCRIBE_PREFIX}};
+
+/// How long a connection waits for the client to answer a server-initiated close frame before giving up on it.
+const CLOSE_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a client has, from when its connection is accepted, to complete the TLS handshake (for wss://) and send its request, and for the websocket handshake to complete. Otherwise a client that connects and goes quiet holds its connection (and its task) open indefinitely.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a shutting-down server waits for its connections to finish their close handshakes and report their disconnects, before dropping whatever is left.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The close reason sent to every client when the server shuts down (with close code 1001).
+const SHUTDOWN_CLOSE_REASON: &str = "Server shutting down.";
+
+/// The tokio-side ends of all of a server's channels, handed over to the tokio thread by server::start().
+pub struct TokioChannels {
+  pub ser_thread_alive_tx: watch::Sender::<bool>,
+  pub cli_conn_queue: BoundedQueue<ConnectEvent>,
+  pub cli_lag_queue: BoundedQueue<LagEvent>,
+  pub cli_disconn_queue: BoundedQueue<DisconnectEvent>,
+  pub clients: ClientRegistry,
+  pub topics: TopicRegistry,
+  pub endpoints: EndpointRegistry,
+  pub metrics: MetricsSource,
+  pub ser_req_shutdown_rx: watch::Receiver::<bool>,
+  pub errors: ErrorQueue,
+  pub event_signal: EventSignal,
+}
+
+/// Channel ends and state shared by all of a server's listeners and connections; each task gets its own clone.
+#[derive(Clone)]
+struct ServerContext {
+  cli_conn_queue: BoundedQueue<ConnectEvent>,
+  cli_lag_queue: BoundedQueue<LagEvent>,
+  cli_disconn_queue: BoundedQueue<DisconnectEvent>,
+  clients: ClientRegistry,
+  topics: TopicRegistry,
+  /// Where each connection's messages go and its broadcast comes from, by request path.
+  endpoints: EndpointRegistry,
+  /// Whether clients can subscribe themselves to topics with control messages.
+  topic_control: bool,
+  /// The origins browser clients may connect from; empty to allow any.
+  allowed_origins: Arc<Vec<String>>,
+  /// The subprotocols the server speaks, in priority order.
+  subprotocols: Arc<Vec<String>>,
+  /// Decides whether to accept each routed upgrade request.
+  handshake_hook: Option<Arc<dyn HandshakeHook>>,
+  /// How to compress messages with clients that offer permessage-deflate, if at all.
+  compression: Option<CompressionConfig>,
+  /// Set when the main listeners answer plain HTTP requests (static files or metrics) as well as websocket upgrades.
+  http: Option<HttpRoutes>,
+  /// Server-wide counters, reported as metrics.
+  counters: Counters,
+  ser_req_shutdown_rx: watch::Receiver::<bool>,
+  /// The consumer's error queue, for errors the tokio side can't otherwise report.
+  errors: ErrorQueue,
+  /// Notified after every event sent to the consumer, waking consumer threads blocked in wait_for_events.
+  event_signal: EventSignal,
+  lag_policy: LagPolicy,
+  /// Set when clients are pinged periodically and disconnected if they don't answer.
+  keepalive: Option<KeepaliveConfig>,
+  /// The most recently assigned client ID, shared so that IDs stay unique across listeners.
+  last_client_id: Arc<AtomicU64>,
+  /// Set when serving wss://; every accepted connection completes a TLS handshake before the websocket handshake.
+  tls_acceptor: Option<TlsAcceptor>,
+  /// Never sent on; held by every task with a copy of the context, so the server can tell once they've all finished.
+  _tasks: mpsc::Sender<()>,
+}
+
+/// The per-connection receivers a connection's tasks read from, and the endpoint the connection was routed to.
+struct ClientChannels {
+  endpoint: Endpoint,
+  server_msg_rx: broadcast::Receiver<Vec<tokio_tungstenite::tungstenite::Message>>,
+  client_outbound_rx: mpsc::Receiver<Vec<tokio_tungstenite::tungstenite::Message>>,
+  client_disconnect_rx: watch::Receiver<Option<CloseFrame<'static>>>,
+  client_topics_rx: mpsc::UnboundedReceiver<TopicCommand>,
+  /// Keepalive ping payloads to send, as timed by the receiver task.
+  ping_rx: mpsc::Receiver<Vec<u8>>,
+  /// The payload of the last keepalive ping written to the socket, and when it was written, for the receiver task to time its pong from.
+  ping_written_tx: watch::Sender<Option<(Vec<u8>, tokio::time::Instant)>>,
+  stats: ClientStats,
+}
 
 /// Main thread loop for running the websocket server.
 ///
 /// This function launches a tokio runtime to handle most server functions. The function will return after the tokio runtime exits.
 pub fn main(
-  port: u32,
-  ser_thread_alive_tx: watch::Sender::<bool>,
-  cli_conn_tokio_tx: mpsc::Sender<String>,
-  ser_msg_tx: broadcast::Sender::<Vec<tokio_tungstenite::tungstenite::Message>>,
-  cli_msg_tx: mpsc::Sender::<tokio_tungstenite::tungstenite::Message>,
-  mut ser_req_shutdown_rx: watch::Receiver::<bool>
+  config: ServerConfig,
+  listeners: Vec<std::net::TcpListener>,
+  metrics_listener: Option<std::net::TcpListener>,
+  tls_acceptor: Option<TlsAcceptor>,
+  channels: TokioChannels
 ) -> Result<String, String> {
+  let TokioChannels {
+    ser_thread_alive_tx, cli_conn_queue, cli_lag_queue, cli_disconn_queue, clients, topics, endpoints, metrics, mut ser_req_shutdown_rx, errors, event_signal
+  } = channels;
+  let (tasks_tx, tasks_rx) = mpsc::channel::<()>(1);
+  let static_dir = config.static_dir.map(Arc::new);
+  let http = match (static_dir, config.metrics) {
+    (None, false) => None,
+    (static_dir, serve_metrics) => Some(HttpRoutes { static_dir, metrics: if serve_metrics { Some(metrics.clone()) } else { None } }),
+  };
+  let ctx = ServerContext {
+    cli_conn_queue, cli_lag_queue, cli_disconn_queue, clients, topics, endpoints, topic_control: config.topic_control, allowed_origins: Arc::new(config.allowed_origins), subprotocols: Arc::new(config.subprotocols), handshake_hook: config.handshake_hook, compression: config.compression, http, counters: metrics.counters.clone(), ser_req_shutdown_rx: ser_req_shutdown_rx.clone(), errors, event_signal: event_signal.clone(),
+    lag_policy: config.lag_policy,
+    keepalive: config.keepalive,
+    last_client_id: Arc::new(AtomicU64::new(0)),
+    tls_acceptor,
+    _tasks: tasks_tx,
+  };
+
   // Start the tokio runtime for the server and launch the top-level server task.
-  println!("Server launching runtime.");
+  let mut tasks_rx = tasks_rx;
+  debug!("Server launching runtime.");
   let tokio_runtime = tokio::runtime::Runtime::new().unwrap();
   tokio_runtime.block_on(async {
 
     // Top-level tokio task
     // --------------------
     //
-    let res = ser_thread_alive_tx.send(true);
-    if res.is_err() { println!("Failed to set server alive."); return; }
-
-    // Bind to websocket on localhost port 59994.
-    let addr = format!("127.0.0.1:{}", port);
-    println!("[quicksocket] Attempting to bind TcpListener at: {}", addr);
-    let listener = TcpListener::bind(&addr).await;
-    if listener.is_err() { println!("Failed to bind TcpListener. It's possible that port {} is already in use.", port); return; }
-    let listener = listener.unwrap();
-    //.expect("Failed to bind to address")
-    println!("Listening on: {}", addr);
-
-    // Listen for connections until shutdown.
-    // -----------------------------------
-    //
-    // Loop, responding to whichever future finishes first. (We break on a shutdown signal.)
-    loop {
-      let accept_conn = listener.accept();
-      tokio::pin!(accept_conn);
-
-      tokio::select! {
-        // Valid connection. Launch task to handle the connection for its lifetime.
-        Ok((stream, _)) = &mut accept_conn => {
-          let peer = stream.peer_addr().expect("Connected streams should have a peer address");
-          println!("[tokio_server.rs] Peer address: {}", peer);
-          let new_client_evt = peer.to_string();
-          cli_conn_tokio_tx.send(new_client_evt).await.unwrap_or_else(|_| println!("[tokio_server.rs] Failed to report new client event to consumer."));
-
-          // Each connection receives a reciever for messages to forward from the server, and a transmitter to forward client messages back to the server.
-          let (ser_msg_broadcast_rx, cli_msg_store_tx) = (
-            ser_msg_tx.subscribe(), cli_msg_tx.clone()
-          );
-
-          // Spawn a connection handler task, which will live for the duration of the connection.
-          tokio::spawn(handle_connection(peer, stream, ser_msg_broadcast_rx, cli_msg_store_tx, ser_req_shutdown_rx.clone()));
-        }
+    // The listeners were already bound by server::start(), so binding errors are reported to the consumer before we get here. Hand each one to its own accept loop; they all feed the same channels.
+    for listener in listeners {
+      let listener = TcpListener::from_std(listener);
+      if let Err(err) = listener {
+        error!("Failed to register TcpListener with the tokio runtime: {}", err);
+        ctx.errors.record(ErrorKind::Bind, format!("Failed to register TcpListener with the tokio runtime: {}", err), None);
+        return;
+      }
+      tokio::spawn(accept_connections(listener.unwrap(), ctx.clone()));
+    }
 
-        // Receive an exit signal and shutdown.
-        _ = ser_req_shutdown_rx.changed() => {
-          if *ser_req_shutdown_rx.borrow() {
-            println!("[tokio_server.rs] Received shutdown signal.");
-            break;
-          }
+    // Metrics on a listener of their own, if asked for.
+    if let Some(listener) = metrics_listener {
+      let routes = HttpRoutes { static_dir: None, metrics: Some(metrics) };
+      let mut shutdown_rx = ctx.ser_req_shutdown_rx.clone();
+      let shutdown = async move {
+        while shutdown_rx.changed().await.is_ok() && !*shutdown_rx.borrow() {}
+      };
+      let errors = ctx.errors.clone();
+      tokio::spawn(async move {
+        let local_addr = listener.local_addr().map(|addr| addr.to_string()).unwrap_or_else(|_| "?".to_string());
+        info!("Serving metrics on: {}", local_addr);
+        if let Err(err) = http::serve_listener(listener, routes, shutdown).await {
+          error!("Metrics listener {} failed: {}", local_addr, err);
+          errors.record(ErrorKind::Bind, format!("Metrics listener {} failed: {}", local_addr, err), None);
         }
-      } // tokio::select!
-    } // loop
+      });
+    }
+
+    let res = ser_thread_alive_tx.send(true);
+    if res.is_err() { error!("Failed to set server alive."); return; }
+
+    // Serve until shutdown.
+    loop {
+      if ser_req_shutdown_rx.changed().await.is_err() || *ser_req_shutdown_rx.borrow() {
+        debug!("Received shutdown signal.");
+        break;
+      }
+    }
 
     // Shut down.
-    println!("[tokio_server.rs] Server writing alive = false.");
-    ser_thread_alive_tx.send(false).unwrap_or_else(|_| println!("[tokio_server.rs] Failed to set server thread alive to false!"));
+    debug!("Server writing alive = false.");
+    ser_thread_alive_tx.send(false).unwrap_or_else(|_| error!("Failed to set server thread alive to false!"));
+
+    // Every connection was sent the shutdown signal too. Wait for them to finish their close handshakes and report their disconnects, since dropping the runtime drops any task still running.
+    drop(ctx);
+    if tokio::time::timeout(SHUTDOWN_DRAIN_TIMEOUT, tasks_rx.recv()).await.is_err() {
+      warn!("Some connections didn't close within {:?} of the shutdown; dropping them.", SHUTDOWN_DRAIN_TIMEOUT);
+    }
   });
   
-  println!("[tokio_server.rs] Server tokio thread exiting.");
+  info!("Server tokio thread exiting.");
+  // Dropping the alive transmitter marks the thread as exited; then wake any consumer still waiting for events from this server.
+  drop(ser_thread_alive_tx);
+  event_signal.notify();
   Ok("Server shut-down successfully.".to_string())
 }
 
+/// Accept loop for a single listener. Launches a connection handler task per accepted connection until shutdown.
+async fn accept_connections(listener: TcpListener, ctx: ServerContext) {
+  let local_addr = listener.local_addr().map(|addr| addr.to_string()).unwrap_or_else(|_| "?".to_string());
+  info!("Listening on: {}", local_addr);
+  let mut ser_req_shutdown_rx = ctx.ser_req_shutdown_rx.clone();
+
+  // Listen for connections until shutdown.
+  // -----------------------------------
+  //
+  // Loop, responding to whichever future finishes first. (We break on a shutdown signal.)
+  loop {
+    let accept_conn = listener.accept();
+    tokio::pin!(accept_conn);
+
+    tokio::select! {
+      // Valid connection. Launch task to handle the connection for its lifetime.
+      Ok((stream, peer)) = &mut accept_conn => {
+        // Every accepted connection is assigned the next client ID, which tags its connection event and all of its messages (and any errors along the way).
+        let client_id = ctx.last_client_id.fetch_add(1, Ordering::Relaxed) + 1;
+        info!("[client {} {}] Accepted connection (via {}).", client_id, peer, local_addr);
+
+        // Spawn a connection handler task, which will live for the duration of the connection.
+        tokio::spawn(handle_connection(client_id, peer, stream, ctx.clone()));
+      }
+
+      // Receive an exit signal and shutdown.
+      _ = ser_req_shutdown_rx.changed() => {
+        if *ser_req_shutdown_rx.borrow() {
+          debug!("Listener {} received shutdown signal.", local_addr);
+          break;
+        }
+      }
+    } // tokio::select!
+  } // loop
+}
+
 async fn handle_connection(
-  _peer: SocketAddr,
+  client_id: ClientId,
+  addr: SocketAddr,
   stream: TcpStream,
-  server_msg_rx: broadcast::Receiver<Vec<tokio_tungstenite::tungstenite::Message>>,
-  client_msg_tx: mpsc::Sender<tokio_tungstenite::tungstenite::Message>,
-  ser_req_shutdown_rx: watch::Receiver::<bool>
+  ctx: ServerContext
 ) {
-  let addr = stream.peer_addr();
-  if addr.is_err() {
-    println!("[handle_connection] Error: Connected streams should have a peer address.");
-    return;
+  let handshake_deadline = tokio::time::Instant::now() + HANDSHAKE_TIMEOUT;
+
+  // For wss://, run the TLS handshake first; everything after that is the same for either kind of stream.
+  match &ctx.tls_acceptor {
+    None => serve_stream(client_id, addr, stream, handshake_deadline, ctx).await,
+    Some(tls_acceptor) => {
+      let tls_stream = tokio::time::timeout_at(handshake_deadline, tls::accept(tls_acceptor, stream)).await
+        .unwrap_or_else(|_| Err(handshake_timed_out()));
+      if let Err(err) = tls_stream {
+        warn!("[client {} {}] Error during the TLS handshake: {}", client_id, addr, err);
+        ctx.errors.record(ErrorKind::Tls, format!("TLS handshake with {} failed: {}", addr, err), Some(client_id));
+        ctx.counters.handshake_failed(HandshakeFailure::Tls);
+        return;
+      }
+      serve_stream(client_id, addr, tls_stream.unwrap(), handshake_deadline, ctx).await
+    }
   }
-  let addr = addr.unwrap();
+}
 
-  let ws_stream = tokio_tungstenite::accept_async(stream)
-    .await
-    .expect("Error during the websocket handshake occurred");
+/// The error for a client that didn't get through its handshake within HANDSHAKE_TIMEOUT.
+fn handshake_timed_out() -> io::Error {
+  io::Error::new(io::ErrorKind::TimedOut, format!("timed out after {:?}", HANDSHAKE_TIMEOUT))
+}
 
-  println!("[handle_connection] New websocket connection: {}", addr);
+/// Serves an accepted (and, for wss://, already decrypted) stream as a websocket, or, if the server answers plain HTTP requests and the request isn't a websocket upgrade, as plain HTTP.
+async fn serve_stream<S>(
+  client_id: ClientId,
+  addr: SocketAddr,
+  mut stream: S,
+  handshake_deadline: tokio::time::Instant,
+  ctx: ServerContext
+) where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+  let routes = match &ctx.http {
+    Some(routes) => routes.clone(),
+    None => return serve_websocket(client_id, addr, stream, handshake_deadline, ctx).await,
+  };
+
+  // Read the request head to see what's being asked for, then replay it to whichever side serves it.
+  let head = tokio::time::timeout_at(handshake_deadline, http::read_request_head(&mut stream)).await
+    .unwrap_or_else(|_| Err(handshake_timed_out()));
+  let head = match head {
+    Ok(head) => head,
+    Err(err) => {
+      warn!("[client {} {}] Error reading the request: {}", client_id, addr, err);
+      ctx.errors.record(ErrorKind::Handshake, format!("Failed to read a request from {}: {}", addr, err), Some(client_id));
+      ctx.counters.handshake_failed(HandshakeFailure::Error);
+      return;
+    }
+  };
+  if http::is_websocket_upgrade(&head) {
+    return serve_websocket(client_id, addr, Rewind::new(head, stream), handshake_deadline, ctx).await;
+  }
+  debug!("[client {} {}] Serving plain HTTP.", client_id, addr);
+  if let Err(err) = http::serve_http(Rewind::new(head, stream), routes).await {
+    debug!("[client {} {}] HTTP connection ended with an error: {}", client_id, addr, err);
+  }
+}
+
+/// Why the server turned a client away during its handshake.
+enum Refusal {
+  Origin(String),
+  UnknownPath,
+  NoSubprotocol,
+  Hook,
+}
+
+/// Builds the response for turning a client away during its handshake.
+fn refuse(status: StatusCode, body: &str) -> ErrorResponse {
+  let mut response = ErrorResponse::new(Some(body.to_string()));
+  *response.status_mut() = status;
+  response
+}
+
+/// Runs the websocket handshake over an accepted (and, for wss://, already decrypted) stream, routing it to an endpoint by its request path, selecting a subprotocol, and letting the handshake hook (if any) accept or reject it, then serves the connection until it closes.
+async fn serve_websocket<S>(
+  client_id: ClientId,
+  addr: SocketAddr,
+  stream: S,
+  handshake_deadline: tokio::time::Instant,
+  ctx: ServerContext
+) where S: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+  let ServerContext { cli_disconn_queue, clients, errors, event_signal, counters, .. } = ctx.clone();
+
+  // Captured from the upgrade request: the request itself, the endpoint serving it and the subprotocol selected (if any), and why it was refused (if it was).
+  let mut request: Option<HandshakeInfo> = None;
+  let mut endpoint: Option<Endpoint> = None;
+  let mut subprotocol: Option<String> = None;
+  let mut compression = None;
+  let mut refusal: Option<Refusal> = None;
+  // The error response type is tungstenite's.
+  #[allow(clippy::result_large_err)]
+  let check_request = |http_request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+    let info = HandshakeInfo::from_request(http_request);
+    endpoint = ctx.endpoints.route(&info.path).cloned();
+    subprotocol = info.select_subprotocol(&ctx.subprotocols);
+    // Browsers always send an Origin, so only a missing one is let through regardless.
+    let origin = info.header_values("origin").next().map(str::to_string);
+    let res = if let Some(origin) = origin.filter(|origin| !ctx.allowed_origins.is_empty() && !handshake::origin_allowed(&ctx.allowed_origins, origin)) {
+      refusal = Some(Refusal::Origin(origin));
+      Err(refuse(StatusCode::FORBIDDEN, "Forbidden"))
+    } else if endpoint.is_none() {
+      refusal = Some(Refusal::UnknownPath);
+      Err(refuse(StatusCode::NOT_FOUND, "Not Found"))
+    } else if subprotocol.is_none() && !ctx.subprotocols.is_empty() {
+      refusal = Some(Refusal::NoSubprotocol);
+      Err(refuse(StatusCode::BAD_REQUEST, "No supported subprotocol."))
+    } else {
+      if let Some(protocol) = subprotocol.as_deref().and_then(|protocol| HeaderValue::from_str(protocol).ok()) {
+        response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, protocol);
+      }
+      compression = ctx.compression.as_ref().and_then(|config| config.negotiate(info.header_values("sec-websocket-extensions")));
+      if let Some(extension) = compression.as_ref().and_then(|(_, extension)| HeaderValue::from_str(extension).ok()) {
+        response.headers_mut().insert(SEC_WEBSOCKET_EXTENSIONS, extension);
+      }
+      match &ctx.handshake_hook {
+        None => Ok(response),
+        Some(hook) => {
+          // The hook may block (e.g. on the GIL), so let the runtime move other tasks off this worker meanwhile.
+          let decision = tokio::task::block_in_place(|| hook.decide(&info));
+          let (res, hook_errors) = decision.respond(response);
+          for err in hook_errors {
+            warn!("[client {} {}] {}", client_id, addr, err);
+            errors.record(ErrorKind::Handshake, err, Some(client_id));
+          }
+          if res.is_err() { refusal = Some(Refusal::Hook); }
+          res
+        }
+      }
+    };
+    request = Some(info);
+    res
+  };
+
+  // The socket is wrapped so messages can be compressed, if the handshake agrees to it.
+  let ws_stream = match tokio::time::timeout_at(handshake_deadline, tokio_tungstenite::accept_hdr_async(DeflateStream::new(stream), check_request)).await {
+    Ok(ws_stream) => ws_stream,
+    Err(_) => {
+      warn!("[client {} {}] The websocket handshake timed out.", client_id, addr);
+      errors.record(ErrorKind::Handshake, format!("Websocket handshake with {} timed out after {:?}.", addr, HANDSHAKE_TIMEOUT), Some(client_id));
+      counters.handshake_failed(HandshakeFailure::Error);
+      return;
+    }
+  };
+  let (mut ws_stream, endpoint, request) = match (ws_stream, endpoint, request, refusal) {
+    (Ok(ws_stream), Some(endpoint), Some(request), None) => (ws_stream, endpoint, request),
+    (_, _, Some(_), Some(Refusal::Origin(origin))) => {
+      warn!("[client {} {}] Rejected a request from disallowed origin {:?}.", client_id, addr, origin);
+      errors.record(ErrorKind::Security, format!("Rejected a connection from {}: origin {:?} isn't allowed.", addr, origin), Some(client_id));
+      counters.handshake_failed(HandshakeFailure::Origin);
+      return;
+    }
+    (_, _, Some(request), Some(Refusal::UnknownPath)) => {
+      info!("[client {} {}] Rejected a request for unknown path {:?}.", client_id, addr, request.path);
+      errors.record(ErrorKind::Handshake, format!("Rejected a connection from {} to unknown path {:?}.", addr, request.path), Some(client_id));
+      counters.handshake_failed(HandshakeFailure::UnknownPath);
+      return;
+    }
+    (_, _, Some(request), Some(Refusal::NoSubprotocol)) => {
+      info!("[client {} {}] Rejected a request offering no supported subprotocol ({:?}).", client_id, addr, request.subprotocols());
+      errors.record(ErrorKind::Handshake, format!("Rejected a connection from {}: it offered none of the supported subprotocols (offered {:?}).", addr, request.subprotocols()), Some(client_id));
+      counters.handshake_failed(HandshakeFailure::Subprotocol);
+      return;
+    }
+    // The hook's rejection is the consumer's own decision, so it isn't an error.
+    (_, _, Some(request), Some(Refusal::Hook)) => {
+      info!("[client {} {}] Handshake hook rejected the request for {:?}.", client_id, addr, request.path_and_query());
+      counters.handshake_failed(HandshakeFailure::Hook);
+      return;
+    }
+    (res, _, _, _) => {
+      let err = res.err().map(|err| err.to_string()).unwrap_or_else(|| "no request".to_string());
+      warn!("[client {} {}] Error during the websocket handshake: {}", client_id, addr, err);
+      errors.record(ErrorKind::Handshake, format!("Websocket handshake with {} failed: {}", addr, err), Some(client_id));
+      counters.handshake_failed(HandshakeFailure::Error);
+      return;
+    }
+  };
+
+  let compression = compression.map(|(negotiated, extension)| {
+    ws_stream.get_mut().enable(negotiated);
+    extension
+  });
+  debug!("[client {} {}] New websocket connection to {:?} (subprotocol {:?}, compression {:?}).", client_id, addr, request.path_and_query(), subprotocol, compression);
+
+  // Each connection receives a receiver for messages to forward from its endpoint's broadcast, and (via the endpoint) a queue to forward client messages back to the server.
+  let ser_msg_broadcast_rx = endpoint.ser_msg_queue.subscribe();
+
+  // Each connection also gets its own outbound queue, registered so the consumer can address this client directly.
+  // Along with a channel the consumer can use to close just this connection, and one for subscribing it to topics.
+  let (cli_outbound_tx, cli_outbound_rx) = mpsc::channel::<Vec<Message>>(CLIENT_OUTBOUND_CAPACITY);
+  let (cli_disconnect_tx, cli_disconnect_rx) = watch::channel::<Option<CloseFrame<'static>>>(None);
+  let (cli_topics_tx, cli_topics_rx) = mpsc::unbounded_channel::<TopicCommand>();
+  let stats = ClientStats::new(&counters);
+  clients.insert(client_id, ClientHandle {
+    outbound_tx: cli_outbound_tx, disconnect_tx: cli_disconnect_tx, topics_tx: cli_topics_tx, topics: BTreeSet::new(),
+    path: request.path_and_query(), subprotocol: subprotocol.clone(), compression, peer: addr.to_string(), stats: stats.clone(),
+  });
+
+  // Report the new client, now that it's connected (and addressable).
+  match ctx.cli_conn_queue.push(ConnectEvent { client_id, peer: addr.to_string(), request, subprotocol }).await {
+    Pushed::Queued => {}
+    Pushed::DroppedOldest => debug!("[client {} {}] New client event queue is full; dropped the oldest event.", client_id, addr),
+    Pushed::DroppedNewest => debug!("[client {} {}] New client event queue is full; dropped this client's event.", client_id, addr),
+    Pushed::Rejected => {
+      // The client was never reported, so it's closed without a disconnect event either.
+      warn!("[client {} {}] New client event queue is full; refusing the connection.", client_id, addr);
+      errors.record(ErrorKind::QueueFull, format!("Refused a connection from {}: the new client event queue is full.", addr), Some(client_id));
+      clients.remove(client_id);
+      let _ = ws_stream.close(Some(CloseFrame { code: CloseCode::Again, reason: Cow::Borrowed("New client event queue is full.") })).await;
+      return;
+    }
+  }
+  event_signal.notify();
+  counters.connected(clients.count());
+
+  // The receiver task times keepalive pings (and watches for their pongs), but only the sender task can write them. It reports back when it has, since the ping may wait behind other messages.
+  let (ping_tx, ping_rx) = mpsc::channel::<Vec<u8>>(1);
+  let (ping_written_tx, ping_written_rx) = watch::channel::<Option<(Vec<u8>, tokio::time::Instant)>>(None);
+
+  // The receiver task watches for disconnect requests too.
+  let client_disconnect_rx = cli_disconnect_rx.clone();
+  let client_channels = ClientChannels { endpoint: endpoint.clone(), server_msg_rx: ser_msg_broadcast_rx, client_outbound_rx: cli_outbound_rx, client_disconnect_rx: cli_disconnect_rx, client_topics_rx: cli_topics_rx, ping_rx, ping_written_tx, stats: stats.clone() };
 
-  
   // Split up the stream to a client reader and a client writer.
   let (ws_client_write, ws_client_read) = ws_stream.split();
 
@@ -106,106 +456,445 @@ async fn handle_connection(
   let (ws_client_req_shutdown_tx, ws_client_req_shutdown_rx) = watch::channel::<()>(());
 
   // Launch a task to handle sending messages from the server-side library consumer to the websocket client over ws_write.
-  tokio::spawn(send_ws_client_messages(
-    server_msg_rx, ws_client_write, ser_req_shutdown_rx.clone(), ws_client_req_shutdown_rx
+  let mut send_task = tokio::spawn(send_ws_client_messages(
+    client_id, client_channels, ws_client_write, ws_client_req_shutdown_rx, ctx.clone()
   ));
 
   // Launch a task to handle receiving messages from the websocket client over ws_read and buffering them for the server-side library consumer to drain and handle later.
-  tokio::spawn(recv_ws_client_messages(
-    client_msg_tx, ws_client_read, ser_req_shutdown_rx, ws_client_req_shutdown_tx
+  let recv_task = tokio::spawn(recv_ws_client_messages(
+    client_id, endpoint.clone(), ws_client_read, ws_client_req_shutdown_tx, client_disconnect_rx, ping_tx, ping_written_rx, stats, ctx.clone()
   ));
 
   // Archived: For debugging purposes, we can create a simple message forwarder for the lifetime of the connection (bouncing messages from the websocket client back to them).
   // let (write, read) = ws_stream.split();
   // read.forward(write).await.expect("Failed to forward message");
 
-  println!("[handle_connection] Websocket connection handled.");
+  // The client stays addressable until both of its tasks are done. The receiver task finishes first and tells the sender task to close the connection; if the sender is stuck writing to a dead connection, give up on it.
+  let recv_res = recv_task.await;
+  if tokio::time::timeout(CLOSE_HANDSHAKE_TIMEOUT, &mut send_task).await.is_err() {
+    debug!("[client {} {}] Sender task did not finish in time; aborting it.", client_id, addr);
+    send_task.abort();
+    let _ = send_task.await;
+    // The sender's receivers are dropped with it, but it never got to leave its broadcast and topics.
+    endpoint.ser_msg_queue.taken();
+    for topic in clients.topics(client_id).unwrap_or_default() { ctx.topics.prune(&topic); }
+  }
+  clients.remove(client_id);
+
+  // The receiver task saw how the connection ended; report it to the consumer.
+  let cause = recv_res.unwrap_or_else(|err| DisconnectCause::abnormal(DisconnectKind::Error, format!("Connection task failed: {}", err)));
+  info!("[client {} {}] Disconnected ({}, code {}): {:?}", client_id, addr, cause.kind.as_str(), cause.code, cause.reason);
+  let disconn_evt = cause.into_event(client_id, addr.to_string());
+  match cli_disconn_queue.push(disconn_evt).await {
+    Pushed::Queued => {}
+    Pushed::DroppedOldest => debug!("[client {} {}] Client disconnect event queue is full; dropped the oldest event.", client_id, addr),
+    // Disconnect isn't allowed for this queue (see ServerConfig::check_disconnect_queue), so this is DropNewest.
+    Pushed::DroppedNewest | Pushed::Rejected => debug!("[client {} {}] Client disconnect event queue is full; dropped this client's event.", client_id, addr),
+  }
+  event_signal.notify();
+
+  debug!("[client {} {}] Websocket connection handled.", client_id, addr);
 }
 
-async fn send_ws_client_messages(
-  mut server_msg_rx: broadcast::Receiver<Vec<tokio_tungstenite::tungstenite::Message>>,
-  mut ws_client_write: SplitSink<WebSocketStream<TcpStream>, Message>,
-  mut ser_req_shutdown_rx: watch::Receiver::<bool>,
-  mut ws_client_req_shutdown_rx: watch::Receiver::<()>
-) {
+async fn send_ws_client_messages<S>(
+  client_id: ClientId,
+  client_channels: ClientChannels,
+  mut ws_client_write: SplitSink<WebSocketStream<S>, Message>,
+  mut ws_client_req_shutdown_rx: watch::Receiver::<()>,
+  ctx: ServerContext
+) where S: AsyncRead + AsyncWrite + Unpin {
+  let ClientChannels { endpoint, mut server_msg_rx, mut client_outbound_rx, mut client_disconnect_rx, mut client_topics_rx, mut ping_rx, ping_written_tx, stats } = client_channels;
+  let mut ser_req_shutdown_rx = ctx.ser_req_shutdown_rx.clone();
+
+  // The topics this client is subscribed to, and a stream of each one's batches, by name.
+  let mut topics: HashMap<String, Topic> = HashMap::new();
+  let mut topic_streams: StreamMap<String, BroadcastStream<Vec<Message>>> = StreamMap::new();
+
+  // Every broadcast batch this client has skipped since it connected, across the server broadcast and all its topics.
+  let mut total_skipped: u64 = 0;
+
   loop { tokio::select! {
-    // Receive server messages and forward them to connected clients.
+    // Receive server messages broadcast to this client's endpoint and forward them.
     recv_res = server_msg_rx.recv() => { match recv_res {
       Ok(msgs) => {
-        for msg in msgs {
-          let res = ws_client_write.feed(msg).await;
-          if res.is_err() {
-            println!("[send_ws_client_messages] Failed to feed ws_client_write. Assuming the connection has closed; terminating server forwarding task for this client.");
-            break;
-          }
+        endpoint.ser_msg_queue.taken();
+        if write_ws_client_messages(client_id, &stats, &mut ws_client_write, msgs).await.is_err() { break; }
+      }
+      Err(broadcast::error::RecvError::Lagged(skipped)) => {
+        let source = BroadcastSource { topic: None, queue: &endpoint.ser_msg_queue, snapshot: &endpoint.snapshot };
+        let resync = handle_broadcast_lag(client_id, source, skipped, &mut total_skipped, &stats, &client_disconnect_rx, &ctx).await;
+        if let Some(snapshot) = resync {
+          if write_ws_client_messages(client_id, &stats, &mut ws_client_write, snapshot).await.is_err() { break; }
         }
-        let res = ws_client_write.flush().await;
-        if res.is_err() {
-          println!("[send_ws_client_messages] Failed to flush ws_client_write. Assuming the connection has closed; terminating server forwarding task for this client.");
-          break;
+      }
+      // The endpoint's broadcast sender is gone, so nothing more can be forwarded. Close the connection rather than leave the client connected but unserved.
+      Err(broadcast::error::RecvError::Closed) => {
+        warn!("[client {}] The server broadcast channel closed; disconnecting.", client_id);
+        ctx.errors.record(ErrorKind::ChannelClosed, format!("Disconnected client {}: the server broadcast channel closed.", client_id), Some(client_id));
+        let frame = CloseFrame { code: CloseCode::Error, reason: Cow::Borrowed("Server broadcast closed") };
+        if let Ok(Err(err)) = tokio::time::timeout(CLOSE_HANDSHAKE_TIMEOUT, ws_client_write.send(Message::Close(Some(frame)))).await {
+          debug!("[client {}] Error sending the close frame: {}", client_id, err);
+        }
+        break;
+      }
+    }}
+
+    // Receive messages published to any of this client's topics and forward them.
+    Some((name, recv_res)) = topic_streams.next(), if !topic_streams.is_empty() => {
+      let topic = match topics.get(&name) { Some(topic) => topic, None => continue };
+      match recv_res {
+        Ok(msgs) => {
+          topic.queue.taken();
+          if write_ws_client_messages(client_id, &stats, &mut ws_client_write, msgs).await.is_err() { break; }
         }
+        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+          let source = BroadcastSource { topic: Some(&topic.name), queue: &topic.queue, snapshot: &topic.snapshot };
+          let resync = handle_broadcast_lag(client_id, source, skipped, &mut total_skipped, &stats, &client_disconnect_rx, &ctx).await;
+          if let Some(snapshot) = resync {
+            if write_ws_client_messages(client_id, &stats, &mut ws_client_write, snapshot).await.is_err() { break; }
+          }
+        }
+      }
+    }
+
+    // Join or leave topics, as asked by the consumer or (via the receiver task) by the client.
+    Some(command) = client_topics_rx.recv() => { match command {
+      TopicCommand::Subscribe(topic, rx) => {
+        debug!("[client {}] Subscribed to topic {:?}.", client_id, topic.name);
+        topic_streams.insert(topic.name.clone(), BroadcastStream::new(rx));
+        topics.insert(topic.name.clone(), topic);
       }
-      Err(err) => {
-        println!("[send_ws_client_messages] Error sending msg to WS client: {:?}", err);
+      TopicCommand::Unsubscribe(name) => {
+        // Dropping the stream drops its receiver, before the topic is checked for subscribers.
+        topic_streams.remove(&name);
+        if let Some(topic) = topics.remove(&name) {
+          debug!("[client {}] Unsubscribed from topic {:?}.", client_id, name);
+          leave_topic(&ctx, topic);
+        }
       }
     }}
 
+    // Receive messages addressed to only this client and forward them.
+    Some(msgs) = client_outbound_rx.recv() => {
+      if write_ws_client_messages(client_id, &stats, &mut ws_client_write, msgs).await.is_err() { break; }
+    }
+
+    // Send keepalive pings when the receiver task asks, and tell it once they're written, so the pong timeout doesn't count the time spent queued here.
+    Some(payload) = ping_rx.recv() => {
+      if let Err(err) = ws_client_write.send(Message::Ping(payload.clone())).await {
+        debug!("[client {}] Failed to send a ping ({}). Assuming the connection has closed; terminating server forwarding task for this client.", client_id, err);
+        break;
+      }
+      let _ = ping_written_tx.send(Some((payload, tokio::time::Instant::now())));
+    }
+
     // Receive a shutdown signal from the client receiver task, indicating the client sent a shutdown handshake.
     _ = ws_client_req_shutdown_rx.changed() => {
-      println!("[send_ws_client_messages] Received shutdown signal from the client receiver task; the client wants to disconnect. Resolving the shutdown handshake.");
+      debug!("[client {}] Received shutdown signal from the client receiver task; the client wants to disconnect. Resolving the shutdown handshake.", client_id);
       let res = ws_client_write.close().await;
       if let Err(err) = res {
-        println!("[send_ws_client_messages] Error closing ws_client_write: {:?}", err);
+        debug!("[client {}] Error closing ws_client_write: {}", client_id, err);
       }
       break;
     }
 
-    // Receive an exit signal and shutdown.
+    // Receive a request from the consumer to disconnect this client. Start the close handshake; the receiver task waits for the client's reply.
+    Ok(_) = client_disconnect_rx.changed() => {
+      let frame = client_disconnect_rx.borrow().clone();
+      if let Some(frame) = frame {
+        debug!("[client {}] Disconnecting client: {:?}", client_id, frame);
+        let res = ws_client_write.send(Message::Close(Some(frame))).await;
+        if let Err(err) = res {
+          warn!("[client {}] Error sending close frame to ws_client_write: {}", client_id, err);
+        }
+        break;
+      }
+    }
+
+    // Receive an exit signal: send the close frame and shut down. The receiver task waits for the client's reply.
     _ = ser_req_shutdown_rx.changed() => {
       if *ser_req_shutdown_rx.borrow() {
-        println!("[send_ws_client_messages] Received shutdown signal.");
+        debug!("[client {}] Sender received shutdown signal.", client_id);
+        let frame = CloseFrame { code: CloseCode::Away, reason: Cow::Borrowed(SHUTDOWN_CLOSE_REASON) };
+        match tokio::time::timeout(CLOSE_HANDSHAKE_TIMEOUT, ws_client_write.send(Message::Close(Some(frame)))).await {
+          Ok(Ok(())) => {}
+          Ok(Err(err)) => debug!("[client {}] Error sending the shutdown close frame: {}", client_id, err),
+          Err(_) => debug!("[client {}] Timed out sending the shutdown close frame.", client_id),
+        }
         break;
       }
     }
   }}
-  println!("[send_ws_client_messages] Client sender loop shutdown.")
+  // Leaving the broadcast and topics may make room for a blocked sender.
+  drop(server_msg_rx);
+  endpoint.ser_msg_queue.taken();
+  drop(topic_streams);
+  for topic in topics.into_values() { leave_topic(&ctx, topic); }
+  trace!("[client {}] Client sender loop shutdown.", client_id)
 }
 
-async fn recv_ws_client_messages(
-  client_msg_tx: mpsc::Sender<tokio_tungstenite::tungstenite::Message>,
-  mut ws_client_read: SplitStream<WebSocketStream<TcpStream>>,
-  mut ser_req_shutdown_rx: watch::Receiver::<bool>,
-  ws_client_req_shutdown_tx: watch::Sender::<()>
-) {
-  loop { tokio::select! {
-    // Receive messages from connected clients and forward them to client message buffer.
+/// Called once a client's receiver for a topic has been dropped: forgets the topic if that was its last subscriber.
+fn leave_topic(ctx: &ServerContext, topic: Topic) {
+  topic.queue.taken();
+  ctx.topics.prune(&topic.name);
+}
+
+/// A broadcast a client can fall behind on: its endpoint's broadcast, or one of its topics.
+struct BroadcastSource<'a> {
+  topic: Option<&'a str>,
+  queue: &'a BroadcastQueue,
+  snapshot: &'a ResyncSnapshot,
+}
+
+/// Counts and reports batches a client skipped by falling a full queue behind, then applies the lag policy. Returns the snapshot to send the client if it should be resynced.
+async fn handle_broadcast_lag(
+  client_id: ClientId,
+  source: BroadcastSource<'_>,
+  skipped: u64,
+  total_skipped: &mut u64,
+  stats: &ClientStats,
+  client_disconnect_rx: &watch::Receiver<Option<CloseFrame<'static>>>,
+  ctx: &ServerContext
+) -> Option<Vec<Message>> {
+  // The batches it missed are gone.
+  source.queue.lagged(skipped);
+  stats.lagged(skipped);
+  *total_skipped += skipped;
+  let snapshot = if ctx.lag_policy == LagPolicy::Resync { source.snapshot.get() } else { None };
+  let action = match (source.queue.config().overflow, ctx.lag_policy) {
+    (OverflowPolicy::Disconnect, _) => LagAction::Disconnected,
+    (_, LagPolicy::Disconnect { threshold }) if *total_skipped > threshold => LagAction::Disconnected,
+    _ if snapshot.is_some() => LagAction::Resynced,
+    _ => LagAction::Continued,
+  };
+  let from = source.topic.map(|topic| format!("topic {:?}", topic)).unwrap_or_else(|| "the server broadcast".to_string());
+  debug!("[client {}] Fell behind {}; skipped {} batches ({} in total), {}.", client_id, from, skipped, total_skipped, action.as_str());
+
+  let evt = LagEvent { client_id, topic: source.topic.map(str::to_string), skipped, total_skipped: *total_skipped, action };
+  if ctx.cli_lag_queue.push(evt).await == Pushed::DroppedOldest {
+    trace!("[client {}] Client lag event queue is full; dropped the oldest event.", client_id);
+  }
+  ctx.event_signal.notify();
+
+  if action == LagAction::Disconnected && client_disconnect_rx.borrow().is_none() {
+    warn!("[client {}] Fell behind {} by {} batches in total; disconnecting.", client_id, from, total_skipped);
+    ctx.errors.record(ErrorKind::QueueFull, format!("Disconnected client {}: it fell behind {} by {} batches.", client_id, from, total_skipped), Some(client_id));
+    // The sender task's disconnect arm sends the close frame, and the receiver task waits for the reply, as for a consumer-requested disconnect.
+    let _ = ctx.clients.request_disconnect(client_id, CloseFrame { code: CloseCode::Policy, reason: Cow::Borrowed("Fell behind the server broadcast.") });
+  }
+  if action == LagAction::Resynced { snapshot } else { None }
+}
+
+/// Feeds a batch of messages to the client and flushes once at the end. An Err means the connection should be assumed closed.
+async fn write_ws_client_messages<S>(
+  client_id: ClientId,
+  stats: &ClientStats,
+  ws_client_write: &mut SplitSink<WebSocketStream<S>, Message>,
+  msgs: Vec<tokio_tungstenite::tungstenite::Message>
+) -> Result<(), ()> where S: AsyncRead + AsyncWrite + Unpin {
+  let (count, bytes) = (msgs.len() as u64, msgs.iter().map(|msg| msg.len() as u64).sum());
+  for msg in msgs {
+    let res = ws_client_write.feed(msg).await;
+    if res.is_err() {
+      debug!("[client {}] Failed to feed ws_client_write. Assuming the connection has closed; terminating server forwarding task for this client.", client_id);
+      return Err(());
+    }
+  }
+  let res = ws_client_write.flush().await;
+  if res.is_err() {
+    debug!("[client {}] Failed to flush ws_client_write. Assuming the connection has closed; terminating server forwarding task for this client.", client_id);
+    return Err(());
+  }
+  stats.sent(count, bytes);
+  Ok(())
+}
+
+/// Forwards client messages to the consumer until the connection ends, and returns how it ended.
+#[allow(clippy::too_many_arguments)]
+async fn recv_ws_client_messages<S>(
+  client_id: ClientId,
+  endpoint: Endpoint,
+  mut ws_client_read: SplitStream<WebSocketStream<S>>,
+  ws_client_req_shutdown_tx: watch::Sender::<()>,
+  mut client_disconnect_rx: watch::Receiver<Option<CloseFrame<'static>>>,
+  ping_tx: mpsc::Sender<Vec<u8>>,
+  mut ping_written_rx: watch::Receiver<Option<(Vec<u8>, tokio::time::Instant)>>,
+  stats: ClientStats,
+  ctx: ServerContext
+) -> DisconnectCause where S: AsyncRead + AsyncWrite + Unpin {
+  let Endpoint { cli_msg_queue, .. } = endpoint;
+  let ServerContext { clients, topics, topic_control, mut ser_req_shutdown_rx, errors, event_signal, keepalive, .. } = ctx;
+
+  // Set once the client sends its close frame. tungstenite replies to it for us, after which the stream ends.
+  let mut client_close_frame: Option<Option<CloseFrame<'static>>> = None;
+
+  // Set once the consumer asks to disconnect this client. The sender task sends this frame; we keep reading until the client replies, or until the close handshake times out.
+  let mut server_close_frame: Option<CloseFrame<'static>> = None;
+  // (Only polled once armed by a disconnect request.)
+  let close_handshake_deadline = tokio::time::sleep(CLOSE_HANDSHAKE_TIMEOUT);
+  tokio::pin!(close_handshake_deadline);
+
+  // With keepalive on, the client is pinged every interval, and must answer each ping (with the same payload) before the timeout, counted from when the sender task writes it. Only one ping is outstanding at a time.
+  let ping_interval = keepalive.map_or(CLOSE_HANDSHAKE_TIMEOUT, |keepalive| keepalive.interval);
+  let pong_timeout = keepalive.map_or(ping_interval, |keepalive| keepalive.timeout);
+  let mut ping_ticker = tokio::time::interval_at(tokio::time::Instant::now() + ping_interval, ping_interval);
+  ping_ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+  let mut pings_sent: u64 = 0;
+  // The payload of the ping awaiting a pong, if any.
+  let mut awaiting_pong: Option<Vec<u8>> = None;
+  // (Only polled while a ping is awaiting its pong.)
+  let pong_deadline = tokio::time::sleep(ping_interval);
+  tokio::pin!(pong_deadline);
+
+  let cause = loop { tokio::select! {
+    // Receive messages from connected clients and forward them to client message buffer, tagged with this connection's client ID.
     read_res = ws_client_read.next() => { match read_res {
-      Some(Ok(msg)) => {
-        let res = client_msg_tx.send(msg).await;
-        if res.is_err() { println!("[recv_ws_client_messages] Failed to send client message to client msg buffer"); }
+      Some(Ok(Message::Close(frame))) => {
+        debug!("[client {}] Client sent a close frame: {:?}", client_id, frame);
+        client_close_frame = Some(frame);
+      }
+      // Topic control messages are handled here, and never reach the consumer.
+      Some(Ok(Message::Text(text))) if topic_control && (text.starts_with(SUBSCRIBE_PREFIX) || text.starts_with(UNSUBSCRIBE_PREFIX)) => {
+        stats.touch();
+        let res = match text.strip_prefix(SUBSCRIBE_PREFIX) {
+          Some(topic) => clients.subscribe(client_id, topic, &topics),
+          None => clients.unsubscribe(client_id, &text[UNSUBSCRIBE_PREFIX.len()..]),
+        };
+        match res {
+          // The client asked for a topic it can't have; let the consumer know.
+          Err(ClientSendError::Topic(err)) => {
+            debug!("[client {}] Refused topic control message {:?}: {}", client_id, text, err);
+            errors.record(ErrorKind::InvalidRequest, format!("Refused client {}'s topic control message: {}.", client_id, err), Some(client_id));
+          }
+          Err(err) => debug!("[client {}] Failed to handle topic control message {:?}: {:?}", client_id, text, err),
+          Ok(_) => {}
+        }
+      }
+      Some(Ok(msg)) if msg.is_text() || msg.is_binary() => {
+        stats.received(&msg);
+        match cli_msg_queue.push((client_id, msg)).await {
+          Pushed::Queued => {}
+          Pushed::DroppedOldest => trace!("[client {}] Client message queue is full; dropped the oldest message.", client_id),
+          Pushed::DroppedNewest => trace!("[client {}] Client message queue is full; dropped this client's message.", client_id),
+          Pushed::Rejected => {
+            if server_close_frame.is_none() {
+              warn!("[client {}] Client message queue is full; disconnecting.", client_id);
+              errors.record(ErrorKind::QueueFull, format!("Disconnected client {}: its message arrived while the client message queue was full.", client_id), Some(client_id));
+              // Start the close handshake just as for a consumer-requested disconnect: the sender task sends the frame, and we wait for the reply.
+              let frame = CloseFrame { code: CloseCode::Policy, reason: Cow::Borrowed("Client message queue is full.") };
+              let _ = clients.request_disconnect(client_id, frame.clone());
+              server_close_frame = Some(frame);
+              close_handshake_deadline.as_mut().reset(tokio::time::Instant::now() + CLOSE_HANDSHAKE_TIMEOUT);
+            }
+          }
+        }
+        event_signal.notify();
+      }
+      // Answers to keepalive pings measure the round-trip time. Unsolicited pongs are allowed, and ignored.
+      Some(Ok(Message::Pong(payload))) => {
+        stats.touch();
+        match &awaiting_pong {
+          Some(expected) if *expected == payload => {
+            // The sender task records the ping as written before the client can have seen it.
+            if let Some((_, written_at)) = ping_written_rx.borrow().as_ref().filter(|(written, _)| *written == payload) {
+              let rtt = written_at.elapsed();
+              trace!("[client {}] Ping answered in {:?}.", client_id, rtt);
+              stats.set_rtt(rtt);
+            }
+            awaiting_pong = None;
+          }
+          _ => trace!("[client {}] Ignoring an unsolicited pong.", client_id),
+        }
       }
+      // Pings are answered by tungstenite; the consumer only sees text and binary messages.
+      Some(Ok(_)) => stats.touch(),
       Some(Err(err)) => {
-        println!("[recv_ws_client_messages] Error receiving msg from WS client: {:?}", err);
+        debug!("[client {}] Error receiving msg from WS client: {}", client_id, err);
+        break match (server_close_frame.take(), client_close_frame.take()) {
+          (Some(frame), _) => DisconnectCause::clean(Some(frame)),
+          (None, Some(frame)) => DisconnectCause::clean(frame),
+          (None, None) => {
+            errors.record(ErrorKind::Connection, format!("Error receiving from client: {}", err), Some(client_id));
+            disconnect_cause_for_error(&err)
+          }
+        };
       }
       None => {
-        println!("[recv_ws_client_messages] None received from ws_client_read.next(), connection stream must be closed. Sending notification to the sender task.");
-
-        // Send the shutdown signal to the sender-side task for this connection.
-        let conn_shutdown_res = ws_client_req_shutdown_tx.send(());
-        if let Err(err) = conn_shutdown_res {
-          println!("[recv_ws_client_messages] Error sending a shutdown signal to the sender-side task for the closed connection: {:?}", err)
-        }
-        break;
+        trace!("[client {}] None received from ws_client_read.next(), connection stream must be closed. Sending notification to the sender task.", client_id);
+        break match (server_close_frame.take(), client_close_frame.take()) {
+          (Some(frame), _) => DisconnectCause::clean(Some(frame)),
+          (None, Some(frame)) => DisconnectCause::clean(frame),
+          (None, None) => DisconnectCause::abnormal(DisconnectKind::Error, "Connection closed without a close frame.".to_string()),
+        };
       }
     }}
 
-    // Receive an exit signal and shutdown.
-    _ = ser_req_shutdown_rx.changed() => {
+    // Receive a request from the consumer to disconnect this client. Keep reading until the client answers the sender task's close frame.
+    Ok(_) = client_disconnect_rx.changed(), if server_close_frame.is_none() => {
+      server_close_frame = client_disconnect_rx.borrow().clone();
+      if server_close_frame.is_some() {
+        close_handshake_deadline.as_mut().reset(tokio::time::Instant::now() + CLOSE_HANDSHAKE_TIMEOUT);
+      }
+    }
+
+    // Time for a keepalive ping, unless the last one is still unanswered or the connection is closing.
+    _ = ping_ticker.tick(), if keepalive.is_some() && awaiting_pong.is_none() && server_close_frame.is_none() => {
+      pings_sent += 1;
+      let payload = pings_sent.to_be_bytes().to_vec();
+      if ping_tx.try_send(payload.clone()).is_ok() {
+        awaiting_pong = Some(payload);
+        // The pong timeout starts once the ping is written. Until then, a ping that can't even be written within another interval means the connection is stuck.
+        pong_deadline.as_mut().reset(tokio::time::Instant::now() + ping_interval + pong_timeout);
+      }
+    }
+
+    // The sender task wrote the ping; the client has until the timeout to answer it.
+    Ok(_) = ping_written_rx.changed() => {
+      let written = ping_written_rx.borrow().clone();
+      if let (Some(expected), Some((payload, written_at))) = (&awaiting_pong, written) {
+        if *expected == payload { pong_deadline.as_mut().reset(written_at + pong_timeout); }
+      }
+    }
+
+    // The client never answered a keepalive ping, so the connection is presumed dead.
+    _ = &mut pong_deadline, if awaiting_pong.is_some() && server_close_frame.is_none() => {
+      warn!("[client {}] Client did not answer a ping within {:?}; disconnecting.", client_id, pong_timeout);
+      break DisconnectCause::abnormal(DisconnectKind::TimedOut, format!("No pong within {:.1} s.", pong_timeout.as_secs_f64()));
+    }
+
+    // The client never answered our close frame.
+    _ = &mut close_handshake_deadline, if server_close_frame.is_some() => {
+      warn!("[client {}] Client did not answer the close frame in time.", client_id);
+      let frame = server_close_frame.take().unwrap();
+      break DisconnectCause { kind: DisconnectKind::TimedOut, code: frame.code.into(), reason: frame.reason.into_owned() };
+    }
+
+    // Receive an exit signal. The sender task sends the close frame; keep reading until the client replies, as for a consumer-requested disconnect.
+    _ = ser_req_shutdown_rx.changed(), if server_close_frame.is_none() => {
       if *ser_req_shutdown_rx.borrow() {
-        println!("[send_ws_client_messages] Received shutdown signal.");
-        break;
+        debug!("[client {}] Receiver received shutdown signal.", client_id);
+        server_close_frame = Some(CloseFrame { code: CloseCode::Away, reason: Cow::Borrowed(SHUTDOWN_CLOSE_REASON) });
+        close_handshake_deadline.as_mut().reset(tokio::time::Instant::now() + CLOSE_HANDSHAKE_TIMEOUT);
       }
     }
-  }}
-  println!("[recv_ws_client_messages] Client receiver loop shutdown.")
+  }};
+
+  // Send the shutdown signal to the sender-side task for this connection.
+  let conn_shutdown_res = ws_client_req_shutdown_tx.send(());
+  if let Err(err) = conn_shutdown_res {
+    trace!("[client {}] Error sending a shutdown signal to the sender-side task for the closed connection: {}", client_id, err)
+  }
+
+  trace!("[client {}] Client receiver loop shutdown.", client_id);
+  cause
+}
+
+/// Classifies a read error that ended a connection before any close frame arrived.
+fn disconnect_cause_for_error(err: &tungstenite::Error) -> DisconnectCause {
+  match err {
+    tungstenite::Error::Io(io_err) if io_err.kind() == io::ErrorKind::TimedOut => {
+      DisconnectCause::abnormal(DisconnectKind::TimedOut, io_err.to_string())
+    }
+    _ => DisconnectCause::abnormal(DisconnectKind::Error, err.to_string()),
+  }
 }
@@ -0,0 +1,129 @@
+// topics.rs
+//
+// Named topics, for publishing to just the clients that joined them. Each topic is its own broadcast fan-out, shared between the consumer (which publishes to it) and the connections subscribed to it (each of which holds a receiver in its sender task).
+//
+// Topics are created on first subscription and forgotten again once nobody's subscribed and they have no resync snapshot to keep. Clients can name topics themselves (with topic control messages), so names and counts are capped.
+
+use std::{collections::HashMap, fmt, sync::{Arc, RwLock}};
+use tokio::sync::broadcast;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::queue::{BroadcastQueue, ResyncSnapshot};
+
+/// Prefix of the text messages clients send to subscribe themselves to a topic, when topic control messages are enabled, e.g. "quicksocket:subscribe:scores".
+pub const SUBSCRIBE_PREFIX: &str = "quicksocket:subscribe:";
+/// Prefix of the text messages clients send to unsubscribe themselves from a topic, e.g. "quicksocket:unsubscribe:scores".
+pub const UNSUBSCRIBE_PREFIX: &str = "quicksocket:unsubscribe:";
+
+/// The longest topic name accepted, in bytes.
+pub const MAX_TOPIC_NAME_LEN: usize = 256;
+/// The most topics a single client can be subscribed to at once.
+pub const MAX_TOPICS_PER_CLIENT: usize = 64;
+/// The most topics a server keeps at once, whether for their subscribers or for their resync snapshots.
+pub const MAX_TOPICS: usize = 4096;
+
+/// Why a topic couldn't be subscribed to (or given a snapshot).
+#[derive(Debug, PartialEq, Eq)]
+pub enum TopicError {
+  /// The name is empty, or longer than MAX_TOPIC_NAME_LEN.
+  InvalidName,
+  /// The client is already subscribed to MAX_TOPICS_PER_CLIENT topics.
+  TooManyForClient,
+  /// The server already has MAX_TOPICS topics.
+  TooMany,
+}
+
+impl fmt::Display for TopicError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TopicError::InvalidName      => write!(f, "topic names must be 1 to {} bytes long", MAX_TOPIC_NAME_LEN),
+      TopicError::TooManyForClient => write!(f, "a client can be subscribed to at most {} topics", MAX_TOPICS_PER_CLIENT),
+      TopicError::TooMany          => write!(f, "the server can have at most {} topics", MAX_TOPICS),
+    }
+  }
+}
+
+/// Checks a topic name: it can't be empty or longer than MAX_TOPIC_NAME_LEN.
+pub fn check_name(name: &str) -> Result<(), TopicError> {
+  if name.is_empty() || name.len() > MAX_TOPIC_NAME_LEN { return Err(TopicError::InvalidName); }
+  Ok(())
+}
+
+/// A single topic: its broadcast fan-out, and what to resync its lagging subscribers with.
+#[derive(Clone)]
+pub struct Topic {
+  pub name: String,
+  pub queue: BroadcastQueue,
+  pub snapshot: ResyncSnapshot,
+}
+
+/// Sent to a connection's sender task to change which topics it forwards.
+pub enum TopicCommand {
+  /// Start forwarding a topic, from this receiver. The receiver is subscribed when the subscription is made rather than when the sender task gets to it, so nothing published in between is missed.
+  Subscribe(Topic, broadcast::Receiver<Vec<Message>>),
+  Unsubscribe(String),
+}
+
+/// Cheaply cloneable, thread-safe map of topic name -> Topic.
+#[derive(Clone)]
+pub struct TopicRegistry {
+  topics: Arc<RwLock<HashMap<String, Topic>>>,
+  /// Every topic gets the same capacity and overflow policy as the server broadcast, and shares its drop count.
+  template: BroadcastQueue,
+}
+
+impl TopicRegistry {
+  pub fn new(template: BroadcastQueue) -> Self {
+    TopicRegistry { topics: Arc::new(RwLock::new(HashMap::new())), template }
+  }
+
+  /// The topic, if anyone has subscribed to it (or it has a snapshot).
+  pub fn get(&self, name: &str) -> Option<Topic> {
+    self.topics.read().ok().and_then(|topics| topics.get(name).cloned())
+  }
+
+  /// The number of topics kept.
+  pub fn count(&self) -> usize {
+    self.topics.read().map(|topics| topics.len()).unwrap_or(0)
+  }
+
+  /// Subscribes a new receiver to the topic, creating the topic if needed.
+  pub fn subscribe(&self, name: &str) -> Result<(Topic, broadcast::Receiver<Vec<Message>>), TopicError> {
+    let mut topics = self.topics.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let topic = self.get_or_create(&mut topics, name)?;
+    let rx = topic.queue.subscribe();
+    Ok((topic.clone(), rx))
+  }
+
+  /// Sets the topic's resync snapshot, creating the topic if needed, or clears it with None.
+  pub fn set_snapshot(&self, name: &str, messages: Option<Vec<Message>>) -> Result<(), TopicError> {
+    if let Ok(mut topics) = self.topics.write() {
+      match (topics.get(name), &messages) {
+        (Some(topic), _) => topic.snapshot.set(messages),
+        // There's nothing to clear.
+        (None, None) => return Ok(()),
+        (None, Some(_)) => self.get_or_create(&mut topics, name)?.snapshot.set(messages),
+      }
+    }
+    self.prune(name);
+    Ok(())
+  }
+
+  /// Forgets the topic if nobody's subscribed to it and it has no snapshot. Called whenever a receiver is dropped.
+  pub fn prune(&self, name: &str) {
+    if let Ok(mut topics) = self.topics.write() {
+      let unused = topics.get(name).is_some_and(|topic| topic.queue.receiver_count() == 0 && topic.snapshot.get().is_none());
+      if unused { topics.remove(name); }
+    }
+  }
+
+  fn get_or_create<'a>(&self, topics: &'a mut HashMap<String, Topic>, name: &str) -> Result<&'a Topic, TopicError> {
+    check_name(name)?;
+    if !topics.contains_key(name) {
+      if topics.len() >= MAX_TOPICS { return Err(TopicError::TooMany); }
+      let topic = Topic { name: name.to_string(), queue: self.template.sibling(), snapshot: ResyncSnapshot::new() };
+      topics.insert(name.to_string(), topic);
+    }
+    Ok(&topics[name])
+  }
+}
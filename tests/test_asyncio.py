@@ -0,0 +1,65 @@
+import time
+
+import quicksocket.server
+from quicksocket.server import ClientConnectEvent, ClientDisconnectEvent, ServerNotRunningError
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+async def client_converse(port: int):
+  uri = "ws://localhost:" + str(port)
+  async with websockets.connect(uri) as websocket:
+    await websocket.send("hello")
+    await websocket.send(b"bytes")
+    assert(await asyncio.wait_for(websocket.recv(), timeout=5.0) == "hello back")
+    await websocket.close()
+
+async def server_converse(server: quicksocket.server.Server, port: int):
+  # A receive that's cancelled while waiting doesn't take anything.
+  try:
+    await asyncio.wait_for(server.recv_message(), timeout=0.100)
+    assert(False)
+  except asyncio.TimeoutError:
+    pass
+
+  events = server.events()
+  client = asyncio.ensure_future(client_converse(port))
+
+  connect = await asyncio.wait_for(events.__anext__(), timeout=5.0)
+  assert(isinstance(connect, ClientConnectEvent))
+
+  client_id, payload = await asyncio.wait_for(server.recv_message(), timeout=5.0)
+  assert(client_id == connect.client_id)
+  assert(payload == "hello")
+  assert(await asyncio.wait_for(server.recv_message(), timeout=5.0) == (client_id, b"bytes"))
+  assert(server.send_to_client(client_id, ["hello back"]))
+
+  disconnect = await asyncio.wait_for(events.__anext__(), timeout=5.0)
+  assert(isinstance(disconnect, ClientDisconnectEvent))
+  assert(disconnect.client_id == client_id)
+  await client
+
+  # Once the server stops, the event stream ends and receives raise.
+  server.stop()
+  remaining = [event async for event in events]
+  assert(remaining == [])
+  try:
+    await asyncio.wait_for(server.recv_message(), timeout=5.0)
+    assert(False)
+  except ServerNotRunningError:
+    pass
+
+def test_asyncio_receive():
+  port = 60060
+
+  server = quicksocket.server.Server()
+  assert(server.start(port))
+  time.sleep(0.200)
+
+  asyncio.new_event_loop().run_until_complete(server_converse(server, port))
+
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_asyncio_receive()
@@ -0,0 +1,79 @@
+import socket
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+def ipv6_available() -> bool:
+  try:
+    with socket.socket(socket.AF_INET6, socket.SOCK_STREAM) as sock:
+      sock.bind(("::1", 0))
+    return True
+  except OSError:
+    return False
+
+async def client_send(uri: str, message: str):
+  async with websockets.connect(uri) as websocket:
+    await websocket.send(message)
+    await asyncio.sleep(0.200)
+    await websocket.close()
+
+def test_multiple_listeners():
+  port = 60010
+  addresses = ["127.0.0.1:" + str(port)]
+  uris = ["ws://127.0.0.1:" + str(port)]
+  if ipv6_available():
+    addresses.append("[::1]:" + str(port))
+    uris.append("ws://[::1]:" + str(port))
+
+  server = quicksocket.server.Server()
+  assert(server.start(addresses = addresses))
+  time.sleep(0.200)
+  assert(server.is_running())
+
+  # Clients on every listener feed the same message queue, with distinct client IDs.
+  async def run_tasks(loop):
+    cli_tasks = [loop.create_task(client_send(uri, uri)) for uri in uris]
+    received = []
+    for attempt_num in range(0, 120):
+      received += server.drain_client_messages()
+      if len(received) == len(uris): break
+      await asyncio.sleep(0.050)
+    for task in cli_tasks: await task
+    return received
+  loop = asyncio.get_event_loop()
+  received = loop.run_until_complete(run_tasks(loop))
+  assert(sorted(msg for _, msg in received) == sorted(uris))
+  assert(len(set(client_id for client_id, _ in received)) == len(uris))
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_bind_failure_is_reported():
+  port = 60011
+
+  first = quicksocket.server.Server()
+  assert(first.start(port))
+  time.sleep(0.200)
+
+  # The port is taken, so the second server can't start, and says why.
+  second = quicksocket.server.Server()
+  try:
+    second.start(port)
+    assert(False)
+  except quicksocket.server.BindError as e:
+    assert("Failed to bind" in str(e))
+  assert("Failed to bind" in second.get_last_error_string())
+  errors = second.drain_errors()
+  assert([error.kind for error in errors] == ["bind"])
+  assert(not second.is_running())
+
+  first.stop()
+  time.sleep(0.200)
+
+if __name__ == "__main__":
+  test_multiple_listeners()
+  test_bind_failure_is_reported()
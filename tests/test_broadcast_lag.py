@@ -0,0 +1,94 @@
+import time
+
+import quicksocket.server
+from quicksocket.server import QueueConfig
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+BIG = "x" * (512 * 1024)
+
+async def stalled_client(port: int):
+  '''Introduce ourselves, then stop reading while the server floods us, so we fall behind. Returns what we read once we catch up, and the close code if the server closed the connection.'''
+  uri = "ws://localhost:" + str(port)
+  async with websockets.connect(uri) as websocket:
+    await websocket.send("ready")
+    await asyncio.sleep(1.5)
+    received = []
+    try:
+      while True:
+        msg = await asyncio.wait_for(websocket.recv(), timeout=5.0)
+        received.append("big" if msg == BIG else msg)
+        if msg == "done":
+          break
+    except websockets.ConnectionClosed as e:
+      return received, e.code
+    await websocket.close()
+    return received, None
+
+async def flood(server: quicksocket.server.Server):
+  for attempt_num in range(0, 120):
+    if server.drain_client_messages():
+      break
+    await asyncio.sleep(0.050)
+  # Far more than the socket buffers hold, so the client's connection stalls and its broadcast receiver falls behind.
+  for i in range(0, 64):
+    server.send_messages([BIG])
+  await asyncio.sleep(0.5)
+  server.send_messages(["done"])
+
+def run_lagging_client(port: int, **start_args):
+  server = quicksocket.server.Server()
+  server.set_resync_snapshot(["snapshot"])
+  assert(server.start(port, broadcast_queue = QueueConfig(1), **start_args))
+  time.sleep(0.200)
+
+  async def run_tasks(loop):
+    client = loop.create_task(stalled_client(port))
+    await flood(server)
+    return await client
+  loop = asyncio.get_event_loop()
+  received, close_code = loop.run_until_complete(run_tasks(loop))
+
+  time.sleep(0.200)
+  lag_events = server.drain_client_lag_events()
+  assert(len(lag_events) >= 1)
+  assert(sum(evt.skipped for evt in lag_events) == lag_events[-1].total_skipped)
+  assert(server.get_drop_counts()["broadcast"] == lag_events[-1].total_skipped)
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+  return received, close_code, lag_events
+
+def test_lag_continue():
+  received, close_code, lag_events = run_lagging_client(60090)
+  assert(close_code is None)
+  assert(received[-1] == "done" and "snapshot" not in received)
+  assert(all(evt.action == "continued" for evt in lag_events))
+
+def test_lag_resync():
+  received, close_code, lag_events = run_lagging_client(60091, lag_policy = "resync")
+  assert(close_code is None)
+  assert(received[-1] == "done" and "snapshot" in received)
+  assert(all(evt.action == "resynced" for evt in lag_events))
+
+def test_lag_disconnect():
+  received, close_code, lag_events = run_lagging_client(60092, lag_policy = "disconnect", lag_threshold = 0)
+  assert(close_code == 1008)
+  assert(lag_events[0].action == "disconnected")
+
+def test_bad_lag_policy():
+  server = quicksocket.server.Server()
+  try:
+    server.start(60093, lag_policy = "sometimes")
+    assert(False)
+  except ValueError:
+    pass
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_lag_continue()
+  test_lag_resync()
+  test_lag_disconnect()
+  test_bad_lag_policy()
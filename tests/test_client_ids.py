@@ -0,0 +1,54 @@
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+async def client_send(port: int, message: str):
+  uri = "ws://localhost:" + str(port)
+  async with websockets.connect(uri) as websocket:
+    await websocket.send(message)
+    await asyncio.sleep(0.300)
+    await websocket.close()
+
+async def collect_messages(server: quicksocket.server.Server, count: int):
+  '''Collect connection events and client messages until `count` messages have arrived.'''
+  connected, received = [], []
+  for attempt_num in range(0, 120):
+    connected += server.drain_new_client_events()
+    received += server.drain_client_messages()
+    if len(received) >= count:
+      return connected, received
+    await asyncio.sleep(0.050)
+
+  raise Exception("[test_client_ids] [collect_messages] Exception: Failed to receive client messages in a reasonable amount of time.")
+
+def test_messages_tagged_with_client_id():
+  port = 59997
+
+  server = quicksocket.server.Server()
+  server.start(port)
+  time.sleep(0.200)
+  assert(server.is_running())
+
+  async def run_tasks(loop):
+    cli_tasks = [loop.create_task(client_send(port, "client " + str(i))) for i in range(2)]
+    connected, received = await collect_messages(server, 2)
+    for task in cli_tasks: await task
+    return connected, received
+  loop = asyncio.get_event_loop()
+  connected, received = loop.run_until_complete(run_tasks(loop))
+
+  # Each connection has its own ID, and each message carries the ID of the connection that sent it.
+  connected_ids = [client_id for client_id, _ in connected]
+  assert(len(set(connected_ids)) == 2)
+  assert(set(client_id for client_id, _ in received) == set(connected_ids))
+  assert(sorted(msg for _, msg in received) == ["client 0", "client 1"])
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_messages_tagged_with_client_id()
@@ -0,0 +1,76 @@
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+def test_client_info():
+  port = 60180
+  server = quicksocket.server.Server()
+  assert(server.get_clients() == [])
+  assert(server.start(port, subprotocols = ["viz.v1"]))
+  time.sleep(0.200)
+
+  async def run_tasks():
+    before = time.time()
+    async with websockets.connect("ws://localhost:" + str(port) + "/hands?user=3", subprotocols = ["viz.v1"]) as first:
+      async with websockets.connect("ws://localhost:" + str(port), subprotocols = ["viz.v1"]) as second:
+        await first.send("hello")
+        await first.send(b"\x00\x01\x02")
+        for attempt_num in range(0, 120):
+          msgs = server.drain_client_messages()
+          if msgs:
+            break
+          await asyncio.sleep(0.050)
+        first_id = msgs[0][0]
+        for attempt_num in range(0, 120):
+          info = server.get_client_info(first_id)
+          if info.messages_received == 2:
+            break
+          await asyncio.sleep(0.050)
+        assert(info.client_id == first_id)
+        assert(info.peer.startswith("127.0.0.1:"))
+        assert(before - 1.0 <= info.connected_at <= time.time())
+        assert(info.path == "/hands?user=3")
+        assert(info.subprotocol == "viz.v1")
+        assert(info.bytes_received == 8)
+        assert(info.messages_sent == 0)
+        assert(info.outbound_queue_depth == 0)
+        assert(info.lag_events == 0 and info.lag_skipped == 0)
+        assert(info.connected_at <= info.last_activity <= time.time())
+
+        clients = server.get_clients()
+        assert(len(clients) == 2)
+        assert(clients[0].client_id < clients[1].client_id)
+        assert(first_id in [client.client_id for client in clients])
+
+        assert(server.send_to_client(first_id, ["hi", "there"]))
+        assert(await asyncio.wait_for(first.recv(), timeout=5.0) == "hi")
+        assert(await asyncio.wait_for(first.recv(), timeout=5.0) == "there")
+        for attempt_num in range(0, 120):
+          info = server.get_client_info(first_id)
+          if info.messages_sent == 2:
+            break
+          await asyncio.sleep(0.050)
+        assert(info.bytes_sent == 7)
+        # The other client's traffic is its own.
+        second_info = [client for client in clients if client.client_id != first_id][0]
+        assert(server.get_client_info(second_info.client_id).messages_received == 0)
+        assert(server.get_client_info(second_info.client_id).path == "/")
+    return first_id
+  first_id = asyncio.get_event_loop().run_until_complete(run_tasks())
+
+  for attempt_num in range(0, 120):
+    if not server.get_clients():
+      break
+    time.sleep(0.050)
+  assert(server.get_clients() == [])
+  assert(server.get_client_info(first_id) is None)
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_client_info()
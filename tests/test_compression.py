@@ -0,0 +1,193 @@
+import base64
+import os
+import struct
+import time
+import zlib
+
+import quicksocket.server
+from quicksocket.server import CompressionConfig
+
+import asyncio # The websockets test library can't negotiate extensions, so these tests speak the protocol over a plain socket.
+
+# Every compressed message's deflate data ends with these bytes, which are left off the wire.
+DEFLATE_TAIL = b"\x00\x00\xff\xff"
+
+async def handshake(port: int, extensions):
+  '''Open a websocket connection offering `extensions` in its Sec-WebSocket-Extensions header. Returns its reader and writer, and the extensions the server answered with (or None).'''
+  reader, writer = await asyncio.open_connection("127.0.0.1", port)
+  key = base64.b64encode(os.urandom(16)).decode()
+  request = "GET / HTTP/1.1\r\nHost: localhost:%d\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: %s\r\nSec-WebSocket-Version: 13\r\n" % (port, key)
+  if extensions is not None:
+    request += "Sec-WebSocket-Extensions: %s\r\n" % extensions
+  writer.write((request + "\r\n").encode())
+  head = (await reader.readuntil(b"\r\n\r\n")).decode().split("\r\n")
+  assert(head[0].startswith("HTTP/1.1 101"))
+  answered = None
+  for line in head[1:]:
+    name, _, value = line.partition(":")
+    if name.lower() == "sec-websocket-extensions":
+      answered = value.strip()
+  return reader, writer, answered
+
+def frame(opcode: int, payload: bytes, fin: bool = True, rsv1: bool = False) -> bytes:
+  '''A masked frame, as clients send them.'''
+  first = (0x80 if fin else 0) | (0x40 if rsv1 else 0) | opcode
+  if len(payload) < 126:
+    head = struct.pack("!BB", first, 0x80 | len(payload))
+  elif len(payload) < 65536:
+    head = struct.pack("!BBH", first, 0x80 | 126, len(payload))
+  else:
+    head = struct.pack("!BBQ", first, 0x80 | 127, len(payload))
+  mask = os.urandom(4)
+  return head + mask + bytes(byte ^ mask[i % 4] for i, byte in enumerate(payload))
+
+async def read_frame(reader):
+  '''Read a frame from the server. Returns its RSV1 bit, opcode and payload.'''
+  first, second = await reader.readexactly(2)
+  length = second & 0x7f
+  if length == 126:
+    length, = struct.unpack("!H", await reader.readexactly(2))
+  elif length == 127:
+    length, = struct.unpack("!Q", await reader.readexactly(8))
+  return bool(first & 0x40), first & 0x0f, await reader.readexactly(length)
+
+def deflate(compressor, data: bytes) -> bytes:
+  compressed = compressor.compress(data) + compressor.flush(zlib.Z_SYNC_FLUSH)
+  assert(compressed.endswith(DEFLATE_TAIL))
+  return compressed[:-len(DEFLATE_TAIL)]
+
+async def wait_for_connect(server):
+  for attempt_num in range(0, 120):
+    events = server.drain_client_connect_events()
+    if events:
+      return events[0]
+    await asyncio.sleep(0.050)
+  assert(False)
+
+async def wait_for_messages(server, count: int):
+  received = []
+  for attempt_num in range(0, 120):
+    received += server.drain_client_messages()
+    if len(received) >= count:
+      return received
+    await asyncio.sleep(0.050)
+  return received
+
+def stop(server):
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_compressed_messages():
+  port = 60200
+  server = quicksocket.server.Server()
+  assert(server.start(port, compression = CompressionConfig(threshold = 64)))
+  time.sleep(0.200)
+  text = "quicksocket " * 100
+
+  async def run_tasks():
+    # The first offer the server understands is accepted.
+    reader, writer, answered = await handshake(port, "x-webkit-deflate-frame, permessage-deflate; client_max_window_bits")
+    assert(answered == "permessage-deflate")
+    client_id = (await wait_for_connect(server)).client_id
+    assert(server.get_client_compression(client_id) == "permessage-deflate")
+
+    # Compressed messages arrive as they were sent, whether in one frame or several, and so do uncompressed ones.
+    compressor = zlib.compressobj(wbits = -15)
+    writer.write(frame(0x1, deflate(compressor, text.encode()), rsv1 = True))
+    payload = deflate(compressor, b"\x00\x01" * 500)
+    writer.write(frame(0x2, payload[:10], fin = False, rsv1 = True) + frame(0x0, payload[10:]))
+    writer.write(frame(0x1, b"plain"))
+    received = await wait_for_messages(server, 3)
+    assert(received == [(client_id, text), (client_id, b"\x00\x01" * 500), (client_id, "plain")])
+
+    # Messages of at least the threshold's size are compressed, each referring back to the ones before; shorter ones aren't compressed.
+    server.send_messages([text, text, "short"])
+    decompressor = zlib.decompressobj(wbits = -15)
+    sizes = []
+    for message_num in range(0, 2):
+      rsv1, opcode, payload = await read_frame(reader)
+      assert(rsv1 and opcode == 0x1)
+      assert(decompressor.decompress(payload + DEFLATE_TAIL) == text.encode())
+      sizes.append(len(payload))
+    assert(sizes[0] < len(text) and sizes[1] < sizes[0])
+    assert(await read_frame(reader) == (False, 0x1, b"short"))
+
+    # Data after the end of a compressed stream is a protocol error, rather than silently dropped.
+    finished = zlib.compressobj(wbits = -15)
+    payload = finished.compress(b"first") + finished.flush(zlib.Z_FINISH) + deflate(zlib.compressobj(wbits = -15), b"dropped")
+    writer.write(frame(0x1, payload, rsv1 = True))
+    errors = []
+    for attempt_num in range(0, 120):
+      errors += server.drain_errors()
+      if errors:
+        break
+      await asyncio.sleep(0.050)
+    assert([(error.kind, error.client_id) for error in errors] == [("connection", client_id)])
+    assert("data after the end of the compressed stream" in errors[0].message)
+    assert(server.drain_client_messages() == [])
+    writer.close()
+  asyncio.get_event_loop().run_until_complete(run_tasks())
+
+  stop(server)
+
+def test_compression_options():
+  port = 60201
+  server = quicksocket.server.Server()
+  config = CompressionConfig(server_max_window_bits = 10, client_max_window_bits = 12, server_no_context_takeover = True, client_no_context_takeover = True, threshold = 0)
+  assert(server.start(port, compression = config))
+  time.sleep(0.200)
+  # Repeats too far back for a 9-bit window to refer to.
+  text = os.urandom(700).hex() * 2
+
+  async def run_tasks():
+    # The client asks for an even smaller window than the server's, and offers to limit its own.
+    reader, writer, answered = await handshake(port, "permessage-deflate; server_max_window_bits=9; client_max_window_bits")
+    assert(answered == "permessage-deflate; server_no_context_takeover; client_no_context_takeover; server_max_window_bits=9; client_max_window_bits=12")
+    await wait_for_connect(server)
+
+    # Without context takeover, every message is compressed on its own, within the window the client asked for.
+    server.send_messages([text, text, "short"])
+    frames = [await read_frame(reader) for message_num in range(0, 3)]
+    assert(all(rsv1 for rsv1, opcode, payload in frames))
+    assert(frames[0][2] == frames[1][2])
+    for (rsv1, opcode, payload), expected in zip(frames, [text, text, "short"]):
+      assert(zlib.decompressobj(wbits = -9).decompress(payload + DEFLATE_TAIL) == expected.encode())
+    writer.close()
+
+    # Offers the server can't honour are declined: it can't compress with an 8-bit window, and doesn't know "bogus".
+    for offer in ["permessage-deflate; server_max_window_bits=8", "permessage-deflate; bogus"]:
+      reader, writer, answered = await handshake(port, offer)
+      assert(answered is None)
+      client_id = (await wait_for_connect(server)).client_id
+      assert(server.get_client_compression(client_id) is None)
+      server.send_to_client(client_id, [text])
+      assert(await read_frame(reader) == (False, 0x1, text.encode()))
+      writer.close()
+  asyncio.get_event_loop().run_until_complete(run_tasks())
+
+  stop(server)
+
+def test_compression_off_by_default():
+  port = 60202
+  try:
+    CompressionConfig(server_max_window_bits = 16)
+    assert(False)
+  except ValueError:
+    pass
+
+  server = quicksocket.server.Server()
+  assert(server.start(port))
+  time.sleep(0.200)
+
+  async def run_tasks():
+    reader, writer, answered = await handshake(port, "permessage-deflate")
+    assert(answered is None)
+    client_id = (await wait_for_connect(server)).client_id
+    assert(server.get_client_compression(client_id) is None)
+    server.send_messages(["quicksocket " * 100])
+    assert(await read_frame(reader) == (False, 0x1, ("quicksocket " * 100).encode()))
+    writer.close()
+  asyncio.get_event_loop().run_until_complete(run_tasks())
+
+  stop(server)
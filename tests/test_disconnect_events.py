@@ -0,0 +1,172 @@
+import socket
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+async def client_close(port: int, code: int, reason: str):
+  uri = "ws://localhost:" + str(port)
+  async with websockets.connect(uri) as websocket:
+    await websocket.send("hello")
+    await asyncio.sleep(0.200)
+    await websocket.close(code=code, reason=reason)
+
+async def wait_for_disconnects(server: quicksocket.server.Server, count: int):
+  disconnects = []
+  for attempt_num in range(0, 120):
+    disconnects += server.drain_client_disconnect_events()
+    if len(disconnects) >= count:
+      return disconnects
+    await asyncio.sleep(0.050)
+
+  raise Exception("[test_disconnect_events] [wait_for_disconnects] Exception: Failed to receive disconnect events in a reasonable amount of time.")
+
+def test_clean_disconnect_event():
+  port = 59999
+
+  server = quicksocket.server.Server()
+  server.start(port)
+  time.sleep(0.200)
+  assert(server.is_running())
+
+  async def run_tasks(loop):
+    cli_task = loop.create_task(client_close(port, 4000, "see you later"))
+    disconnects = await wait_for_disconnects(server, 1)
+    await cli_task
+    return disconnects
+  loop = asyncio.get_event_loop()
+  disconnects = loop.run_until_complete(run_tasks(loop))
+
+  # The disconnect is reported for the same client that connected, with the client's close code and reason.
+  connected = server.drain_new_client_events()
+  assert(len(connected) == 1 and len(disconnects) == 1)
+  evt = disconnects[0]
+  assert(evt.client_id == connected[0][0])
+  assert(evt.peer == connected[0][1])
+  assert(evt.kind == "clean")
+  assert(evt.code == 4000)
+  assert(evt.reason == "see you later")
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+async def client_wait_for_close(port: int):
+  '''Stay connected until the server closes the connection, then return the close code the server sent.'''
+  uri = "ws://localhost:" + str(port)
+  async with websockets.connect(uri) as websocket:
+    await websocket.send("hello")
+    try:
+      await asyncio.wait_for(websocket.recv(), timeout=5.0)
+    except websockets.ConnectionClosed:
+      pass
+    return websocket.close_code
+
+def test_server_initiated_disconnect():
+  port = 60000
+
+  server = quicksocket.server.Server()
+  server.start(port)
+  time.sleep(0.200)
+  assert(server.is_running())
+
+  async def kick_first_client():
+    for attempt_num in range(0, 120):
+      for client_id, _ in server.drain_client_messages():
+        assert(server.disconnect_client(client_id, 4001, "banned"))
+        return client_id
+      await asyncio.sleep(0.050)
+    raise Exception("[test_disconnect_events] [kick_first_client] Exception: Failed to receive client message in a reasonable amount of time.")
+
+  async def run_tasks(loop):
+    cli_task = loop.create_task(client_wait_for_close(port))
+    kicked_id = await kick_first_client()
+    close_code = await cli_task
+    disconnects = await wait_for_disconnects(server, 1)
+    return kicked_id, close_code, disconnects
+  loop = asyncio.get_event_loop()
+  kicked_id, close_code, disconnects = loop.run_until_complete(run_tasks(loop))
+
+  # The client saw our close frame, and the disconnect event reports it.
+  assert(close_code == 4001)
+  assert(disconnects[0].client_id == kicked_id)
+  assert(disconnects[0].kind == "clean")
+  assert(disconnects[0].code == 4001)
+  assert(disconnects[0].reason == "banned")
+
+  # The client is gone, so it can no longer be addressed.
+  assert(not server.disconnect_client(kicked_id))
+  assert(not server.send_to_client(kicked_id, ["still there?"]))
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def wait_for_disconnects_after_stop(server: quicksocket.server.Server, count: int, timeout_s: float):
+  '''Disconnect events are still reported while a stopped server's connections finish closing.'''
+  disconnects = []
+  deadline = time.time() + timeout_s
+  while len(disconnects) < count and time.time() < deadline:
+    disconnects += server.drain_client_disconnect_events()
+    time.sleep(0.050)
+  return disconnects
+
+def test_shutdown_disconnect():
+  port = 60001
+
+  server = quicksocket.server.Server()
+  server.start(port)
+  time.sleep(0.200)
+
+  async def run_tasks(loop):
+    cli_task = loop.create_task(client_wait_for_close(port))
+    for attempt_num in range(0, 120):
+      if server.drain_client_messages():
+        break
+      await asyncio.sleep(0.050)
+    server.stop()
+    return await cli_task
+  loop = asyncio.get_event_loop()
+  close_code = loop.run_until_complete(run_tasks(loop))
+
+  # The client was sent a close frame, and answered it, so the disconnect is clean.
+  assert(close_code == 1001)
+  disconnects = wait_for_disconnects_after_stop(server, 1, 5.0)
+  assert(len(disconnects) == 1)
+  assert(disconnects[0].kind == "clean")
+  assert(disconnects[0].code == 1001)
+  assert(disconnects[0].reason == "Server shutting down.")
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_shutdown_unanswered():
+  port = 60002
+
+  server = quicksocket.server.Server()
+  server.start(port)
+  time.sleep(0.200)
+
+  # A client that never reads, so never answers the close frame.
+  sock = socket.create_connection(("127.0.0.1", port))
+  sock.sendall(b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n")
+  assert(sock.recv(1024).startswith(b"HTTP/1.1 101"))
+  for attempt_num in range(0, 120):
+    if server.drain_new_client_events():
+      break
+    time.sleep(0.050)
+
+  server.stop()
+  disconnects = wait_for_disconnects_after_stop(server, 1, 8.0)
+  assert(len(disconnects) == 1)
+  assert(disconnects[0].kind == "timed_out")
+  assert(disconnects[0].code == 1001)
+  sock.close()
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_clean_disconnect_event()
+  test_server_initiated_disconnect()
+  test_shutdown_disconnect()
+  test_shutdown_unanswered()
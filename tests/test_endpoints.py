@@ -0,0 +1,125 @@
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+async def endpoint_client(port: int, path: str, greeting: str):
+  '''Connect to `path`, send a greeting, then read until "done". Returns everything else we read.'''
+  uri = "ws://localhost:" + str(port) + path
+  async with websockets.connect(uri) as websocket:
+    await websocket.send(greeting)
+    received = []
+    while True:
+      msg = await asyncio.wait_for(websocket.recv(), timeout=5.0)
+      if msg == "done":
+        break
+      received.append(msg)
+    await websocket.close()
+    return received
+
+async def rejected_status(port: int, path: str):
+  '''Try to connect to `path`, returning the HTTP status it was rejected with, or None if it was accepted.'''
+  try:
+    async with websockets.connect("ws://localhost:" + str(port) + path):
+      return None
+  except websockets.InvalidStatusCode as e:
+    return e.status_code
+
+def test_any_path_without_endpoints():
+  port = 60110
+  server = quicksocket.server.Server()
+  assert(server.start(port))
+  time.sleep(0.200)
+
+  async def run_tasks(loop):
+    client = loop.create_task(endpoint_client(port, "/anything?user=3", "hello"))
+    for attempt_num in range(0, 120):
+      msgs = server.drain_client_messages()
+      if msgs:
+        break
+      await asyncio.sleep(0.050)
+    assert([payload for _, payload in msgs] == ["hello"])
+    assert(server.get_client_path(msgs[0][0]) == "/anything?user=3")
+    server.send_messages(["done"])
+    return await client
+  loop = asyncio.get_event_loop()
+  assert(loop.run_until_complete(run_tasks(loop)) == [])
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_endpoints():
+  port = 60111
+  server = quicksocket.server.Server()
+  assert(server.start(port, endpoints = ["/hands", "/debug"]))
+  time.sleep(0.200)
+
+  async def run_tasks(loop):
+    # Unknown paths are turned away before the upgrade, like any other failed handshake.
+    assert(await rejected_status(port, "/nope") == 404)
+    errors = []
+    for attempt_num in range(0, 120):
+      errors += server.drain_errors()
+      if errors:
+        break
+      await asyncio.sleep(0.050)
+    assert([error.kind for error in errors] == ["handshake"])
+    # ...so they're never reported as connected.
+    assert(server.drain_new_client_events() == [])
+
+    clients = [
+      loop.create_task(endpoint_client(port, "/", "from root")),
+      loop.create_task(endpoint_client(port, "/hands", "from hands")),
+      loop.create_task(endpoint_client(port, "/debug?verbose=1", "from debug")),
+    ]
+    received = {None: [], "/hands": [], "/debug": []}
+    for attempt_num in range(0, 120):
+      for endpoint in received:
+        received[endpoint] += server.drain_client_messages(endpoint = endpoint)
+      if all(received.values()):
+        break
+      await asyncio.sleep(0.050)
+    # Each endpoint only sees its own clients' messages.
+    assert([payload for _, payload in received[None]] == ["from root"])
+    assert([payload for _, payload in received["/hands"]] == ["from hands"])
+    assert([payload for _, payload in received["/debug"]] == ["from debug"])
+    assert(server.get_client_path(received["/debug"][0][0]) == "/debug?verbose=1")
+    assert(len(server.drain_new_client_events()) == 3)
+
+    # ...and its own broadcast.
+    server.send_messages(["to root"])
+    server.send_messages(["to hands"], endpoint = "/hands")
+    server.send_messages(["to debug"], endpoint = "/debug")
+    await asyncio.sleep(0.200)
+    for endpoint in ["/", "/hands", "/debug"]:
+      server.send_messages(["done"], endpoint = endpoint)
+    return [await client for client in clients]
+  loop = asyncio.get_event_loop()
+  root, hands, debug = loop.run_until_complete(run_tasks(loop))
+  assert(root == ["to root"])
+  assert(hands == ["to hands"])
+  assert(debug == ["to debug"])
+
+  for bad in [lambda: server.send_messages(["x"], endpoint = "/nope"), lambda: server.drain_client_messages(endpoint = "/nope")]:
+    try:
+      bad()
+      assert(False)
+    except ValueError:
+      pass
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_invalid_endpoints():
+  server = quicksocket.server.Server()
+  for endpoints in [["hands"], ["/hands?x=1"], ["/"]]:
+    try:
+      server.start(60112, endpoints = endpoints)
+      assert(False)
+    except ValueError:
+      pass
+  assert(not server.is_running())
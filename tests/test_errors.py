@@ -0,0 +1,103 @@
+import socket
+import time
+
+import quicksocket.server
+from quicksocket.server import BindError, QuicksocketError, ServerNotRunningError
+
+def test_not_running_raises():
+  server = quicksocket.server.Server()
+
+  # Sending before the server has started is an error the caller hears about directly.
+  try:
+    server._backend.try_send_messages(["hello"])
+    assert(False)
+  except ServerNotRunningError as e:
+    assert(isinstance(e, QuicksocketError))
+
+  # The same goes for the Python wrapper.
+  try:
+    server.send_messages(["hello"])
+    assert(False)
+  except ServerNotRunningError:
+    pass
+
+  # Nothing went wrong on the server's side, so nothing is queued.
+  assert(server.drain_errors() == [])
+
+def test_errors_are_queued():
+  port = 60030
+
+  server = quicksocket.server.Server()
+  assert(server.start(port))
+  time.sleep(0.200)
+
+  # A client-addressed failure is queued with its client ID.
+  before = time.time()
+  assert(not server.send_to_client(10000, ["nobody"]))
+
+  # So is a connection that never completes the websocket handshake.
+  with socket.create_connection(("127.0.0.1", port)) as sock:
+    sock.sendall(b"not a websocket handshake\r\n\r\n")
+    time.sleep(0.200)
+
+  errors = []
+  for attempt_num in range(0, 120):
+    errors += server.drain_errors()
+    if len(errors) >= 2:
+      break
+    time.sleep(0.050)
+
+  assert([error.kind for error in errors] == ["client_not_connected", "handshake"])
+  assert(errors[0].client_id == 10000)
+  assert(errors[0].timestamp >= before - 1.0)
+  assert(errors[1].client_id is not None)
+  assert(server.get_last_error_string() == errors[1].message)
+
+  # Draining empties the queue.
+  assert(server.drain_errors() == [])
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_handshake_timeout():
+  # One server reads the request itself (to tell plain HTTP requests from websocket upgrades), the other leaves it all to the websocket handshake.
+  ports = [60031, 60032]
+  servers = [quicksocket.server.Server(), quicksocket.server.Server()]
+  assert(servers[0].start(ports[0], metrics = True))
+  assert(servers[1].start(ports[1]))
+  time.sleep(0.200)
+
+  # Clients that connect and never finish their request are dropped once the handshake times out.
+  socks = [socket.create_connection(("127.0.0.1", port)) for port in ports]
+  for sock in socks:
+    sock.sendall(b"GET / HTTP/1.1\r\n")
+    sock.settimeout(15.0)
+  before = time.time()
+  for sock in socks:
+    assert(sock.recv(1024) == b"")
+    sock.close()
+  assert(9.0 <= time.time() - before < 14.0)
+
+  for server in servers:
+    errors = []
+    for attempt_num in range(0, 40):
+      errors += server.drain_errors()
+      if errors:
+        break
+      time.sleep(0.050)
+    assert([error.kind for error in errors] == ["handshake"])
+    assert("timed out" in errors[0].message)
+    assert(server.drain_new_client_events() == [])
+    server.stop()
+  time.sleep(0.200)
+
+def test_bind_error_is_a_quicksocket_error():
+  assert(issubclass(BindError, QuicksocketError))
+  assert(issubclass(ServerNotRunningError, QuicksocketError))
+
+if __name__ == "__main__":
+  test_not_running_raises()
+  test_errors_are_queued()
+  test_handshake_timeout()
+  test_bind_error_is_a_quicksocket_error()
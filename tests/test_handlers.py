@@ -0,0 +1,76 @@
+import threading
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+async def client_converse(port: int):
+  uri = "ws://localhost:" + str(port)
+  async with websockets.connect(uri) as websocket:
+    await websocket.send("first")
+    await websocket.send("boom")
+    await websocket.send(b"last")
+    assert(await asyncio.wait_for(websocket.recv(), timeout=5.0) == "got last")
+    await websocket.close()
+
+async def client_converse_no_reply(port: int):
+  uri = "ws://localhost:" + str(port)
+  async with websockets.connect(uri) as websocket:
+    await websocket.send("hi")
+    await websocket.close()
+
+def test_handlers():
+  port = 60070
+
+  server = quicksocket.server.Server()
+  calls = []
+  done = threading.Event()
+
+  def on_connect(client_id, peer):
+    calls.append(("connect", client_id))
+
+  def on_message(client_id, payload):
+    if payload == "boom":
+      raise ValueError("boom")
+    calls.append(("message", client_id, payload))
+    if payload == b"last":
+      server.send_to_client(client_id, ["got last"])
+
+  def on_disconnect(client_id, event):
+    calls.append(("disconnect", client_id, event.kind))
+    done.set()
+
+  # Handlers can be registered before the server starts.
+  server.set_handlers(on_connect = on_connect, on_message = on_message, on_disconnect = on_disconnect)
+  assert(server.start(port))
+  time.sleep(0.200)
+
+  asyncio.get_event_loop().run_until_complete(client_converse(port))
+  assert(done.wait(5.0))
+
+  # The exception didn't stop the dispatcher; it was recorded instead.
+  client_id = calls[0][1]
+  assert(calls == [("connect", client_id), ("message", client_id, "first"), ("message", client_id, b"last"), ("disconnect", client_id, "clean")])
+  errors = server.drain_errors()
+  assert([(error.kind, error.client_id) for error in errors] == [("callback", client_id)])
+  assert("boom" in errors[0].message)
+
+  # Without handlers, events are left for drain_* again.
+  server.set_handlers()
+  asyncio.get_event_loop().run_until_complete(client_converse_no_reply(port))
+  for attempt_num in range(0, 120):
+    messages = server.drain_client_messages()
+    if messages:
+      break
+    time.sleep(0.050)
+  assert([payload for _, payload in messages] == ["hi"])
+  assert(len(calls) == 4)
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_handlers()
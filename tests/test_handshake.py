@@ -36,13 +36,16 @@ async def client_handshake(port: int):
 async def server_handshake(server: quicksocket.server.Server):
   print("[test_handshake] [server_handshake] Launching server_handshake.")
 
+  client_ids = set()
   for attempt_num in range(0, 120):
+    client_ids.update(client_id for client_id, _ in server.drain_new_client_events())
     cli_msgs = server.drain_client_messages()
 
     # Process messages, if any.
-    for cli_msg in cli_msgs:
-      print("[test_handshake] [server_handshake] Got client message: {}".format(cli_msg))
+    for client_id, cli_msg in cli_msgs:
+      print("[test_handshake] [server_handshake] Got client {} message: {}".format(client_id, cli_msg))
       assert(cli_msg == TEMPEST_A4S1_CLIENT_CALL)
+      assert(client_id in client_ids)
 
       print("[test_handshake] [server_handshake] Sending response: {}".format(TEMPEST_A4S1_SERVER_RESPONSE))
       server.send_messages([TEMPEST_A4S1_SERVER_RESPONSE])
@@ -0,0 +1,74 @@
+import time
+
+import quicksocket.server
+from quicksocket.server import HandshakeDecision
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+def check_session(request):
+  '''Accepts clients with a session cookie, tagging the response, and turns everyone else away.'''
+  if request.cookies.get("session") == "abc":
+    return HandshakeDecision.accept({"X-Session-User": "alice"})
+  if request.path == "/open":
+    return None
+  return HandshakeDecision.reject(401, "No session.", {"WWW-Authenticate": "Cookie"})
+
+async def connect_and_greet(port: int, path: str, headers: dict):
+  '''Connect, send a greeting, and return the upgrade response's headers, or the HTTP status the connection was rejected with.'''
+  try:
+    async with websockets.connect("ws://localhost:" + str(port) + path, extra_headers = headers) as websocket:
+      await websocket.send("hello")
+      await asyncio.sleep(0.200)
+      return websocket.response_headers
+  except websockets.InvalidStatusCode as e:
+    return e.status_code
+
+def test_handshake_hook():
+  port = 60120
+  server = quicksocket.server.Server()
+  assert(server.start(port, handshake_hook = check_session))
+  time.sleep(0.200)
+
+  async def run_tasks():
+    accepted = await connect_and_greet(port, "/game?room=7", {"Cookie": "theme=dark; session=abc", "X-Client": "test"})
+    assert(accepted["x-session-user"] == "alice")
+    assert(await connect_and_greet(port, "/game", {}) == 401)
+    assert(isinstance(await connect_and_greet(port, "/open", {}), dict))
+  asyncio.get_event_loop().run_until_complete(run_tasks())
+
+  # Only the accepted clients are reported, each with its upgrade request.
+  events = server.drain_client_connect_events()
+  assert(len(events) == 2)
+  request = events[0].request
+  assert(request.path == "/game" and request.query == "room=7")
+  assert(request.headers["x-client"] == "test")
+  assert(request.cookies == {"theme": "dark", "session": "abc"})
+  assert(events[1].request.path == "/open" and events[1].request.query is None)
+  # Rejections are the hook's own decision, not errors.
+  assert(server.drain_errors() == [])
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_failing_handshake_hook():
+  port = 60121
+  server = quicksocket.server.Server()
+  def broken_hook(request):
+    raise RuntimeError("oops")
+  assert(server.start(port, handshake_hook = broken_hook))
+  time.sleep(0.200)
+
+  status = asyncio.get_event_loop().run_until_complete(connect_and_greet(port, "/", {}))
+  assert(status == 500)
+  assert(server.drain_client_connect_events() == [])
+  assert([error.kind for error in server.drain_errors()] == ["callback"])
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_handshake_hook()
+  test_failing_handshake_hook()
@@ -0,0 +1,94 @@
+import socket
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+def connect_silently(port: int) -> socket.socket:
+  '''Completes a websocket handshake over a raw socket that's never read from again, so it never answers pings.'''
+  sock = socket.create_connection(("127.0.0.1", port))
+  sock.sendall(b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n")
+  assert(sock.recv(1024).startswith(b"HTTP/1.1 101"))
+  return sock
+
+def test_keepalive():
+  port = 60190
+  server = quicksocket.server.Server()
+  assert(server.start(port, ping_interval_s = 0.2, pong_timeout_s = 0.5))
+  time.sleep(0.200)
+
+  async def run_tasks(loop):
+    async with websockets.connect("ws://localhost:" + str(port)) as websocket:
+      # Pongs are sent while the client is reading.
+      reader = loop.create_task(websocket.recv())
+      for attempt_num in range(0, 120):
+        events = server.drain_new_client_events()
+        if events:
+          break
+        await asyncio.sleep(0.050)
+      responsive_id = events[0][0]
+      assert(server.get_client_info(responsive_id).rtt_s is None)
+
+      silent = connect_silently(port)
+      for attempt_num in range(0, 120):
+        events = server.drain_client_disconnect_events()
+        if events:
+          break
+        await asyncio.sleep(0.050)
+      # The silent client is dropped after missing a pong, while the responsive one stays, with a measured round-trip time.
+      assert(len(events) == 1)
+      assert(events[0].client_id != responsive_id)
+      assert(events[0].kind == "timed_out")
+      assert(events[0].code == 1006)
+      info = server.get_client_info(responsive_id)
+      assert(info is not None)
+      assert(0.0 < info.rtt_s < 0.5)
+      assert([client.client_id for client in server.get_clients()] == [responsive_id])
+      silent.close()
+      reader.cancel()
+  loop = asyncio.get_event_loop()
+  loop.run_until_complete(run_tasks(loop))
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_keepalive_off_by_default():
+  port = 60191
+  server = quicksocket.server.Server()
+  assert(server.start(port))
+  time.sleep(0.200)
+
+  silent = connect_silently(port)
+  for attempt_num in range(0, 120):
+    events = server.drain_new_client_events()
+    if events:
+      break
+    time.sleep(0.050)
+  time.sleep(0.500)
+  info = server.get_client_info(events[0][0])
+  assert(info is not None)
+  assert(info.rtt_s is None)
+  assert(server.drain_client_disconnect_events() == [])
+  silent.close()
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_invalid_keepalive():
+  server = quicksocket.server.Server()
+  for interval_s, timeout_s in [(0.0, None), (-1.0, None), (float("nan"), None), (1.0, 0.0), (None, 1.0)]:
+    try:
+      server.start(60192, ping_interval_s = interval_s, pong_timeout_s = timeout_s)
+      assert(False)
+    except ValueError:
+      pass
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_keepalive()
+  test_keepalive_off_by_default()
+  test_invalid_keepalive()
@@ -0,0 +1,64 @@
+import logging
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+class RecordCollector(logging.Handler):
+  def __init__(self):
+    super().__init__()
+    self.records = []
+
+  def emit(self, record):
+    self.records.append(record)
+
+async def client_connect(port: int):
+  uri = "ws://localhost:" + str(port)
+  async with websockets.connect(uri) as websocket:
+    await websocket.send("hello")
+    await websocket.close()
+
+def test_python_logging_bridge():
+  port = 60040
+
+  logger = logging.getLogger("quicksocket_test")
+  logger.setLevel(logging.INFO)
+  collector = RecordCollector()
+  logger.addHandler(collector)
+  quicksocket.server.enable_python_logging(logger)
+
+  server = quicksocket.server.Server()
+  assert(server.start(port))
+  time.sleep(0.200)
+  asyncio.get_event_loop().run_until_complete(client_connect(port))
+
+  # The connection and disconnection are logged, with the client they concern, under the module that logged them.
+  for attempt_num in range(0, 120):
+    messages = [record.getMessage() for record in collector.records]
+    if any("Disconnected" in message for message in messages):
+      break
+    time.sleep(0.050)
+  assert(any("Accepted connection" in message and "[client 1 " in message for message in messages))
+  assert(any("Disconnected" in message for message in messages))
+  assert(any(record.name == "quicksocket_test.server.tokio_server" for record in collector.records))
+
+  # Nothing below the logger's level is forwarded.
+  assert(all(record.levelno >= logging.INFO for record in collector.records))
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+  # Once disabled, nothing more is forwarded.
+  quicksocket.server.disable_python_logging()
+  count = len(collector.records)
+  assert(server.start(port))
+  time.sleep(0.200)
+  server.stop()
+  time.sleep(0.200)
+  assert(len(collector.records) == count)
+
+if __name__ == "__main__":
+  test_python_logging_bridge()
@@ -0,0 +1,142 @@
+import re
+import time
+import urllib.error
+import urllib.request
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+def scrape(port: int, path: str = "/metrics") -> dict:
+  '''Fetches the metrics and returns {sample name with labels: value}.'''
+  with urllib.request.urlopen("http://localhost:" + str(port) + path, timeout = 5.0) as response:
+    assert(response.headers["Content-Type"].startswith("text/plain; version=0.0.4"))
+    text = response.read().decode()
+  samples = {}
+  for line in text.splitlines():
+    if line.startswith("#"):
+      continue
+    name, value = line.rsplit(" ", 1)
+    samples[name] = int(value)
+  return samples
+
+async def wait_for(metrics_port: int, name: str, value: int) -> dict:
+  '''Scrapes until the sample reaches the value (or we give up). Scrapes run on the default executor, so the event loop (and the clients on it) keep running meanwhile.'''
+  loop = asyncio.get_event_loop()
+  for attempt_num in range(0, 120):
+    samples = await loop.run_in_executor(None, scrape, metrics_port)
+    if samples[name] == value:
+      break
+    await asyncio.sleep(0.050)
+  return samples
+
+def test_metrics_on_main_port():
+  port = 60160
+  server = quicksocket.server.Server()
+  assert(server.start(port, metrics = True, subprotocols = ["viz.v1"]))
+  time.sleep(0.200)
+
+  samples = scrape(port)
+  assert(samples["quicksocket_connected_clients"] == 0)
+  assert(samples["quicksocket_connections_total"] == 0)
+  assert(samples['quicksocket_queue_depth{queue="new_client_events"}'] == 0)
+  assert(samples['quicksocket_endpoint_queue_depth{endpoint="/",queue="client_messages"}'] == 0)
+  # Other paths aren't served without a static_dir.
+  try:
+    urllib.request.urlopen("http://localhost:" + str(port) + "/index.html", timeout = 5.0)
+    assert(False)
+  except urllib.error.HTTPError as e:
+    assert(e.code == 404)
+
+  async def run_tasks():
+    try:
+      async with websockets.connect("ws://localhost:" + str(port)) as websocket:
+        assert(False)
+    except websockets.InvalidStatusCode as e:
+      assert(e.status_code == 400)
+    async with websockets.connect("ws://localhost:" + str(port), subprotocols = ["viz.v1"]) as websocket:
+      await websocket.send("hello")
+      await websocket.send(b"\x00\x01\x02")
+      samples = await wait_for(port, 'quicksocket_endpoint_queue_depth{endpoint="/",queue="client_messages"}', 2)
+      assert(samples["quicksocket_connected_clients"] == 1)
+      assert(samples["quicksocket_connections_total"] == 1)
+      assert(samples['quicksocket_queue_depth{queue="new_client_events"}'] == 1)
+      assert(samples["quicksocket_messages_received_total"] == 2)
+      assert(samples["quicksocket_received_bytes_total"] == 8)
+
+      server.drain_client_messages()
+      server.send_messages(["hi", "there"])
+      assert(await asyncio.wait_for(websocket.recv(), timeout=5.0) == "hi")
+      assert(await asyncio.wait_for(websocket.recv(), timeout=5.0) == "there")
+  asyncio.get_event_loop().run_until_complete(run_tasks())
+
+  samples = asyncio.get_event_loop().run_until_complete(wait_for(port, "quicksocket_connected_clients", 0))
+  assert(samples["quicksocket_connected_clients"] == 0)
+  assert(samples["quicksocket_messages_sent_total"] == 2)
+  assert(samples["quicksocket_sent_bytes_total"] == 7)
+  assert(samples['quicksocket_handshake_failures_total{reason="subprotocol"}'] == 1)
+  assert(samples['quicksocket_handshake_failures_total{reason="origin"}'] == 0)
+  assert(samples["quicksocket_broadcast_lag_skipped_total"] == 0)
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_metrics_on_separate_port():
+  port = 60161
+  metrics_port = 60162
+  server = quicksocket.server.Server()
+  assert(server.start(port, endpoints = ["/hands"], metrics_address = "127.0.0.1:" + str(metrics_port)))
+  time.sleep(0.200)
+
+  async def run_tasks():
+    # Unknown paths are counted as handshake failures.
+    try:
+      async with websockets.connect("ws://localhost:" + str(port) + "/feet") as websocket:
+        assert(False)
+    except websockets.InvalidStatusCode as e:
+      assert(e.status_code == 404)
+    async with websockets.connect("ws://localhost:" + str(port) + "/hands") as websocket:
+      await websocket.send("hello")
+      return await wait_for(metrics_port, 'quicksocket_endpoint_queue_depth{endpoint="/hands",queue="client_messages"}', 1)
+  samples = asyncio.get_event_loop().run_until_complete(run_tasks())
+  assert(samples["quicksocket_connections_total"] == 1)
+  assert(samples['quicksocket_endpoint_queue_depth{endpoint="/",queue="client_messages"}'] == 0)
+  assert(samples['quicksocket_handshake_failures_total{reason="unknown_path"}'] == 1)
+
+  # The main port doesn't answer plain HTTP requests, so a scrape there fails.
+  try:
+    scrape(port)
+    assert(False)
+  except (urllib.error.URLError, ConnectionError):
+    pass
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+  # The metrics listener stops with the server.
+  try:
+    scrape(metrics_port)
+    assert(False)
+  except (urllib.error.URLError, ConnectionError):
+    pass
+
+def test_metrics_address_in_use():
+  port = 60163
+  blocker = quicksocket.server.Server()
+  assert(blocker.start(60164))
+  server = quicksocket.server.Server()
+  try:
+    server.start(port, metrics_address = "127.0.0.1:60164")
+    assert(False)
+  except quicksocket.server.BindError:
+    pass
+  assert(not server.is_running())
+  blocker.stop()
+  time.sleep(0.200)
+
+if __name__ == "__main__":
+  test_metrics_on_main_port()
+  test_metrics_on_separate_port()
+  test_metrics_address_in_use()
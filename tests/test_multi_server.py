@@ -0,0 +1,64 @@
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+async def client_exchange(port: int, message: str) -> str:
+  '''Send a message to the server on the given port and return its response.'''
+  uri = "ws://localhost:" + str(port)
+  async with websockets.connect(uri) as websocket:
+    await websocket.send(message)
+    response = await websocket.recv()
+    await websocket.close()
+  return response
+
+async def server_echo(server: quicksocket.server.Server, prefix: str):
+  '''Reply to the first message received with the message prefixed by this server's name.'''
+  for attempt_num in range(0, 120):
+    for _, cli_msg in server.drain_client_messages():
+      server.send_messages([prefix + cli_msg])
+      await asyncio.sleep(0.200)
+      return
+    await asyncio.sleep(0.050)
+
+  raise Exception("[test_multi_server] [server_echo] Exception: Failed to receive client message in a reasonable amount of time.")
+
+def test_independent_servers():
+  port_a, port_b = 59995, 59996
+
+  # Start two servers and confirm both run.
+  server_a = quicksocket.server.Server()
+  server_b = quicksocket.server.Server()
+  server_a.start(port_a)
+  server_b.start(port_b)
+  time.sleep(0.200)
+  assert(server_a.is_running())
+  assert(server_b.is_running())
+
+  # Each client should only be answered by the server it connected to.
+  async def run_tasks(loop):
+    cli_a_task = loop.create_task(client_exchange(port_a, "hello"))
+    cli_b_task = loop.create_task(client_exchange(port_b, "hello"))
+    srv_a_task = loop.create_task(server_echo(server_a, "a:"))
+    srv_b_task = loop.create_task(server_echo(server_b, "b:"))
+    assert(await cli_a_task == "a:hello")
+    assert(await cli_b_task == "b:hello")
+    await srv_a_task
+    await srv_b_task
+  loop = asyncio.get_event_loop()
+  loop.run_until_complete(run_tasks(loop))
+
+  # Stopping one server must not affect the other.
+  server_a.stop()
+  time.sleep(0.200)
+  assert(not server_a.is_running())
+  assert(server_b.is_running())
+
+  server_b.stop()
+  time.sleep(0.200)
+  assert(not server_b.is_running())
+
+if __name__ == "__main__":
+  test_independent_servers()
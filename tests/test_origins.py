@@ -0,0 +1,75 @@
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+async def connect_from(port: int, origin):
+  '''Connect with the given Origin header (or none) and send a greeting. Returns True if accepted, or the HTTP status the connection was rejected with.'''
+  try:
+    async with websockets.connect("ws://localhost:" + str(port), origin = origin) as websocket:
+      await websocket.send("hello")
+      await asyncio.sleep(0.100)
+      return True
+  except websockets.InvalidStatusCode as e:
+    return e.status_code
+
+def test_origin_allowlist():
+  port = 60140
+  server = quicksocket.server.Server()
+  assert(server.start(port, allowed_origins = ["https://viz.example.com", "http://localhost:8080", "https://*.trusted.org"]))
+  time.sleep(0.200)
+
+  async def run_tasks():
+    results = {}
+    for origin in [
+      "https://viz.example.com",
+      "HTTPS://Viz.Example.com",
+      "http://localhost:8080",
+      "https://a.trusted.org",
+      "https://a.b.trusted.org",
+      None,
+      "https://evil.com",
+      "http://viz.example.com",
+      "http://localhost:9090",
+      "https://trusted.org",
+      "https://eviltrusted.org",
+      "https://trusted.org.evil.com",
+    ]:
+      results[origin] = await connect_from(port, origin)
+    return results
+  results = asyncio.get_event_loop().run_until_complete(run_tasks())
+
+  allowed = [origin for origin, result in results.items() if result is True]
+  forbidden = [origin for origin, result in results.items() if result == 403]
+  assert(allowed == ["https://viz.example.com", "HTTPS://Viz.Example.com", "http://localhost:8080", "https://a.trusted.org", "https://a.b.trusted.org", None])
+  assert(len(forbidden) == 6)
+  # Every rejection is recorded as a security event (just after its response is sent), and never reported as a connection.
+  errors = []
+  for attempt_num in range(0, 120):
+    errors += server.drain_errors()
+    if len(errors) >= 6:
+      break
+    time.sleep(0.050)
+  assert([error.kind for error in errors] == ["security"] * 6)
+  assert("https://evil.com" in errors[0].message)
+  assert(len(server.drain_new_client_events()) == 6)
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_invalid_origins():
+  server = quicksocket.server.Server()
+  for origins in [["example.com"], ["https://example.com/path"], ["https://*"], ["https://a.*.example.com"]]:
+    try:
+      server.start(60141, allowed_origins = origins)
+      assert(False)
+    except ValueError:
+      pass
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_origin_allowlist()
+  test_invalid_origins()
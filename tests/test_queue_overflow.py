@@ -0,0 +1,140 @@
+import time
+
+import quicksocket.server
+from quicksocket.server import QueueConfig
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+async def client_flood(port: int, count: int):
+  '''Send `count` messages, then wait for the server to close the connection (or for a timeout). Returns the close code, if any.'''
+  uri = "ws://localhost:" + str(port)
+  async with websockets.connect(uri) as websocket:
+    for i in range(count):
+      await websocket.send(str(i))
+    try:
+      await asyncio.wait_for(websocket.recv(), timeout=1.0)
+    except websockets.ConnectionClosed as e:
+      return e.code
+    except asyncio.TimeoutError:
+      pass
+    await websocket.close()
+    return None
+
+def wait_for_drops(server: quicksocket.server.Server, key: str, count: int):
+  for attempt_num in range(0, 120):
+    if server.get_drop_counts()[key] >= count:
+      break
+    time.sleep(0.050)
+  assert(server.get_drop_counts()[key] == count)
+
+def run_flood(port: int, overflow: str, count: int):
+  server = quicksocket.server.Server()
+  assert(server.start(port, client_message_queue = QueueConfig(4, overflow)))
+  time.sleep(0.200)
+  close_code = asyncio.get_event_loop().run_until_complete(client_flood(port, count))
+  wait_for_drops(server, "client_messages", count - 4)
+  messages = [payload for _, payload in server.drain_client_messages()]
+  disconnects = []
+  for attempt_num in range(0, 120):
+    disconnects += server.drain_client_disconnect_events()
+    if disconnects:
+      break
+    time.sleep(0.050)
+  errors = server.drain_errors()
+  server.stop()
+  time.sleep(0.200)
+  return messages, close_code, disconnects, errors
+
+def test_queue_config():
+  assert(QueueConfig().capacity == 16 and QueueConfig().overflow is None)
+  assert(repr(QueueConfig(8, "drop_newest")) == 'QueueConfig(capacity=8, overflow="drop_newest")')
+  for bad in [lambda: QueueConfig(0), lambda: QueueConfig(4, "sometimes")]:
+    try:
+      bad()
+      assert(False)
+    except ValueError:
+      pass
+
+  # Nothing has been dropped by a server that was never started.
+  server = quicksocket.server.Server()
+  assert(server.get_drop_counts() == {"new_client_events": 0, "client_messages": 0, "broadcast": 0, "client_disconnect_events": 0})
+
+def test_client_message_overflow():
+  # The newest messages win...
+  messages, close_code, _, _ = run_flood(60080, "drop_oldest", 10)
+  assert(messages == ["6", "7", "8", "9"])
+  assert(close_code is None)
+
+  # ...or the oldest do...
+  messages, close_code, _, _ = run_flood(60081, "drop_newest", 10)
+  assert(messages == ["0", "1", "2", "3"])
+  assert(close_code is None)
+
+  # ...or the client is disconnected for flooding.
+  messages, close_code, disconnects, errors = run_flood(60082, "disconnect", 10)
+  assert(messages == ["0", "1", "2", "3"])
+  assert(close_code == 1008)
+  assert([(evt.kind, evt.code) for evt in disconnects] == [("clean", 1008)])
+  assert([error.kind for error in errors] == ["queue_full"])
+
+def test_new_client_overflow():
+  port = 60083
+  server = quicksocket.server.Server()
+  assert(server.start(port, new_client_queue = QueueConfig(1, "disconnect")))
+  time.sleep(0.200)
+
+  async def connect_two():
+    async with websockets.connect("ws://localhost:" + str(port)):
+      # The first client's event fills the queue, so the second connection is closed as soon as its handshake completes.
+      async with websockets.connect("ws://localhost:" + str(port)) as websocket:
+        try:
+          await asyncio.wait_for(websocket.recv(), timeout=5.0)
+          assert(False)
+        except websockets.ConnectionClosed as e:
+          assert(e.code == 1013)
+  asyncio.get_event_loop().run_until_complete(connect_two())
+
+  assert(len(server.drain_new_client_events()) == 1)
+  assert(server.get_drop_counts()["new_client_events"] == 1)
+  assert([error.kind for error in server.drain_errors()] == ["queue_full"])
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_disconnect_overflow():
+  port = 60003
+  server = quicksocket.server.Server()
+
+  # Its clients are already gone, so there's nobody to disconnect.
+  try:
+    server.start(port, disconnect_queue = QueueConfig(1, "disconnect"))
+    assert(False)
+  except ValueError:
+    pass
+
+  assert(server.start(port, disconnect_queue = QueueConfig(1, "drop_oldest")))
+  time.sleep(0.200)
+
+  async def connect_three():
+    for i in range(3):
+      async with websockets.connect("ws://localhost:" + str(port)):
+        pass
+  asyncio.get_event_loop().run_until_complete(connect_three())
+
+  # Only the last client's disconnection is kept.
+  wait_for_drops(server, "client_disconnect_events", 2)
+  client_ids = [client_id for client_id, _ in server.drain_new_client_events()]
+  assert(len(client_ids) == 3)
+  assert([evt.client_id for evt in server.drain_client_disconnect_events()] == client_ids[-1:])
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_queue_config()
+  test_client_message_overflow()
+  test_new_client_overflow()
+  test_disconnect_overflow()
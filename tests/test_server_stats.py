@@ -0,0 +1,64 @@
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+async def wait_for_stats(server, check):
+  for attempt_num in range(0, 120):
+    stats = server.get_server_stats()
+    if check(stats):
+      break
+    await asyncio.sleep(0.050)
+  return stats
+
+def test_server_stats():
+  port = 60170
+  server = quicksocket.server.Server()
+  assert(server.get_server_stats() is None)
+  assert(server.start(port, endpoints = ["/hands"]))
+  time.sleep(0.200)
+
+  stats = server.get_server_stats()
+  assert(stats.uptime_s > 0.0)
+  assert(stats.connected_clients == 0)
+  assert(stats.connections_total == 0)
+  assert(stats.client_message_queue_depths == {"/": 0, "/hands": 0})
+
+  async def run_tasks():
+    async with websockets.connect("ws://localhost:" + str(port)) as first:
+      async with websockets.connect("ws://localhost:" + str(port) + "/hands") as second:
+        await first.send("hello")
+        await second.send(b"\x00\x01\x02")
+        stats = await wait_for_stats(server, lambda stats: stats.messages_received == 2)
+        assert(stats.connected_clients == 2)
+        assert(stats.peak_clients == 2)
+        assert(stats.bytes_received == 8)
+        assert(stats.client_message_queue_depths == {"/": 1, "/hands": 1})
+
+        server.drain_client_messages()
+        server.send_messages(["hi", "there"])
+        assert(await asyncio.wait_for(first.recv(), timeout=5.0) == "hi")
+        assert(await asyncio.wait_for(first.recv(), timeout=5.0) == "there")
+      # Peak connections stay put as clients leave.
+      return await wait_for_stats(server, lambda stats: stats.connected_clients == 1)
+  stats = asyncio.get_event_loop().run_until_complete(run_tasks())
+  assert(stats.peak_clients == 2)
+  assert(stats.connections_total == 2)
+  assert(stats.client_message_queue_depths == {"/": 0, "/hands": 1})
+
+  stats = asyncio.get_event_loop().run_until_complete(wait_for_stats(server, lambda stats: stats.connected_clients == 0))
+  assert(stats.connected_clients == 0)
+  assert(stats.messages_sent == 2)
+  assert(stats.bytes_sent == 7)
+  assert(stats.dropped == {"new_client_events": 0, "client_messages": 0, "broadcast": 0, "client_disconnect_events": 0})
+  assert(stats.lag_skipped == 0)
+  assert(set(stats.broadcast_queue_depths) == {"/", "/hands"})
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_server_stats()
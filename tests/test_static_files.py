@@ -0,0 +1,75 @@
+import os
+import tempfile
+import time
+import urllib.error
+import urllib.request
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+def http_get(port: int, path: str, method: str = "GET"):
+  '''Returns (status, content type, body) for a plain HTTP request.'''
+  request = urllib.request.Request("http://localhost:" + str(port) + path, method = method)
+  try:
+    with urllib.request.urlopen(request, timeout = 5.0) as response:
+      return response.status, response.headers["Content-Type"], response.read()
+  except urllib.error.HTTPError as e:
+    return e.code, e.headers["Content-Type"], e.read()
+
+def test_static_files():
+  port = 60150
+  with tempfile.TemporaryDirectory() as root:
+    with open(os.path.join(root, "index.html"), "w") as f:
+      f.write("<h1>viz</h1>")
+    os.mkdir(os.path.join(root, "js"))
+    with open(os.path.join(root, "js", "app.js"), "w") as f:
+      f.write("connect();")
+    with open(os.path.join(os.path.dirname(root), "outside.txt"), "w") as f:
+      f.write("secret")
+
+    server = quicksocket.server.Server()
+    assert(server.start(port, static_dir = root))
+    time.sleep(0.200)
+
+    assert(http_get(port, "/") == (200, "text/html; charset=utf-8", b"<h1>viz</h1>"))
+    assert(http_get(port, "/js/app.js") == (200, "text/javascript; charset=utf-8", b"connect();"))
+    assert(http_get(port, "/js/app.js", "HEAD")[:2] == (200, "text/javascript; charset=utf-8"))
+    assert(http_get(port, "/missing.html")[0] == 404)
+    assert(http_get(port, "/../outside.txt")[0] == 404)
+    assert(http_get(port, "/%2e%2e/outside.txt")[0] == 404)
+    assert(http_get(port, "/", "POST")[0] == 405)
+
+    # Websockets still upgrade on the same port.
+    async def echo():
+      async with websockets.connect("ws://localhost:" + str(port) + "/socket") as websocket:
+        await websocket.send("hello")
+        for attempt_num in range(0, 120):
+          msgs = server.drain_client_messages()
+          if msgs:
+            break
+          await asyncio.sleep(0.050)
+        server.send_messages(["hi"])
+        return [payload for _, payload in msgs], await asyncio.wait_for(websocket.recv(), timeout=5.0)
+    assert(asyncio.get_event_loop().run_until_complete(echo()) == (["hello"], "hi"))
+    # Plain HTTP requests aren't clients, so they're never reported as connected.
+    assert(len(server.drain_new_client_events()) == 1)
+
+    server.stop()
+    time.sleep(0.200)
+    assert(not server.is_running())
+    os.remove(os.path.join(os.path.dirname(root), "outside.txt"))
+
+def test_missing_static_dir():
+  server = quicksocket.server.Server()
+  try:
+    server.start(60151, static_dir = "/nonexistent/quicksocket/web")
+    assert(False)
+  except quicksocket.server.QuicksocketError:
+    pass
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_static_files()
+  test_missing_static_dir()
@@ -0,0 +1,83 @@
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+async def negotiate(port: int, subprotocols: list):
+  '''Connect offering `subprotocols` and send a greeting. Returns the subprotocol the server selected, or the HTTP status the connection was rejected with.'''
+  try:
+    async with websockets.connect("ws://localhost:" + str(port), subprotocols = subprotocols) as websocket:
+      await websocket.send("hello")
+      await asyncio.sleep(0.200)
+      return websocket.subprotocol
+  except websockets.InvalidStatusCode as e:
+    return e.status_code
+
+def test_subprotocol_negotiation():
+  port = 60130
+  server = quicksocket.server.Server()
+  assert(server.start(port, subprotocols = ["viz.v2", "viz.v1"]))
+  time.sleep(0.200)
+
+  async def run_tasks():
+    # The server's priority order wins over the client's.
+    assert(await negotiate(port, ["viz.v1", "viz.v2"]) == "viz.v2")
+    assert(await negotiate(port, ["viz.v1", "other"]) == "viz.v1")
+    # Clients that offer none of them (or nothing at all) are turned away.
+    assert(await negotiate(port, ["other"]) == 400)
+    assert(await negotiate(port, None) == 400)
+  asyncio.get_event_loop().run_until_complete(run_tasks())
+
+  events = server.drain_client_connect_events()
+  assert([evt.subprotocol for evt in events] == ["viz.v2", "viz.v1"])
+  # The rejections are recorded just after the responses are sent.
+  errors = []
+  for attempt_num in range(0, 120):
+    errors += server.drain_errors()
+    if len(errors) >= 2:
+      break
+    time.sleep(0.050)
+  assert([error.kind for error in errors] == ["handshake", "handshake"])
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_subprotocols_ignored_by_default():
+  port = 60131
+  server = quicksocket.server.Server()
+  assert(server.start(port))
+  time.sleep(0.200)
+
+  async def run_tasks(loop):
+    client = loop.create_task(negotiate(port, ["viz.v2"]))
+    for attempt_num in range(0, 120):
+      msgs = server.drain_client_messages()
+      if msgs:
+        break
+      await asyncio.sleep(0.050)
+    assert(server.get_client_subprotocol(msgs[0][0]) is None)
+    return await client
+  loop = asyncio.get_event_loop()
+  assert(loop.run_until_complete(run_tasks(loop)) is None)
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_invalid_subprotocols():
+  server = quicksocket.server.Server()
+  for subprotocols in [[""], ["viz v2"], ["viz.v2,viz.v1"]]:
+    try:
+      server.start(60132, subprotocols = subprotocols)
+      assert(False)
+    except ValueError:
+      pass
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_subprotocol_negotiation()
+  test_subprotocols_ignored_by_default()
+  test_invalid_subprotocols()
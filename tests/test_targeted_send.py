@@ -0,0 +1,59 @@
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+async def client_converse(port: int, name: str, expect: list):
+  '''Introduce ourselves, then collect messages until everything expected has arrived.'''
+  uri = "ws://localhost:" + str(port)
+  async with websockets.connect(uri) as websocket:
+    await websocket.send(name)
+    received = []
+    while len(received) < len(expect):
+      received.append(await asyncio.wait_for(websocket.recv(), timeout=5.0))
+    assert(received == expect)
+    await websocket.close()
+
+async def server_reply(server: quicksocket.server.Server):
+  '''Reply privately to each client, then send one message to both.'''
+  ids_by_name = {}
+  for attempt_num in range(0, 120):
+    for client_id, name in server.drain_client_messages():
+      ids_by_name[name] = client_id
+    if len(ids_by_name) == 2:
+      break
+    await asyncio.sleep(0.050)
+  assert(len(ids_by_name) == 2)
+
+  assert(server.send_to_client(ids_by_name["alice"], ["only alice"]))
+  assert(server.send_to_client(ids_by_name["bob"], ["only bob"]))
+  assert(server.send_to_clients(list(ids_by_name.values()), ["both"]) == 2)
+
+  # Unknown clients are reported rather than silently ignored.
+  assert(not server.send_to_client(10000, ["nobody"]))
+
+def test_targeted_send():
+  port = 59998
+
+  server = quicksocket.server.Server()
+  server.start(port)
+  time.sleep(0.200)
+  assert(server.is_running())
+
+  async def run_tasks(loop):
+    alice = loop.create_task(client_converse(port, "alice", ["only alice", "both"]))
+    bob = loop.create_task(client_converse(port, "bob", ["only bob", "both"]))
+    await server_reply(server)
+    await alice
+    await bob
+  loop = asyncio.get_event_loop()
+  loop.run_until_complete(run_tasks(loop))
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_targeted_send()
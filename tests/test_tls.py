@@ -0,0 +1,82 @@
+import os
+import ssl
+import subprocess
+import tempfile
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+def generate_self_signed_cert(directory: str):
+  '''Generate a self-signed certificate for localhost with the openssl command line tool. Returns (cert_path, key_path).'''
+  cert_path = os.path.join(directory, "cert.pem")
+  key_path = os.path.join(directory, "key.pem")
+  subprocess.run([
+    "openssl", "req", "-x509", "-nodes", "-days", "1",
+    "-newkey", "ec", "-pkeyopt", "ec_paramgen_curve:prime256v1",
+    "-subj", "/CN=localhost", "-addext", "subjectAltName=DNS:localhost",
+    "-keyout", key_path, "-out", cert_path,
+  ], check=True, stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL)
+  return cert_path, key_path
+
+async def client_exchange(port: int, ssl_context: ssl.SSLContext) -> str:
+  uri = "wss://localhost:" + str(port)
+  async with websockets.connect(uri, ssl=ssl_context) as websocket:
+    await websocket.send("secret")
+    response = await websocket.recv()
+    await websocket.close()
+  return response
+
+async def server_echo(server: quicksocket.server.Server):
+  for attempt_num in range(0, 120):
+    for client_id, cli_msg in server.drain_client_messages():
+      server.send_to_client(client_id, ["echo " + cli_msg])
+      return
+    await asyncio.sleep(0.050)
+
+  raise Exception("[test_tls] [server_echo] Exception: Failed to receive client message in a reasonable amount of time.")
+
+def test_tls_connection():
+  port = 60020
+
+  with tempfile.TemporaryDirectory() as directory:
+    cert_path, key_path = generate_self_signed_cert(directory)
+
+    server = quicksocket.server.Server()
+    assert(server.start(port, tls_cert=cert_path, tls_key=key_path))
+    time.sleep(0.200)
+    assert(server.is_running())
+
+    # Trust only our self-signed certificate.
+    ssl_context = ssl.SSLContext(ssl.PROTOCOL_TLS_CLIENT)
+    ssl_context.load_verify_locations(cert_path)
+
+    async def run_tasks(loop):
+      cli_task = loop.create_task(client_exchange(port, ssl_context))
+      await server_echo(server)
+      return await cli_task
+    loop = asyncio.get_event_loop()
+    assert(loop.run_until_complete(run_tasks(loop)) == "echo secret")
+
+    server.stop()
+    time.sleep(0.200)
+    assert(not server.is_running())
+
+def test_tls_bad_key_path():
+  with tempfile.TemporaryDirectory() as directory:
+    cert_path, _ = generate_self_signed_cert(directory)
+
+    server = quicksocket.server.Server()
+    try:
+      server.start(60021, tls_cert=cert_path, tls_key=os.path.join(directory, "missing.pem"))
+      assert(False)
+    except quicksocket.server.QuicksocketError as e:
+      assert("missing.pem" in str(e))
+    assert("missing.pem" in server.get_last_error_string())
+    assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_tls_connection()
+  test_tls_bad_key_path()
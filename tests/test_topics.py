@@ -0,0 +1,182 @@
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+async def topic_client(port: int, greeting: str, control: list = []):
+  '''Send any control messages and a greeting, then read until "done". Returns everything else we read.'''
+  uri = "ws://localhost:" + str(port)
+  async with websockets.connect(uri) as websocket:
+    for msg in control:
+      await websocket.send(msg)
+    await websocket.send(greeting)
+    received = []
+    while True:
+      msg = await asyncio.wait_for(websocket.recv(), timeout=5.0)
+      if msg == "done":
+        break
+      received.append(msg)
+    await websocket.close()
+    return received
+
+async def wait_for_greetings(server: quicksocket.server.Server, count: int):
+  '''Returns {greeting: client_id} once `count` clients have greeted us.'''
+  greeted = {}
+  for attempt_num in range(0, 120):
+    for client_id, payload in server.drain_client_messages():
+      greeted[payload] = client_id
+    if len(greeted) >= count:
+      break
+    await asyncio.sleep(0.050)
+  assert(len(greeted) == count)
+  return greeted
+
+def test_server_side_topics():
+  port = 60100
+  server = quicksocket.server.Server()
+  assert(server.start(port))
+  time.sleep(0.200)
+
+  # Nobody's subscribed yet, so this goes nowhere.
+  server.publish("scores", ["too early"])
+
+  async def run_tasks(loop):
+    clients = [loop.create_task(topic_client(port, name)) for name in ["a", "b", "c"]]
+    ids = await wait_for_greetings(server, 3)
+    assert(server.subscribe_client(ids["a"], "scores"))
+    assert(server.subscribe_client(ids["a"], "news"))
+    assert(server.subscribe_client(ids["b"], "news"))
+    assert(server.get_client_topics(ids["a"]) == ["news", "scores"])
+    assert(server.get_client_topics(ids["c"]) == [])
+    assert(not server.subscribe_client(12345, "scores"))
+    await asyncio.sleep(0.100)
+
+    server.publish("scores", ["1-0"])
+    server.publish("news", ["headline"])
+    server.send_messages(["everyone"])
+    await asyncio.sleep(0.200)
+
+    assert(server.unsubscribe_client(ids["a"], "news"))
+    assert(not server.unsubscribe_client(ids["a"], "news"))
+    await asyncio.sleep(0.100)
+    server.publish("news", ["second headline"])
+    await asyncio.sleep(0.200)
+    server.send_messages(["done"])
+    return [await client for client in clients]
+  loop = asyncio.get_event_loop()
+  a, b, c = loop.run_until_complete(run_tasks(loop))
+
+  assert(sorted(a) == ["1-0", "everyone", "headline"])
+  assert(sorted(b) == ["everyone", "headline", "second headline"])
+  assert(c == ["everyone"])
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_client_control_messages():
+  port = 60101
+  server = quicksocket.server.Server()
+  assert(server.start(port, topic_control = True))
+  time.sleep(0.200)
+
+  async def run_tasks(loop):
+    clients = [
+      loop.create_task(topic_client(port, "a", ["quicksocket:subscribe:scores"])),
+      loop.create_task(topic_client(port, "b", ["quicksocket:subscribe:scores", "quicksocket:unsubscribe:scores"])),
+    ]
+    ids = await wait_for_greetings(server, 2)
+    assert(server.get_client_topics(ids["a"]) == ["scores"])
+    assert(server.get_client_topics(ids["b"]) == [])
+    server.publish("scores", ["1-0"])
+    await asyncio.sleep(0.200)
+    server.send_messages(["done"])
+    return [await client for client in clients]
+  loop = asyncio.get_event_loop()
+  a, b = loop.run_until_complete(run_tasks(loop))
+
+  # Control messages are handled by the server, so only the greetings were drained above.
+  assert(a == ["1-0"])
+  assert(b == [])
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+def test_control_messages_off_by_default():
+  port = 60102
+  server = quicksocket.server.Server()
+  assert(server.start(port))
+  time.sleep(0.200)
+
+  async def run_tasks(loop):
+    client = loop.create_task(topic_client(port, "a", ["quicksocket:subscribe:scores"]))
+    greeted = {}
+    for attempt_num in range(0, 120):
+      for client_id, payload in server.drain_client_messages():
+        greeted[payload] = client_id
+      if "a" in greeted:
+        break
+      await asyncio.sleep(0.050)
+    # Without topic_control, it's just another client message.
+    assert("quicksocket:subscribe:scores" in greeted)
+    assert(server.get_client_topics(greeted["a"]) == [])
+    server.send_messages(["done"])
+    return await client
+  loop = asyncio.get_event_loop()
+  loop.run_until_complete(run_tasks(loop))
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+async def wait_for_topic_count(server: quicksocket.server.Server, count: int):
+  for attempt_num in range(0, 120):
+    if server.get_server_stats().topics == count:
+      break
+    await asyncio.sleep(0.050)
+  assert(server.get_server_stats().topics == count)
+
+def test_topic_limits():
+  port = 60004
+  server = quicksocket.server.Server()
+  assert(server.start(port, topic_control = True))
+  time.sleep(0.200)
+
+  # An empty name, an oversized one, and one topic more than a client may have.
+  control = ["quicksocket:subscribe:", "quicksocket:subscribe:" + "x" * 257]
+  control += ["quicksocket:subscribe:t{:02}".format(i) for i in range(65)]
+
+  async def run_tasks(loop):
+    client = loop.create_task(topic_client(port, "a", control))
+    ids = await wait_for_greetings(server, 1)
+    assert(server.get_client_topics(ids["a"]) == ["t{:02}".format(i) for i in range(64)])
+    assert([error.kind for error in server.drain_errors()] == ["invalid_request"] * 3)
+    await wait_for_topic_count(server, 64)
+
+    # The same limits apply to the consumer.
+    for bad in [lambda: server.subscribe_client(ids["a"], ""), lambda: server.set_resync_snapshot(["state"], topic = "x" * 257)]:
+      try:
+        bad()
+        assert(False)
+      except ValueError:
+        pass
+    assert(not server.subscribe_client(ids["a"], "t64"))
+    assert([error.kind for error in server.drain_errors()] == ["invalid_request"])
+
+    # A topic is forgotten once its last subscriber leaves.
+    assert(server.unsubscribe_client(ids["a"], "t00"))
+    await wait_for_topic_count(server, 63)
+    server.send_messages(["done"])
+    return await client
+  loop = asyncio.get_event_loop()
+  loop.run_until_complete(run_tasks(loop))
+
+  # ...or disconnects.
+  loop.run_until_complete(wait_for_topic_count(server, 0))
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
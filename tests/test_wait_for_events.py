@@ -0,0 +1,118 @@
+import threading
+import time
+
+import quicksocket.server
+
+import asyncio    # Dependency for use with `websockets`.
+import websockets # A test websocket library to use as a client.
+
+async def client_converse(port: int):
+  uri = "ws://localhost:" + str(port)
+  async with websockets.connect(uri) as websocket:
+    await asyncio.sleep(0.200)
+    await websocket.send("hello")
+    await asyncio.sleep(0.200)
+    await websocket.close()
+
+def run_client(port: int):
+  asyncio.new_event_loop().run_until_complete(client_converse(port))
+
+def test_wait_for_events():
+  port = 60050
+
+  server = quicksocket.server.Server()
+
+  # Not started yet, so there's nothing to wait for.
+  assert(not server.wait_for_events(5.0))
+
+  assert(server.start(port))
+
+  # Nothing happens, so the wait times out, empty-handed.
+  before = time.time()
+  batch = server.wait_for_events(0.200)
+  assert(not batch)
+  assert(time.time() - before >= 0.150)
+
+  client = threading.Thread(target=run_client, args=(port,))
+  client.start()
+
+  # Each wait returns as soon as there's something, long before the timeout.
+  new_clients, messages, disconnects = [], [], []
+  before = time.time()
+  while len(disconnects) == 0 and time.time() - before < 5.0:
+    batch = server.wait_for_events(5.0)
+    new_clients += batch.new_client_events
+    messages += batch.client_messages
+    disconnects += batch.client_disconnect_events
+  client.join()
+
+  assert(time.time() - before < 2.0)
+  assert(len(new_clients) == 1)
+  client_id = new_clients[0][0]
+  assert(messages == [(client_id, "hello")])
+  assert(disconnects[0].client_id == client_id)
+
+  # Waiting doesn't hold the GIL, so other threads keep running.
+  ticks = []
+  ticker = threading.Thread(target=lambda: [ticks.append(time.sleep(0.010)) for _ in range(10)])
+  ticker.start()
+  server.wait_for_events(0.300)
+  assert(len(ticks) == 10)
+  ticker.join()
+
+  # A shutdown ends the wait.
+  threading.Timer(0.100, server.stop).start()
+  before = time.time()
+  server.wait_for_events(5.0)
+  assert(time.time() - before < 2.0)
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+async def endpoint_client_converse(port: int, path: str, message: str):
+  async with websockets.connect("ws://localhost:" + str(port) + path) as websocket:
+    await websocket.send(message)
+    await asyncio.sleep(0.500)
+
+def test_wait_for_endpoint_messages():
+  port = 60051
+
+  server = quicksocket.server.Server()
+  assert(server.start(port, endpoints = ["/hands"]))
+  time.sleep(0.200)
+
+  # A message to a registered endpoint ends the wait, and comes back under its path.
+  client = threading.Thread(target=lambda: asyncio.new_event_loop().run_until_complete(endpoint_client_converse(port, "/hands", "wave")))
+  client.start()
+  new_clients, endpoint_messages = [], {}
+  before = time.time()
+  while not endpoint_messages and time.time() - before < 5.0:
+    batch = server.wait_for_events(5.0)
+    new_clients += batch.new_client_events
+    endpoint_messages.update(batch.endpoint_messages)
+    assert(batch.client_messages == [])
+  client.join()
+
+  assert(time.time() - before < 2.0)
+  client_id = new_clients[0][0]
+  assert(endpoint_messages == {"/hands": [(client_id, "wave")]})
+  assert(server.drain_client_messages(endpoint = "/hands") == [])
+
+  # The default endpoint's messages stay in client_messages.
+  client = threading.Thread(target=lambda: asyncio.new_event_loop().run_until_complete(endpoint_client_converse(port, "/", "hello")))
+  client.start()
+  messages = []
+  before = time.time()
+  while not messages and time.time() - before < 5.0:
+    batch = server.wait_for_events(5.0)
+    messages += batch.client_messages
+    assert(batch.endpoint_messages == {})
+  client.join()
+  assert([payload for client_id, payload in messages] == ["hello"])
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_wait_for_events()
+  test_wait_for_endpoint_messages()